@@ -1,6 +1,13 @@
 use std::io::Result;
 
 fn main() -> Result<()> {
-    prost_build::compile_protos(&["../proto/frames.proto"], &["../proto/"])?;
+    prost_build::compile_protos(
+        &[
+            "../proto/frames.proto",
+            "../proto/peer.proto",
+            "../proto/trace.proto",
+        ],
+        &["../proto/"],
+    )?;
     Ok(())
 }
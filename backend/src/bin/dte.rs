@@ -0,0 +1,369 @@
+//! Terminal client for a running node. Talks the frontend protocol
+//! (`proto/frames.proto`) over the node's IPC socket, or over stdin/stdout of
+//! a node it starts itself, so edits can be scripted without Electron.
+
+mod protocol {
+    include!(concat!(env!("OUT_DIR"), "/dte.rs"));
+}
+
+use futures::{SinkExt, StreamExt};
+use prost::Message;
+use protocol::{ClientCommand, LocalInsert, LocalOp, LocalRemove, client_command, local_op};
+use protocol::{ListPeers, ReloadConfig, SaveDocument, server_event};
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::process::Stdio;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+
+const USAGE: &str = "\
+usage: dte [--socket PATH | --spawn BACKEND] COMMAND
+
+Connects to the node listening on PATH (dte.sock in $DTE_DATA_DIR, or in
+./native by default), or starts BACKEND and talks to it over its
+stdin/stdout.
+
+commands:
+  print                     print the document
+  watch                     print the document, then every remote edit
+  insert POSITION TEXT      insert ASCII TEXT before character POSITION
+  delete POSITION [COUNT]   delete COUNT characters (1 by default) from POSITION
+  save FILENAME             save the document as text in the node's data directory
+  reload                    reload the node's config file and print what changed
+
+Positions count characters from 0.";
+
+enum Command {
+    Print,
+    Watch,
+    Insert { position: usize, text: String },
+    Delete { position: usize, count: usize },
+    Save { filename: String },
+    Reload,
+}
+
+enum Target {
+    Socket(PathBuf),
+    Spawn(PathBuf),
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<(Target, Command), String> {
+    let data_dir = std::env::var_os("DTE_DATA_DIR").filter(|dir| !dir.is_empty());
+    let data_dir = data_dir.map_or_else(|| PathBuf::from("./native"), PathBuf::from);
+    let mut target = Target::Socket(data_dir.join("dte.sock"));
+    let command = loop {
+        match args.next().as_deref() {
+            Some("--socket") => {
+                target = Target::Socket(args.next().ok_or("--socket needs a path")?.into())
+            }
+            Some("--spawn") => {
+                target = Target::Spawn(args.next().ok_or("--spawn needs a path")?.into())
+            }
+            Some("-h" | "--help") | None => return Err(USAGE.to_string()),
+            Some(command) => break command.to_string(),
+        }
+    };
+    let number = |arg: Option<String>, what: &str| {
+        arg.ok_or(format!("{} missing", what))?
+            .parse::<usize>()
+            .map_err(|e| format!("invalid {}: {}", what, e))
+    };
+    let command = match command.as_str() {
+        "print" => Command::Print,
+        "watch" => Command::Watch,
+        "insert" => {
+            let position = number(args.next(), "position")?;
+            let text = args.next().ok_or("text missing")?;
+            if !text.is_ascii() {
+                return Err("only ASCII text can be inserted".to_string());
+            }
+            Command::Insert { position, text }
+        }
+        "delete" => Command::Delete {
+            position: number(args.next(), "position")?,
+            count: match args.next() {
+                Some(count) => number(Some(count), "count")?,
+                None => 1,
+            },
+        },
+        "save" => Command::Save {
+            filename: args.next().ok_or("filename missing")?,
+        },
+        "reload" => Command::Reload,
+        other => return Err(format!("unknown command {:?}\n\n{}", other, USAGE)),
+    };
+    match args.next() {
+        Some(extra) => Err(format!("unexpected argument {:?}", extra)),
+        None => Ok((target, command)),
+    }
+}
+
+struct Client {
+    commands: FramedWrite<Box<dyn AsyncWrite + Send + Unpin>, LengthDelimitedCodec>,
+    events: FramedRead<Box<dyn AsyncRead + Send + Unpin>, LengthDelimitedCodec>,
+    /// The node we started, shut down when we're done.
+    node: Option<tokio::process::Child>,
+}
+
+impl Client {
+    async fn connect(target: Target) -> std::io::Result<Self> {
+        let (commands, events, node): (
+            Box<dyn AsyncWrite + Send + Unpin>,
+            Box<dyn AsyncRead + Send + Unpin>,
+            _,
+        ) = match target {
+            Target::Socket(path) => {
+                let stream = connect_socket(&path).await?;
+                let (events, commands) = tokio::io::split(stream);
+                (Box::new(commands), Box::new(events), None)
+            }
+            Target::Spawn(backend) => {
+                let mut node = tokio::process::Command::new(&backend)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::null())
+                    .kill_on_drop(true)
+                    .spawn()?;
+                let commands = node.stdin.take().expect("stdin is piped");
+                let events = node.stdout.take().expect("stdout is piped");
+                (Box::new(commands), Box::new(events), Some(node))
+            }
+        };
+        Ok(Self {
+            commands: FramedWrite::new(commands, LengthDelimitedCodec::new()),
+            events: FramedRead::new(events, LengthDelimitedCodec::new()),
+            node,
+        })
+    }
+
+    async fn send(&mut self, variant: client_command::Variant) -> std::io::Result<()> {
+        let command = ClientCommand {
+            variant: Some(variant),
+        };
+        self.commands.send(command.encode_to_vec().into()).await
+    }
+
+    /// The next event from the node, or None once it hung up.
+    async fn next_event(&mut self) -> std::io::Result<Option<server_event::Variant>> {
+        loop {
+            let Some(bytes) = self.events.next().await.transpose()? else {
+                return Ok(None);
+            };
+            let event = protocol::ServerEvent::decode(bytes)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            if let Some(variant) = event.variant {
+                return Ok(Some(variant));
+            }
+        }
+    }
+
+    async fn expect_event(&mut self) -> std::io::Result<server_event::Variant> {
+        self.next_event()
+            .await?
+            .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "node hung up"))
+    }
+
+    /// The document as of now. Every client is sent it when it attaches.
+    async fn document(&mut self) -> std::io::Result<Vec<u8>> {
+        loop {
+            if let server_event::Variant::State(state) = self.expect_event().await? {
+                return Ok(state.content);
+            }
+        }
+    }
+
+    /// Waits until the node handled everything sent so far. Commands are
+    /// handled in order, so the answer to a roster request comes after.
+    async fn flush(&mut self) -> std::io::Result<()> {
+        self.send(client_command::Variant::ListPeers(ListPeers {}))
+            .await?;
+        loop {
+            if let server_event::Variant::Roster(_) = self.expect_event().await? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Like `flush`, but fails if the node turned the edits down. A viewer
+    /// node answers them with its role.
+    async fn flush_edits(&mut self) -> std::io::Result<()> {
+        self.send(client_command::Variant::ListPeers(ListPeers {}))
+            .await?;
+        let mut refused = false;
+        loop {
+            match self.expect_event().await? {
+                server_event::Variant::Role(assignment)
+                    if assignment.role == protocol::Role::Viewer as i32 =>
+                {
+                    refused = true
+                }
+                server_event::Variant::Roster(_) if refused => {
+                    return Err(Error::new(
+                        ErrorKind::PermissionDenied,
+                        "the node is a viewer, its document can't be edited",
+                    ));
+                }
+                server_event::Variant::Roster(_) => return Ok(()),
+                _ => {}
+            }
+        }
+    }
+
+    /// Shuts down the node we started, letting it save the document first.
+    async fn close(mut self) -> std::io::Result<()> {
+        let Some(mut node) = self.node.take() else {
+            return Ok(());
+        };
+        self.send(client_command::Variant::Close(
+            protocol::CloseApplication {},
+        ))
+        .await?;
+        node.wait().await?;
+        Ok(())
+    }
+}
+
+async fn connect_socket(
+    path: &std::path::Path,
+) -> std::io::Result<impl AsyncRead + AsyncWrite + Send + use<>> {
+    #[cfg(unix)]
+    {
+        tokio::net::UnixStream::connect(path).await.map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!("can't reach a node at {}: {}", path.display(), e),
+            )
+        })
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        Err::<tokio::io::DuplexStream, _>(Error::new(
+            ErrorKind::Unsupported,
+            "IPC sockets are only supported on Unix, use --spawn",
+        ))
+    }
+}
+
+fn edit(position: usize, op_type: local_op::OpType) -> client_command::Variant {
+    client_command::Variant::Edit(LocalOp {
+        position: position as u32,
+        remote: false,
+        op_type: Some(op_type),
+    })
+}
+
+fn out_of_range(position: usize, len: usize) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!(
+            "position {} is past the end of the document ({} characters)",
+            position, len
+        ),
+    )
+}
+
+async fn run(client: &mut Client, command: Command) -> std::io::Result<()> {
+    let document = client.document().await?;
+    match command {
+        Command::Print => println!("{}", String::from_utf8_lossy(&document)),
+        Command::Watch => {
+            println!("{}", String::from_utf8_lossy(&document));
+            while let Some(event) = client.next_event().await? {
+                match event {
+                    server_event::Variant::Op(op) if op.remote => match op.op_type {
+                        Some(local_op::OpType::Insert(insert)) => {
+                            let value =
+                                char::from_u32(insert.value).unwrap_or(char::REPLACEMENT_CHARACTER);
+                            println!("insert {} {:?}", op.position, value);
+                        }
+                        // Removals carry the cursor position after the character.
+                        Some(local_op::OpType::Remove(_)) => {
+                            println!("delete {}", op.position.saturating_sub(1))
+                        }
+                        None => {}
+                    },
+                    server_event::Variant::State(state) => {
+                        println!("state {:?}", String::from_utf8_lossy(&state.content));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Command::Insert { position, text } => {
+            if position > document.len() {
+                return Err(out_of_range(position, document.len()));
+            }
+            for (offset, byte) in text.bytes().enumerate() {
+                let insert = LocalInsert { value: byte as u32 };
+                client
+                    .send(edit(position + offset, local_op::OpType::Insert(insert)))
+                    .await?;
+            }
+            client.flush_edits().await?;
+        }
+        Command::Delete { position, count } => {
+            if position + count > document.len() {
+                return Err(out_of_range(position + count, document.len()));
+            }
+            for _ in 0..count {
+                client
+                    .send(edit(position + 1, local_op::OpType::Remove(LocalRemove {})))
+                    .await?;
+            }
+            client.flush_edits().await?;
+        }
+        Command::Save { filename } => {
+            client
+                .send(client_command::Variant::Save(SaveDocument { filename }))
+                .await?;
+            client.flush().await?;
+        }
+        Command::Reload => {
+            client
+                .send(client_command::Variant::ReloadConfig(ReloadConfig {}))
+                .await?;
+            let reloaded = loop {
+                if let server_event::Variant::ConfigReloaded(reloaded) =
+                    client.expect_event().await?
+                {
+                    break reloaded;
+                }
+            };
+            if !reloaded.error.is_empty() {
+                return Err(Error::other(reloaded.error));
+            }
+            if reloaded.applied.is_empty() && reloaded.restart_needed.is_empty() {
+                println!("nothing changed");
+            }
+            for setting in reloaded.applied {
+                println!("applied {}", setting);
+            }
+            for setting in reloaded.restart_needed {
+                println!("restart needed for {}", setting);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let (target, command) = match parse_args(std::env::args().skip(1)) {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(2);
+        }
+    };
+    let result = async {
+        let mut client = Client::connect(target).await?;
+        run(&mut client, command).await?;
+        client.close().await
+    }
+    .await;
+    if let Err(e) = result {
+        eprintln!("dte: {}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,237 @@
+use crate::config::{self, ConfigError, NodeConfig};
+use crate::logging::LogLevel;
+use crate::types::PeerId;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+pub const USAGE: &str = "\
+usage: backend [OPTIONS]
+       backend --print-config [OPTIONS]
+       backend --replay TRACE
+
+Runs a node with its frontend on stdin/stdout. Options take precedence over
+the environment variable next to them, which takes precedence over the
+config file.
+
+options:
+  --config PATH           DTE_CONFIG          config file (DATA_DIR/config.toml)
+  --data-dir DIR          DTE_DATA_DIR        where the document and saved files go (./native)
+  --port PORT             DTE_PORT            TCP port peers dial
+  --discovery-port PORT   DTE_DISCOVERY_PORT  UDP port of discovery beacons
+  --websocket-port PORT   DTE_WEBSOCKET_PORT  port browser peers dial, if enabled
+  --peer-id ID            DTE_PEER_ID         this node's peer ID
+  --name NAME             DTE_NAME            name shown to peers
+  --room ROOM             DTE_ROOM            room to join
+  --log-level LEVEL       DTE_LOG             off, error, warn, info or debug
+  --relay                                     run headless as a relay
+  --print-config                              print the resolved config and exit
+  --replay TRACE                              replay a trace file and exit";
+
+/// Options taking a value, with the environment variable for each.
+const SETTINGS: [(&str, &str); 9] = [
+    ("--config", "DTE_CONFIG"),
+    ("--data-dir", "DTE_DATA_DIR"),
+    ("--port", "DTE_PORT"),
+    ("--discovery-port", "DTE_DISCOVERY_PORT"),
+    ("--websocket-port", "DTE_WEBSOCKET_PORT"),
+    ("--peer-id", "DTE_PEER_ID"),
+    ("--name", "DTE_NAME"),
+    ("--room", "DTE_ROOM"),
+    ("--log-level", "DTE_LOG"),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mode {
+    Run,
+    PrintConfig,
+    Replay(PathBuf),
+    Help,
+}
+
+/// Settings from the command line and the environment. Every one that's set
+/// replaces the config file's.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Overrides {
+    pub data_dir: Option<PathBuf>,
+    pub tcp_port: Option<u16>,
+    pub discovery_port: Option<u16>,
+    pub websocket_port: Option<u16>,
+    pub peer_id: Option<PeerId>,
+    pub display_name: Option<String>,
+    pub room: Option<String>,
+    pub log_level: Option<LogLevel>,
+    pub relay: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Args {
+    pub mode: Mode,
+    config: Option<PathBuf>,
+    pub overrides: Overrides,
+}
+
+fn value<T: FromStr>(value: &str) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| format!("invalid value {:?}: {}", value, e))
+}
+
+impl Args {
+    /// Reads `args`, without the program name, on top of the environment as
+    /// seen through `env`. Empty variables count as unset.
+    pub fn parse(
+        args: impl IntoIterator<Item = String>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, String> {
+        let mut parsed = Self {
+            mode: Mode::Run,
+            config: None,
+            overrides: Overrides::default(),
+        };
+        for (option, var) in SETTINGS {
+            if let Some(value) = env(var).filter(|value| !value.is_empty()) {
+                parsed
+                    .set(option, value)
+                    .map_err(|e| format!("{}: {}", var, e))?;
+            }
+        }
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--relay" => parsed.overrides.relay = true,
+                "--print-config" => parsed.mode = Mode::PrintConfig,
+                "--replay" => {
+                    let path = args.next().ok_or("--replay needs a trace file")?;
+                    parsed.mode = Mode::Replay(path.into());
+                }
+                "-h" | "--help" => {
+                    parsed.mode = Mode::Help;
+                    return Ok(parsed);
+                }
+                option if SETTINGS.iter().any(|(name, _)| *name == option) => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| format!("{} needs a value", option))?;
+                    parsed
+                        .set(option, value)
+                        .map_err(|e| format!("{}: {}", option, e))?;
+                }
+                other => return Err(format!("unknown argument {:?}\n\n{}", other, USAGE)),
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn set(&mut self, option: &str, arg: String) -> Result<(), String> {
+        let overrides = &mut self.overrides;
+        match option {
+            "--config" => self.config = Some(arg.into()),
+            "--data-dir" => overrides.data_dir = Some(arg.into()),
+            "--port" => overrides.tcp_port = Some(value(&arg)?),
+            "--discovery-port" => overrides.discovery_port = Some(value(&arg)?),
+            "--websocket-port" => overrides.websocket_port = Some(value(&arg)?),
+            "--peer-id" => overrides.peer_id = Some(value(&arg)?),
+            "--name" => overrides.display_name = Some(arg),
+            "--room" => overrides.room = Some(arg),
+            "--log-level" => overrides.log_level = Some(value(&arg)?),
+            _ => unreachable!("{} isn't in SETTINGS", option),
+        }
+        Ok(())
+    }
+
+    /// `--config`, or `config.toml` in the data directory.
+    pub fn config_path(&self) -> PathBuf {
+        match (&self.config, &self.overrides.data_dir) {
+            (Some(path), _) => path.clone(),
+            (None, Some(dir)) => dir.join("config.toml"),
+            (None, None) => Path::new(config::DEFAULT_DATA_DIR).join("config.toml"),
+        }
+    }
+
+    /// Loads the config file, creating it if needed, applies the overrides
+    /// and validates the result. Every path in it is resolved. Printing the
+    /// config leaves the file as it is, or missing.
+    pub fn load(&self) -> Result<NodeConfig, ConfigError> {
+        let path = self.config_path();
+        let config = match self.mode {
+            Mode::PrintConfig => config::preview(&path)?,
+            _ => config::load_or_create(&path)?,
+        };
+        self.resolve(config)
+    }
+
+    /// Like `load`, for a node already `running`. The file is only read: if
+    /// it was deleted it isn't recreated, and if it lost its peer ID or
+    /// static key the running ones are kept.
+    pub fn reload(&self, running: &NodeConfig) -> Result<NodeConfig, ConfigError> {
+        let (mut config, missing) = config::read(&self.config_path())?;
+        if missing.peer_id {
+            config.peer_id = running.peer_id;
+        }
+        if missing.static_key {
+            config.static_key = running.static_key.clone();
+        }
+        self.resolve(config)
+    }
+
+    fn resolve(&self, mut config: NodeConfig) -> Result<NodeConfig, ConfigError> {
+        let path = self.config_path();
+        self.overrides.apply(&mut config);
+        let data_dir = config
+            .data_dir
+            .get_or_insert_with(|| match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+                _ => PathBuf::from("."),
+            })
+            .clone();
+        config.ipc.socket_path = Some(config.ipc.socket_path(&data_dir));
+        config.trace.path = Some(config.trace.path(&data_dir));
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl Overrides {
+    pub fn apply(&self, config: &mut NodeConfig) {
+        if let Some(dir) = &self.data_dir {
+            config.data_dir = Some(dir.clone());
+        }
+        if let Some(port) = self.tcp_port {
+            config.tcp_port = port;
+        }
+        if let Some(port) = self.discovery_port {
+            config.udp_discovery_port = port;
+        }
+        if let Some(port) = self.websocket_port {
+            config.websocket.port = port;
+        }
+        if let Some(peer_id) = self.peer_id {
+            config.peer_id = peer_id;
+        }
+        if let Some(name) = &self.display_name {
+            config.display_name = Some(name.clone());
+        }
+        if let Some(room) = &self.room {
+            config.room = room.clone();
+        }
+        if let Some(level) = self.log_level {
+            config.log_level = level;
+        }
+        if self.relay {
+            config.relay.enabled = true;
+        }
+    }
+}
+
+/// The config as TOML, without the static key and workspace secret.
+pub fn print_config(config: &NodeConfig) -> Result<String, ConfigError> {
+    let mut config = config.clone();
+    let hidden = || "(hidden)".to_string();
+    config.static_key = config.static_key.as_ref().map(|_| hidden());
+    config.workspace_secret = config.workspace_secret.as_ref().map(|_| hidden());
+    Ok(toml::to_string_pretty(&config)?)
+}
@@ -1,15 +1,282 @@
-use crate::types::PeerId;
+use crate::logging::LogLevel;
+use crate::transport::StaticKey;
+use crate::transport::handshake::MAX_ROOM_LEN;
+use crate::types::{PeerId, RESERVED_PEER};
+use crate::{info, warn};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fmt;
 use std::fs;
-use std::path::Path;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Where a node keeps its config and files unless told otherwise.
+pub const DEFAULT_DATA_DIR: &str = "./native";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct NodeConfig {
+    /// A random ID is generated for files without one, and written back.
+    #[serde(default = "random_peer_id")]
     pub peer_id: PeerId,
+    /// Shown to peers next to this node's ID.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    /// Only nodes in the same room link up and share a document.
+    #[serde(default = "default_room")]
+    pub room: String,
+    #[serde(default = "default_tcp_port")]
     pub tcp_port: u16,
+    #[serde(default = "default_discovery_port")]
     pub udp_discovery_port: u16,
+    /// Where the document and saved files go, and by default the IPC socket
+    /// and trace. `--data-dir` comes first, then `DTE_DATA_DIR`, then this;
+    /// with none of them, the directory holding the config file, which is
+    /// `./native` unless `--config` says otherwise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub log_level: LogLevel,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub workspace_secret: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub static_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trusted_fingerprints: Vec<String>,
+    /// `host:port` addresses dialed on startup and redialed whenever the
+    /// link drops, for networks where broadcast discovery can't reach.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub static_peers: Vec<String>,
+    #[serde(default)]
+    pub discovery: DiscoveryConfig,
+    #[serde(default)]
+    pub heartbeat: HeartbeatConfig,
+    /// How often document digests are exchanged with each peer.
+    #[serde(default = "default_anti_entropy_interval")]
+    pub anti_entropy_interval_ms: u64,
+    #[serde(default)]
+    pub relay: RelayConfig,
+    #[serde(default)]
+    pub websocket: WebSocketConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub roles: RolesConfig,
+    #[serde(default)]
+    pub ipc: IpcConfig,
+    #[serde(default)]
+    pub trace: TraceConfig,
+}
+
+/// What a peer may do to the document.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Edits, and may repair the document for everyone.
+    Owner,
+    Editor,
+    /// Follows the document without changing it.
+    Viewer,
+}
+
+impl Role {
+    pub fn can_edit(self) -> bool {
+        self != Role::Viewer
+    }
+
+    pub fn can_repair(self) -> bool {
+        self == Role::Owner
+    }
+}
+
+/// Roles by static key fingerprint, checked once the handshake proved the
+/// peer holds that key. A node takes its own role from the same table, so
+/// every node of a workspace should list the same roles.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct RolesConfig {
+    /// Role of anyone not listed. Everyone owns the document unless
+    /// configured otherwise.
+    pub default: Role,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub peers: BTreeMap<String, Role>,
+}
+
+impl Default for RolesConfig {
+    fn default() -> Self {
+        Self {
+            default: Role::Owner,
+            peers: BTreeMap::new(),
+        }
+    }
+}
+
+impl RolesConfig {
+    pub fn role_of(&self, fingerprint: &str) -> Role {
+        self.peers.get(fingerprint).copied().unwrap_or(self.default)
+    }
+}
+
+/// Bounds on what a peer can make this node read and allocate. A peer
+/// breaking them is disconnected and its address banned for `ban_ms`.
+///
+/// Peers must agree on these: a node refuses to send a frame above its own
+/// `max_frame_bytes`, but a smaller limit on the other end gets it banned.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// Largest frame on a link, both as sent and once decompressed.
+    pub max_frame_bytes: usize,
+    /// Most keys in a single identifier.
+    pub max_identifier_depth: usize,
+    /// Most keys across all identifiers of one snapshot, which is what a
+    /// FullSync or RangeSync costs to decode.
+    pub max_snapshot_keys: usize,
+    /// Larger discovery datagrams are dropped unread.
+    pub max_beacon_bytes: usize,
+    pub ban_ms: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_frame_bytes: 8 << 20,
+            max_identifier_depth: 128,
+            max_snapshot_keys: 4 << 20,
+            max_beacon_bytes: 256,
+            ban_ms: 600_000,
+        }
+    }
+}
+
+/// Lets local clients, such as a CLI or an editor plugin, attach to a running
+/// node over a Unix domain socket, next to the frontend on stdin/stdout.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct IpcConfig {
+    pub enabled: bool,
+    /// `dte.sock` in the data directory unless set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub socket_path: Option<PathBuf>,
+}
+
+impl IpcConfig {
+    pub fn socket_path(&self, data_dir: &Path) -> PathBuf {
+        self.socket_path
+            .clone()
+            .unwrap_or_else(|| data_dir.join("dte.sock"))
+    }
+}
+
+/// Records every command from frontends and every op to and from peers in
+/// `path`, for `backend --replay`. Each run appends to the file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct TraceConfig {
+    pub enabled: bool,
+    /// `trace.bin` in the data directory unless set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+}
+
+impl TraceConfig {
+    pub fn path(&self, data_dir: &Path) -> PathBuf {
+        self.path
+            .clone()
+            .unwrap_or_else(|| data_dir.join("trace.bin"))
+    }
+}
+
+/// Lets browsers join as peers. They speak the TCP protocol, byte for byte,
+/// inside binary WebSocket messages.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct WebSocketConfig {
+    pub enabled: bool,
+    pub port: u16,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 2138,
+        }
+    }
+}
+
+/// Headless mode: no frontend on stdin/stdout, just a long-lived node that
+/// peers dial by address. It keeps a replica of the document, saves it every
+/// `save_interval_ms` and relays ops between peers.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct RelayConfig {
+    pub enabled: bool,
+    pub save_interval_ms: u64,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            save_interval_ms: 30_000,
+        }
+    }
+}
+
+/// A link that stays silent for `timeout_ms`, pongs included, is dropped.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct HeartbeatConfig {
+    pub interval_ms: u64,
+    pub timeout_ms: u64,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval_ms: 5_000,
+            timeout_ms: 15_000,
+        }
+    }
+}
+
+fn random_peer_id() -> PeerId {
+    rand::rng().random_range(RESERVED_PEER + 1..=PeerId::MAX)
+}
+
+fn default_room() -> String {
+    "default".to_string()
+}
+
+fn default_tcp_port() -> u16 {
+    2137
+}
+
+fn default_discovery_port() -> u16 {
+    9000
+}
+
+fn default_anti_entropy_interval() -> u64 {
+    10_000
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+    pub enabled: bool,
+    pub broadcast_address: Ipv4Addr,
+    pub interval_ms: u64,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            broadcast_address: Ipv4Addr::BROADCAST,
+            interval_ms: 1000,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -17,6 +284,8 @@ pub enum ConfigError {
     Io(std::io::Error),
     Parse(toml::de::Error),
     Serialize(toml::ser::Error),
+    /// Every problem `NodeConfig::validate` found, as `key: problem`.
+    Invalid(Vec<String>),
 }
 
 impl fmt::Display for ConfigError {
@@ -25,6 +294,13 @@ impl fmt::Display for ConfigError {
             ConfigError::Io(err) => write!(f, "IO error: {}", err),
             ConfigError::Parse(err) => write!(f, "Parse error: {}", err),
             ConfigError::Serialize(err) => write!(f, "Serialize error: {}", err),
+            ConfigError::Invalid(problems) => {
+                write!(f, "Invalid settings:")?;
+                for problem in problems {
+                    write!(f, "\n  {}", problem)?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -35,6 +311,7 @@ impl std::error::Error for ConfigError {
             ConfigError::Io(err) => Some(err),
             ConfigError::Parse(err) => Some(err),
             ConfigError::Serialize(err) => Some(err),
+            ConfigError::Invalid(_) => None,
         }
     }
 }
@@ -57,27 +334,323 @@ impl From<toml::ser::Error> for ConfigError {
     }
 }
 
-pub fn load_or_create(file_path: &str) -> Result<NodeConfig, ConfigError> {
-    let path = Path::new(file_path);
+/// Whether `s` looks like `StaticKey::fingerprint` output: eight groups of
+/// four lowercase hex digits.
+fn is_fingerprint(s: &str) -> bool {
+    let groups: Vec<_> = s.split(':').collect();
+    groups.len() == 8
+        && groups.iter().all(|group| {
+            group.len() == 4
+                && group
+                    .bytes()
+                    .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+        })
+}
 
-    if path.exists() {
-        let content = fs::read_to_string(path)?;
-        let config: NodeConfig = toml::from_str(&content)?;
-        Ok(config)
-    } else {
-        let mut rng = rand::rng();
-
-        let config = NodeConfig {
-            peer_id: rng.random(),
-            tcp_port: 2137,
-            udp_discovery_port: 9000,
+fn is_host_port(address: &str) -> bool {
+    match address.rsplit_once(':') {
+        Some((host, port)) => !host.is_empty() && port.parse::<u16>().is_ok_and(|port| port != 0),
+        None => false,
+    }
+}
+
+impl NodeConfig {
+    /// Checks what deserializing doesn't: ranges, settings that clash and the
+    /// format of keys, fingerprints and addresses. Lists every problem, not
+    /// just the first.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        let mut positive = |key: &str, value: u64| {
+            if value == 0 {
+                problems.push(format!("{}: must be above 0", key));
+            }
         };
+        positive("anti_entropy_interval_ms", self.anti_entropy_interval_ms);
+        positive("discovery.interval_ms", self.discovery.interval_ms);
+        positive("heartbeat.interval_ms", self.heartbeat.interval_ms);
+        positive("relay.save_interval_ms", self.relay.save_interval_ms);
+        positive("limits.max_frame_bytes", self.limits.max_frame_bytes as u64);
+        positive(
+            "limits.max_identifier_depth",
+            self.limits.max_identifier_depth as u64,
+        );
+        positive(
+            "limits.max_snapshot_keys",
+            self.limits.max_snapshot_keys as u64,
+        );
+        positive(
+            "limits.max_beacon_bytes",
+            self.limits.max_beacon_bytes as u64,
+        );
+
+        if self.peer_id == RESERVED_PEER {
+            problems.push(format!(
+                "peer_id: {} is reserved, pick one from {} to {}",
+                RESERVED_PEER,
+                RESERVED_PEER + 1,
+                PeerId::MAX
+            ));
+        }
+        if self.room.is_empty() || self.room.len() > MAX_ROOM_LEN {
+            problems.push(format!("room: must be 1 to {} bytes long", MAX_ROOM_LEN));
+        }
+        if self.tcp_port == 0 {
+            problems.push("tcp_port: must not be 0, peers need a known port to dial".to_string());
+        }
+        if self.discovery.enabled && self.udp_discovery_port == 0 {
+            problems
+                .push("udp_discovery_port: must not be 0 while discovery is enabled".to_string());
+        }
+        if self.websocket.enabled && self.websocket.port == 0 {
+            problems.push("websocket.port: must not be 0 while enabled".to_string());
+        } else if self.websocket.enabled && self.websocket.port == self.tcp_port {
+            problems.push(format!(
+                "websocket.port: {} is already tcp_port",
+                self.websocket.port
+            ));
+        }
+        if self.heartbeat.timeout_ms <= self.heartbeat.interval_ms {
+            problems.push(format!(
+                "heartbeat.timeout_ms: must be longer than interval_ms ({})",
+                self.heartbeat.interval_ms
+            ));
+        }
+        if let Some(Err(e)) = self.static_key.as_deref().map(StaticKey::from_hex) {
+            problems.push(format!("static_key: {}", e));
+        }
+        for address in &self.static_peers {
+            if !is_host_port(address) {
+                problems.push(format!("static_peers: {:?} isn't host:port", address));
+            }
+        }
+        let fingerprints = self
+            .trusted_fingerprints
+            .iter()
+            .map(|fingerprint| ("trusted_fingerprints", fingerprint))
+            .chain(
+                self.roles
+                    .peers
+                    .keys()
+                    .map(|fingerprint| ("roles.peers", fingerprint)),
+            );
+        for (key, fingerprint) in fingerprints {
+            if !is_fingerprint(fingerprint) {
+                problems.push(format!(
+                    "{}: {:?} isn't a fingerprint as printed on startup, like \
+                     3f1a:09bc:5e77:d012:8c4a:f1e0:2b9d:7a36",
+                    key, fingerprint
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+}
+
+/// How a reloaded config differs from the running one, by key.
+#[derive(Debug, Default, PartialEq)]
+pub struct Changes {
+    /// Take effect as soon as the config is reloaded.
+    pub live: Vec<&'static str>,
+    /// Only take effect once the node restarts.
+    pub restart: Vec<&'static str>,
+}
 
-        let toml_string = toml::to_string_pretty(&config)?;
+fn changed<const N: usize>(settings: [(&'static str, bool); N]) -> Vec<&'static str> {
+    settings
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(key, _)| key)
+        .collect()
+}
+
+/// Sorts what changed between `old` and `new` by whether a running node can
+/// apply it. Static peers are dialed as soon as they're added, but one that
+/// was removed is only dropped on restart.
+pub fn changes(old: &NodeConfig, new: &NodeConfig) -> Changes {
+    let added_peers = new
+        .static_peers
+        .iter()
+        .any(|p| !old.static_peers.contains(p));
+    let removed_peers = old
+        .static_peers
+        .iter()
+        .any(|p| !new.static_peers.contains(p));
+    Changes {
+        live: changed([
+            ("display_name", old.display_name != new.display_name),
+            ("log_level", old.log_level != new.log_level),
+            (
+                "trusted_fingerprints",
+                old.trusted_fingerprints != new.trusted_fingerprints,
+            ),
+            ("roles", old.roles != new.roles),
+            ("static_peers", added_peers),
+            ("heartbeat", old.heartbeat != new.heartbeat),
+            ("limits", old.limits != new.limits),
+            (
+                "anti_entropy_interval_ms",
+                old.anti_entropy_interval_ms != new.anti_entropy_interval_ms,
+            ),
+            (
+                "relay.save_interval_ms",
+                old.relay.save_interval_ms != new.relay.save_interval_ms,
+            ),
+        ]),
+        restart: changed([
+            ("peer_id", old.peer_id != new.peer_id),
+            ("room", old.room != new.room),
+            ("tcp_port", old.tcp_port != new.tcp_port),
+            (
+                "udp_discovery_port",
+                old.udp_discovery_port != new.udp_discovery_port,
+            ),
+            ("data_dir", old.data_dir != new.data_dir),
+            (
+                "workspace_secret",
+                old.workspace_secret != new.workspace_secret,
+            ),
+            ("static_key", old.static_key != new.static_key),
+            ("static_peers", removed_peers),
+            ("discovery", old.discovery != new.discovery),
+            ("relay.enabled", old.relay.enabled != new.relay.enabled),
+            ("websocket", old.websocket != new.websocket),
+            ("ipc", old.ipc != new.ipc),
+            ("trace", old.trace != new.trace),
+        ]),
+    }
+}
+
+/// Keys a file may leave out that get a fresh value instead of a default.
+#[derive(Deserialize)]
+struct Generated {
+    peer_id: Option<toml::Value>,
+    static_key: Option<toml::Value>,
+}
+
+/// Which of the generated settings a file left out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Missing {
+    pub peer_id: bool,
+    pub static_key: bool,
+}
 
-        fs::write(path, toml_string)?;
+/// Reads the config at `path` without touching the file. Missing settings
+/// take their defaults, a missing peer ID a random one. Unknown keys are
+/// logged and ignored.
+pub fn read(path: &Path) -> Result<(NodeConfig, Missing), ConfigError> {
+    parse(&fs::read_to_string(path)?, path)
+}
+
+fn parse(content: &str, path: &Path) -> Result<(NodeConfig, Missing), ConfigError> {
+    let config: NodeConfig =
+        serde_ignored::deserialize(toml::Deserializer::parse(content)?, |key| {
+            warn!("Ignoring unknown setting {} in {}", key, path.display());
+        })?;
+    let generated: Generated = toml::from_str(content)?;
+    let missing = Missing {
+        peer_id: generated.peer_id.is_none(),
+        static_key: generated.static_key.is_none(),
+    };
+    Ok((config, missing))
+}
+
+/// Reads the config at `path` as a node starts. A missing peer ID or static
+/// key is generated and added to the file, so it stays the same from run to
+/// run; the rest of the file is left as written. Reloads only `read`.
+pub fn load(path: &Path) -> Result<NodeConfig, ConfigError> {
+    let content = fs::read_to_string(path)?;
+    let (mut config, missing) = parse(&content, path)?;
+    if !missing.peer_id && !missing.static_key {
+        return Ok(config);
+    }
+    // Top-level keys must come before the first table, so they go first.
+    let mut generated = String::new();
+    if missing.peer_id {
+        generated += &format!("peer_id = {}\n", config.peer_id);
+        info!(
+            "Generated peer ID {} in: {}",
+            config.peer_id,
+            path.display()
+        );
+    }
+    if missing.static_key {
+        let key = StaticKey::generate().to_hex();
+        generated += &format!("static_key = \"{}\"\n", key);
+        config.static_key = Some(key);
+        info!("Generated new static key in: {}", path.display());
+    }
+    write_private(path, &(generated + &content))?;
+    Ok(config)
+}
+
+/// Writes `content` to `path`, readable by its owner only, as the file holds
+/// the static key and maybe the workspace secret.
+fn write_private(path: &Path, content: &str) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    std::io::Write::write_all(&mut file, content.as_bytes())
+}
+
+/// What `load_or_create` would return, without creating or writing the file.
+pub fn preview(path: &Path) -> Result<NodeConfig, ConfigError> {
+    if !path.exists() {
+        return Ok(fresh());
+    }
+    let (mut config, missing) = read(path)?;
+    if missing.static_key {
+        config.static_key = Some(StaticKey::generate().to_hex());
+    }
+    Ok(config)
+}
+
+pub fn load_or_create(path: &Path) -> Result<NodeConfig, ConfigError> {
+    if path.exists() {
+        return load(path);
+    }
+    let config = fresh();
+    let toml_string = toml::to_string_pretty(&config)?;
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    write_private(path, &toml_string)?;
+
+    info!("Generated new config at: {}", path.display());
+    Ok(config)
+}
 
-        eprintln!("Generated new config at: {}", file_path);
-        Ok(config)
+/// Defaults, with a new identity.
+fn fresh() -> NodeConfig {
+    NodeConfig {
+        peer_id: random_peer_id(),
+        display_name: None,
+        room: default_room(),
+        tcp_port: default_tcp_port(),
+        udp_discovery_port: default_discovery_port(),
+        data_dir: None,
+        log_level: LogLevel::default(),
+        workspace_secret: None,
+        static_key: Some(StaticKey::generate().to_hex()),
+        trusted_fingerprints: Vec::new(),
+        static_peers: Vec::new(),
+        discovery: DiscoveryConfig::default(),
+        heartbeat: HeartbeatConfig::default(),
+        anti_entropy_interval_ms: default_anti_entropy_interval(),
+        relay: RelayConfig::default(),
+        websocket: WebSocketConfig::default(),
+        limits: LimitsConfig::default(),
+        roles: RolesConfig::default(),
+        ipc: IpcConfig::default(),
+        trace: TraceConfig::default(),
     }
 }
@@ -0,0 +1,236 @@
+use crate::debug;
+use crate::protocol::{self, OpId, PeerSyncOp};
+use crate::session::Session;
+use crate::types::PeerId;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// How many recent op IDs each node remembers. An op only needs to be
+/// remembered for as long as it can still be in flight along another path;
+/// anything arriving later is harmless to apply again, since CRDT ops are
+/// idempotent.
+pub const SEEN_CAPACITY: usize = 1 << 16;
+
+/// Sliding window of operation IDs this node has already applied, so that
+/// ops relayed along several paths are applied and forwarded only once.
+pub struct SeenOps {
+    seen: HashSet<OpId>,
+    order: VecDeque<OpId>,
+    capacity: usize,
+}
+
+impl SeenOps {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            seen: HashSet::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Records `id` and returns true if it wasn't seen before.
+    pub fn insert(&mut self, id: OpId) -> bool {
+        if !self.seen.insert(id) {
+            return false;
+        }
+        if self.order.len() == self.capacity
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.seen.remove(&oldest);
+        }
+        self.order.push_back(id);
+        true
+    }
+}
+
+/// What to do after receiving an op from a peer.
+#[derive(Default)]
+pub struct Outcome {
+    /// Update for the frontend.
+    pub event: Option<protocol::ServerEvent>,
+    /// Goes back to the peer the op came from.
+    pub reply: Option<PeerSyncOp>,
+    /// Goes to every other peer.
+    pub relay: Option<PeerSyncOp>,
+    /// Digest buckets found to differ from the sender's, now being reconciled.
+    pub diverged: Vec<u32>,
+}
+
+/// Relay and anti-entropy rules for ops arriving from peers. Shared by the
+/// service and the simulator, so both behave the same.
+pub struct Gossip {
+    seen: SeenOps,
+    // Buckets that differed in the last digest from each peer.
+    suspects: HashMap<PeerId, Vec<u32>>,
+    // Last digest each peer sent, kept across reconnects. The peer holds at
+    // least the state it describes, so a reconnect resumes from there.
+    acked: HashMap<PeerId, Vec<u64>>,
+    // Peers sent a resume rather than a snapshot, until their first op.
+    resuming: HashSet<PeerId>,
+}
+
+impl Default for Gossip {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gossip {
+    pub fn new() -> Self {
+        Self {
+            seen: SeenOps::new(SEEN_CAPACITY),
+            suspects: HashMap::new(),
+            acked: HashMap::new(),
+            resuming: HashSet::new(),
+        }
+    }
+
+    /// Marks a locally made edit as seen, so it isn't applied again when a
+    /// peer relays it back.
+    pub fn local_op(&mut self, op: &PeerSyncOp) {
+        if let Some(op_id) = op.op_id() {
+            self.seen.insert(op_id);
+        }
+    }
+
+    pub fn digest(&self, session: &Session) -> PeerSyncOp {
+        PeerSyncOp::Digest {
+            buckets: session.digest(),
+        }
+    }
+
+    /// What to send first on a new link to `peer_id`. A peer we had a digest
+    /// from only gets the ranges that changed here since, and answers with
+    /// the ones that changed on its side; anyone else gets a snapshot.
+    pub fn initial_sync(&mut self, session: &Session, peer_id: PeerId) -> PeerSyncOp {
+        let Some(acked) = self.acked.get(&peer_id) else {
+            self.resuming.remove(&peer_id);
+            return PeerSyncOp::FullSync {
+                state: session.get_doc_snapshot(),
+            };
+        };
+        let changed: Vec<u32> = (session.digest().iter().zip(acked))
+            .enumerate()
+            .filter(|(_, (ours, theirs))| ours != theirs)
+            .map(|(idx, _)| idx as u32)
+            .collect();
+        self.resuming.insert(peer_id);
+        PeerSyncOp::RangeSync {
+            state: session.slice(&changed),
+            buckets: changed,
+            reply: true,
+        }
+    }
+
+    /// Forgets a dropped link. What the peer acknowledged is kept, for when
+    /// it comes back.
+    pub fn forget(&mut self, peer_id: PeerId) {
+        self.suspects.remove(&peer_id);
+        self.resuming.remove(&peer_id);
+    }
+
+    /// Edits are applied and relayed the first time their ID is seen. State
+    /// exchanges are relayed only if merging them taught us something, which
+    /// stops once every replica holds the same entries.
+    pub fn receive(&mut self, session: &mut Session, from: PeerId, op: PeerSyncOp) -> Outcome {
+        let resumed = self.resuming.remove(&from);
+        match op {
+            PeerSyncOp::Digest { buckets } => {
+                let ours = session.digest();
+                let diverged = self.compare(from, &ours, &buckets);
+                if buckets.len() == ours.len() {
+                    self.acked.insert(from, buckets);
+                }
+                if diverged.is_empty() {
+                    return Outcome::default();
+                }
+                Outcome {
+                    reply: Some(PeerSyncOp::RangeSync {
+                        state: session.slice(&diverged),
+                        buckets: diverged.clone(),
+                        reply: true,
+                    }),
+                    diverged,
+                    ..Outcome::default()
+                }
+            }
+            PeerSyncOp::RangeSync {
+                buckets,
+                state,
+                reply,
+            } => {
+                let reply = reply.then(|| PeerSyncOp::RangeSync {
+                    state: session.slice(&buckets),
+                    buckets: buckets.clone(),
+                    reply: false,
+                });
+                let before = session.entry_counts();
+                let event = session.apply_peer_sync_op(
+                    from,
+                    PeerSyncOp::RangeSync {
+                        buckets: buckets.clone(),
+                        state,
+                        reply: false,
+                    },
+                );
+                let relay = (session.entry_counts() != before).then(|| PeerSyncOp::RangeSync {
+                    state: session.slice(&buckets),
+                    buckets,
+                    reply: false,
+                });
+                Outcome {
+                    event,
+                    reply,
+                    relay,
+                    ..Outcome::default()
+                }
+            }
+            PeerSyncOp::FullSync { .. } => {
+                let before = session.entry_counts();
+                let event = session.apply_peer_sync_op(from, op);
+                let relay = (session.entry_counts() != before).then(|| PeerSyncOp::FullSync {
+                    state: session.get_doc_snapshot(),
+                });
+                // A peer answering our resume with a snapshot lost track of
+                // us, so it needs everything rather than what changed.
+                let reply = resumed.then(|| PeerSyncOp::FullSync {
+                    state: session.get_doc_snapshot(),
+                });
+                Outcome {
+                    event,
+                    reply,
+                    relay,
+                    ..Outcome::default()
+                }
+            }
+            PeerSyncOp::Insert { op_id, .. } | PeerSyncOp::Remove { op_id, .. } => {
+                // Edits a peer wasn't allowed to make go no further.
+                if !session.accepts(from, &op) || !self.seen.insert(op_id) {
+                    return Outcome::default();
+                }
+                Outcome {
+                    relay: Some(op.clone()),
+                    event: session.apply_peer_sync_op(from, op),
+                    ..Outcome::default()
+                }
+            }
+        }
+    }
+
+    /// Only buckets that differ in two digests in a row are reconciled. A
+    /// single mismatch is usually just an edit still in flight.
+    fn compare(&mut self, from: PeerId, ours: &[u64], theirs: &[u64]) -> Vec<u32> {
+        if ours.len() != theirs.len() {
+            debug!("Peer {} sent a digest of {} buckets", from, theirs.len());
+            return Vec::new();
+        }
+        let mismatched: Vec<u32> = (0..ours.len())
+            .filter(|&idx| ours[idx] != theirs[idx])
+            .map(|idx| idx as u32)
+            .collect();
+        let previous = self.suspects.insert(from, mismatched.clone());
+        mismatched
+            .into_iter()
+            .filter(|bucket| previous.as_ref().is_some_and(|prev| prev.contains(bucket)))
+            .collect()
+    }
+}
@@ -0,0 +1,64 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// How much a node writes to stderr. Each level includes the ones above it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    /// Failures the node can't work around: files it can't write, listeners
+    /// that crashed.
+    Error,
+    /// Misbehaving peers, dropped links and rejected edits.
+    Warn,
+    /// Links, listeners and other changes of state.
+    #[default]
+    Info,
+    /// Every edit and digest, as it's handled.
+    Debug,
+}
+
+const NAMES: [(&str, LogLevel); 5] = [
+    ("off", LogLevel::Off),
+    ("error", LogLevel::Error),
+    ("warn", LogLevel::Warn),
+    ("info", LogLevel::Info),
+    ("debug", LogLevel::Debug),
+];
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        NAMES
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(s))
+            .map(|(_, level)| *level)
+            .ok_or_else(|| {
+                format!(
+                    "unknown log level {:?}, expected off, error, warn, info or debug",
+                    s
+                )
+            })
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (name, _) = NAMES.iter().find(|(_, level)| level == self).unwrap();
+        f.write_str(name)
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+/// Applies to every node in the process.
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: LogLevel) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
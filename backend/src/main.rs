@@ -1,29 +1,78 @@
+mod cli;
 mod config;
+mod gossip;
+mod logging;
 mod macros;
+mod outbox;
+mod peers;
 mod protocol;
+mod rooms;
 mod service;
 mod session;
+#[cfg(test)]
+mod sim;
 mod state;
 #[cfg(test)]
 mod tests;
+mod trace;
 mod transport;
 mod types;
 
 #[tokio::main]
 async fn main() {
-    let config = match config::load_or_create("./native/config.toml") {
+    let args = match cli::Args::parse(std::env::args().skip(1), |var| std::env::var(var).ok()) {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(2);
+        }
+    };
+    if args.mode == cli::Mode::Help {
+        println!("{}", cli::USAGE);
+        std::process::exit(0);
+    }
+    if let Some(level) = args.overrides.log_level {
+        logging::set_level(level);
+    }
+
+    if let cli::Mode::Replay(path) = &args.mode {
+        if let Err(e) = trace::replay_file(path) {
+            error!("CRITICAL: Failed to replay {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+        std::process::exit(0);
+    }
+
+    let config = match args.load() {
         Ok(cfg) => cfg,
         Err(e) => {
-            eprintln!("CRITICAL: Failed to load config: {}", e);
+            error!(
+                "CRITICAL: Failed to load config from {}: {}",
+                args.config_path().display(),
+                e
+            );
             std::process::exit(1);
         }
     };
+    logging::set_level(config.log_level);
+
+    if args.mode == cli::Mode::PrintConfig {
+        match cli::print_config(&config) {
+            Ok(toml) => print!("{}", toml),
+            Err(e) => {
+                error!("CRITICAL: Failed to print config: {}", e);
+                std::process::exit(1);
+            }
+        }
+        std::process::exit(0);
+    }
 
-    if let Err(_) = service::run(config).await {
-        eprintln!("CRITICAL: Service crashed unexpectedly");
+    let runtime = service::Runtime::native(&config, args);
+    if service::run(config, runtime).await.is_err() {
+        error!("CRITICAL: Service crashed unexpectedly");
         std::process::exit(2);
     }
 
-    eprintln!("Service stopped gracefully.");
+    info!("Service stopped gracefully.");
     std::process::exit(0);
 }
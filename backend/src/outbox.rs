@@ -0,0 +1,138 @@
+use crate::protocol::PeerSyncOp;
+use crate::state::Doc;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// How many ops may wait for one peer before the queue is replaced by a
+/// snapshot. A snapshot of the whole document is cheaper to send than this
+/// many single-character ops.
+pub const MAX_PENDING: usize = 1024;
+
+/// What happened to an op handed to [`Outbox::push`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Push {
+    Queued,
+    /// The peer fell too far behind; everything queued was replaced by a
+    /// snapshot of the document.
+    Resynced,
+    /// The peer fell behind again before it caught up with the last
+    /// snapshot. The link should be dropped.
+    Overflowed,
+}
+
+/// Ordered outbound queue for one peer link. The event loop pushes without
+/// waiting and the link's writer drains it, so ops go out in the order they
+/// were produced no matter how slow the peer is.
+///
+/// While ops are waiting, newer ones may supersede older ones: a digest
+/// replaces an unsent digest, and a full snapshot replaces any unsent edits
+/// and states, since it already contains them.
+#[derive(Clone)]
+pub struct Outbox {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    ready: Notify,
+    limit: usize,
+}
+
+#[derive(Default)]
+struct Queue {
+    ops: VecDeque<PeerSyncOp>,
+    /// Set by a resync and cleared once the writer finds the queue empty,
+    /// meaning everything up to and including the snapshot was written.
+    resyncing: bool,
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Self::new(MAX_PENDING)
+    }
+}
+
+impl Outbox {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                queue: Mutex::new(Queue::default()),
+                ready: Notify::new(),
+                limit,
+            }),
+        }
+    }
+
+    /// Queues `op` behind everything already waiting. `snapshot` is only
+    /// called when the queue overflows and has to be replaced.
+    pub fn push(&self, op: PeerSyncOp, snapshot: impl FnOnce() -> Doc) -> Push {
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.coalesce(&op);
+        queue.ops.push_back(op);
+
+        let result = if queue.ops.len() <= self.shared.limit {
+            Push::Queued
+        } else if queue.resyncing {
+            queue.ops.clear();
+            Push::Overflowed
+        } else {
+            queue.ops.clear();
+            queue
+                .ops
+                .push_back(PeerSyncOp::FullSync { state: snapshot() });
+            queue.resyncing = true;
+            Push::Resynced
+        };
+        drop(queue);
+        self.shared.ready.notify_one();
+        result
+    }
+
+    /// Number of ops waiting to be written.
+    pub fn len(&self) -> usize {
+        self.shared.queue.lock().unwrap().ops.len()
+    }
+
+    /// Takes the oldest waiting op, if any.
+    pub fn pop(&self) -> Option<PeerSyncOp> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        let op = queue.ops.pop_front();
+        if op.is_none() {
+            queue.resyncing = false;
+        }
+        op
+    }
+
+    /// Waits for the next op. Cancel safe: an op is only taken from the
+    /// queue when it is returned.
+    pub async fn next(&self) -> PeerSyncOp {
+        loop {
+            if let Some(op) = self.pop() {
+                return op;
+            }
+            self.shared.ready.notified().await;
+        }
+    }
+}
+
+impl Queue {
+    /// Drops waiting ops that `op` makes redundant.
+    fn coalesce(&mut self, op: &PeerSyncOp) {
+        match op {
+            PeerSyncOp::Digest { .. } => self
+                .ops
+                .retain(|queued| !matches!(queued, PeerSyncOp::Digest { .. })),
+            // A range request still has to reach the peer so it answers.
+            PeerSyncOp::FullSync { .. } => self.ops.retain(|queued| {
+                matches!(
+                    queued,
+                    PeerSyncOp::Digest { .. } | PeerSyncOp::RangeSync { reply: true, .. }
+                )
+            }),
+            PeerSyncOp::Insert { .. }
+            | PeerSyncOp::Remove { .. }
+            | PeerSyncOp::RangeSync { .. } => {}
+        }
+    }
+}
@@ -0,0 +1,449 @@
+use crate::outbox::Outbox;
+use crate::protocol::{self, peer_info, peer_status, server_event};
+use crate::types::{PeerId, RESERVED_PEER};
+use crate::{info, warn};
+use rand::Rng;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+const BACKOFF_BASE: Duration = Duration::from_millis(500);
+const BACKOFF_MAX: Duration = Duration::from_secs(30);
+const MAX_ATTEMPTS: u32 = 10;
+/// Smoothed RTTs up to these count as a good or fair link; anything slower
+/// is poor.
+const GOOD_RTT: Duration = Duration::from_millis(100);
+const FAIR_RTT: Duration = Duration::from_millis(300);
+
+/// Delay before the given reconnect attempt: doubles every time up to
+/// `BACKOFF_MAX`, with the upper half jittered so peers don't retry in lockstep.
+pub fn backoff_delay(attempt: u32) -> Duration {
+    let exp = BACKOFF_BASE.saturating_mul(1 << attempt.min(16));
+    let cap = exp.min(BACKOFF_MAX);
+    let jitter = rand::rng().random_range(0.0..=0.5);
+    cap.mul_f64(0.5 + jitter)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DialState {
+    Dialing,
+    Connected,
+    Waiting,
+    /// The peer behind this address is reachable over another link; dial
+    /// again once that one drops.
+    Standby,
+}
+
+#[derive(Debug)]
+struct DialTarget {
+    peer_id: Option<PeerId>,
+    attempt: u32,
+    state: DialState,
+    /// Static peers are never given up on.
+    persistent: bool,
+}
+
+impl DialTarget {
+    fn new(peer_id: Option<PeerId>, persistent: bool) -> Self {
+        Self {
+            peer_id,
+            attempt: 0,
+            state: DialState::Dialing,
+            persistent,
+        }
+    }
+}
+
+struct Link {
+    outbox: Outbox,
+    addr: SocketAddr,
+    outbound: bool,
+    /// Of the static key the peer proved it holds.
+    fingerprint: String,
+    closer: CancellationToken,
+    rtt: RttStats,
+}
+
+/// Round-trip times measured by heartbeats on one link. `smoothed` is an
+/// exponential moving average with the same 1/8 gain TCP uses for SRTT.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RttStats {
+    pub last: Duration,
+    pub smoothed: Duration,
+    pub min: Duration,
+    pub samples: u32,
+}
+
+impl RttStats {
+    pub fn record(&mut self, rtt: Duration) {
+        if self.samples == 0 {
+            self.smoothed = rtt;
+            self.min = rtt;
+        } else {
+            self.smoothed = (self.smoothed * 7 + rtt) / 8;
+            self.min = self.min.min(rtt);
+        }
+        self.last = rtt;
+        self.samples = self.samples.saturating_add(1);
+    }
+
+    pub fn quality(&self) -> peer_info::Quality {
+        match self.smoothed {
+            _ if self.samples == 0 => peer_info::Quality::Unknown,
+            rtt if rtt <= GOOD_RTT => peer_info::Quality::Good,
+            rtt if rtt <= FAIR_RTT => peer_info::Quality::Fair,
+            _ => peer_info::Quality::Poor,
+        }
+    }
+}
+
+pub enum Redial {
+    After(Duration),
+    GiveUp,
+}
+
+/// Tracks live peer links and the addresses this node is responsible for
+/// dialing. What a reconnected peer is sent first is up to `Gossip`.
+pub struct PeerManager {
+    my_id: PeerId,
+    links: HashMap<PeerId, Link>,
+    targets: HashMap<SocketAddr, DialTarget>,
+    /// Addresses of peers that broke the protocol, until when they're
+    /// neither accepted nor dialed.
+    bans: HashMap<IpAddr, Instant>,
+    /// Display names peers gave in their handshake, kept after they leave.
+    names: HashMap<PeerId, String>,
+}
+
+impl PeerManager {
+    pub fn new(my_id: PeerId) -> Self {
+        Self {
+            my_id,
+            links: HashMap::new(),
+            targets: HashMap::new(),
+            bans: HashMap::new(),
+            names: HashMap::new(),
+        }
+    }
+
+    pub fn set_name(&mut self, id: PeerId, name: String) {
+        self.names.insert(id, name);
+    }
+
+    /// Everyone currently linked, with their fingerprint.
+    pub fn linked_fingerprints(&self) -> impl Iterator<Item = (PeerId, &str)> {
+        self.links
+            .iter()
+            .map(|(id, link)| (*id, link.fingerprint.as_str()))
+    }
+
+    /// Whether the link to `id` in use is the one at `addr`.
+    pub fn is_linked(&self, id: PeerId, addr: SocketAddr) -> bool {
+        self.links.get(&id).is_some_and(|link| link.addr == addr)
+    }
+
+    fn info(&self, id: PeerId, address: String) -> protocol::PeerInfo {
+        let rtt = self.links.get(&id).map(|link| link.rtt).unwrap_or_default();
+        protocol::PeerInfo {
+            peer_id: id as u32,
+            name: self.names.get(&id).cloned().unwrap_or_default(),
+            address,
+            quality: rtt.quality() as i32,
+            rtt_us: rtt.smoothed.as_micros().min(u32::MAX as u128) as u32,
+        }
+    }
+
+    /// Everyone currently linked, by ID.
+    pub fn roster(&self) -> Vec<protocol::PeerInfo> {
+        let mut roster: Vec<_> = self
+            .links
+            .iter()
+            .map(|(id, link)| self.info(*id, link.addr.to_string()))
+            .collect();
+        roster.sort_by_key(|info| info.peer_id);
+        roster
+    }
+
+    /// The roster event for the frontend matching a change in `status`.
+    pub fn roster_change(&self, status: &protocol::PeerStatus) -> server_event::Variant {
+        let peer = Some(self.info(status.peer_id as PeerId, status.address.clone()));
+        match status.state() {
+            peer_status::State::Connected => {
+                server_event::Variant::Joined(protocol::PeerJoined { peer })
+            }
+            peer_status::State::Disconnected => {
+                server_event::Variant::Left(protocol::PeerLeft { peer })
+            }
+            peer_status::State::Reconnecting => {
+                server_event::Variant::Reconnecting(protocol::PeerReconnecting {
+                    peer,
+                    attempt: status.attempt,
+                    retry_in_ms: status.retry_in_ms,
+                })
+            }
+        }
+    }
+
+    /// Refuses links from and to `addr`'s host for `duration`.
+    pub fn ban(&mut self, addr: SocketAddr, duration: Duration) {
+        self.bans.insert(addr.ip(), Instant::now() + duration);
+    }
+
+    /// Time left on the ban of `addr`'s host, if there's one.
+    pub fn banned_for(&mut self, addr: SocketAddr) -> Option<Duration> {
+        let until = *self.bans.get(&addr.ip())?;
+        let left = until.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            self.bans.remove(&addr.ip());
+            return None;
+        }
+        Some(left)
+    }
+
+    pub fn links(&self) -> impl Iterator<Item = (&PeerId, &Outbox)> {
+        self.links.iter().map(|(id, link)| (id, &link.outbox))
+    }
+
+    pub fn link(&self, id: PeerId) -> Option<&Outbox> {
+        self.links.get(&id).map(|link| &link.outbox)
+    }
+
+    /// Drops the link to `id`. It is forgotten once its task reports the
+    /// disconnect, like any other dropped link.
+    pub fn close(&self, id: PeerId) {
+        if let Some(link) = self.links.get(&id) {
+            link.closer.cancel();
+        }
+    }
+
+    pub fn on_rtt(&mut self, id: PeerId, addr: SocketAddr, rtt: Duration) {
+        if let Some(link) = self.links.get_mut(&id).filter(|link| link.addr == addr) {
+            link.rtt.record(rtt);
+        }
+    }
+
+    pub fn stats(&self) -> Vec<protocol::PeerStats> {
+        let micros = |d: Duration| d.as_micros().min(u32::MAX as u128) as u32;
+        let mut stats: Vec<_> = self
+            .links
+            .iter()
+            .map(|(id, link)| protocol::PeerStats {
+                peer_id: *id as u32,
+                address: link.addr.to_string(),
+                rtt_us: micros(link.rtt.last),
+                smoothed_rtt_us: micros(link.rtt.smoothed),
+                min_rtt_us: micros(link.rtt.min),
+                samples: link.rtt.samples,
+                queued: link.outbox.len() as u32,
+            })
+            .collect();
+        stats.sort_by_key(|stats| stats.peer_id);
+        stats
+    }
+
+    /// Returns true if the caller should dial `addr` now. Only the lower ID
+    /// dials, so two nodes never race each other into duplicate links.
+    pub fn on_discovered(&mut self, id: PeerId, addr: SocketAddr) -> bool {
+        if self.links.contains_key(&id) || self.my_id >= id || self.banned_for(addr).is_some() {
+            return false;
+        }
+        match self.targets.get_mut(&addr) {
+            Some(target) => {
+                target.peer_id = Some(id);
+                false
+            }
+            None => {
+                self.targets.insert(addr, DialTarget::new(Some(id), false));
+                true
+            }
+        }
+    }
+
+    /// Returns true if the caller should dial `addr` now. Unlike discovered
+    /// peers, configured and user-requested addresses are dialed whatever
+    /// the remote ID turns out to be; a pending backoff is cut short.
+    pub fn on_dial_request(&mut self, addr: SocketAddr, persistent: bool) -> bool {
+        let banned = self.banned_for(addr).is_some();
+        match self.targets.get_mut(&addr) {
+            Some(target) => {
+                target.persistent |= persistent;
+                if target.state == DialState::Waiting && !banned {
+                    target.state = DialState::Dialing;
+                    return true;
+                }
+                false
+            }
+            None if banned => false,
+            None => {
+                self.targets.insert(addr, DialTarget::new(None, persistent));
+                true
+            }
+        }
+    }
+
+    /// Registers a link that finished its handshake. When both sides dialed
+    /// each other, each keeps the link opened by the lower ID and closes the
+    /// other, so they agree on which one survives. A link claiming the ID of
+    /// a peer linked with another key is closed, as IDs aren't authenticated.
+    pub fn on_connected(
+        &mut self,
+        id: PeerId,
+        addr: SocketAddr,
+        outbound: bool,
+        fingerprint: String,
+        outbox: Outbox,
+        closer: CancellationToken,
+    ) -> Option<protocol::PeerStatus> {
+        if let Some(existing) = self.links.get(&id)
+            && existing.fingerprint != fingerprint
+        {
+            warn!(
+                "Refusing {} at {}, peer {} is linked with another key",
+                fingerprint, addr, id
+            );
+            closer.cancel();
+            return None;
+        }
+        if let Some(target) = self.targets.get_mut(&addr).filter(|_| outbound) {
+            target.peer_id = Some(id);
+            target.state = DialState::Connected;
+        }
+        let link = Link {
+            outbox,
+            addr,
+            outbound,
+            fingerprint,
+            closer,
+            rtt: RttStats::default(),
+        };
+        let Some(existing) = self.links.get(&id) else {
+            self.links.insert(id, link);
+            return Some(status(
+                id,
+                addr,
+                peer_status::State::Connected,
+                0,
+                Duration::ZERO,
+            ));
+        };
+        let preferred = |link: &Link| link.outbound == (self.my_id < id);
+        if preferred(&link) && !preferred(existing) {
+            info!("Replacing duplicate link to peer {}", id);
+            existing.closer.cancel();
+            self.links.insert(id, link);
+        } else {
+            info!("Closing duplicate link to peer {}", id);
+            link.closer.cancel();
+        }
+        None
+    }
+
+    /// Drops the link to `id` if it is the one at `addr`, and returns the
+    /// addresses that were on standby for it and should be dialed again.
+    pub fn on_disconnected(
+        &mut self,
+        id: PeerId,
+        addr: SocketAddr,
+    ) -> Option<(protocol::PeerStatus, Vec<SocketAddr>)> {
+        if self.links.get(&id).is_none_or(|link| link.addr != addr) {
+            return None;
+        }
+        self.links.remove(&id);
+        let standby = self
+            .targets
+            .iter_mut()
+            .filter(|(_, target)| target.state == DialState::Standby && target.peer_id == Some(id))
+            .map(|(addr, target)| {
+                target.state = DialState::Waiting;
+                *addr
+            })
+            .collect();
+        let state = peer_status::State::Disconnected;
+        Some((status(id, addr, state, 0, Duration::ZERO), standby))
+    }
+
+    /// Called when a dial attempt to `addr` ends, whether it failed outright
+    /// or an established link dropped. A link that got connected starts the
+    /// backoff over; repeated failures back off further until we give up.
+    /// A banned address isn't tried again before the ban ends.
+    pub fn on_dial_ended(&mut self, addr: SocketAddr) -> Option<(Redial, protocol::PeerStatus)> {
+        if self.park_if_linked(addr) {
+            return None;
+        }
+        let banned_for = self.banned_for(addr).unwrap_or_default();
+        let target = self.targets.get_mut(&addr)?;
+        let peer_id = target.peer_id.unwrap_or(RESERVED_PEER);
+        target.attempt = match target.state {
+            DialState::Connected => 0,
+            _ => target.attempt + 1,
+        };
+        if target.attempt >= MAX_ATTEMPTS && !target.persistent {
+            self.targets.remove(&addr);
+            let state = peer_status::State::Disconnected;
+            return Some((
+                Redial::GiveUp,
+                status(peer_id, addr, state, MAX_ATTEMPTS, Duration::ZERO),
+            ));
+        }
+        target.state = DialState::Waiting;
+        let delay = backoff_delay(target.attempt).max(banned_for);
+        let state = peer_status::State::Reconnecting;
+        Some((
+            Redial::After(delay),
+            status(peer_id, addr, state, target.attempt + 1, delay),
+        ))
+    }
+
+    /// Returns true if a scheduled redial of `addr` should still go ahead.
+    pub fn on_redial(&mut self, addr: SocketAddr) -> bool {
+        if self.park_if_linked(addr) {
+            return false;
+        }
+        match self.targets.get_mut(&addr) {
+            Some(target) if target.state == DialState::Waiting => {
+                target.state = DialState::Dialing;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// A target whose peer is already linked over another connection is
+    /// put on standby if it's persistent and forgotten otherwise.
+    fn park_if_linked(&mut self, addr: SocketAddr) -> bool {
+        let Some(target) = self.targets.get_mut(&addr) else {
+            return false;
+        };
+        let linked_elsewhere = target
+            .peer_id
+            .is_some_and(|id| self.links.get(&id).is_some_and(|link| link.addr != addr));
+        if !linked_elsewhere {
+            return false;
+        }
+        if target.persistent {
+            target.state = DialState::Standby;
+            target.attempt = 0;
+        } else {
+            self.targets.remove(&addr);
+        }
+        true
+    }
+}
+
+fn status(
+    peer_id: PeerId,
+    addr: SocketAddr,
+    state: peer_status::State,
+    attempt: u32,
+    retry_in: Duration,
+) -> protocol::PeerStatus {
+    protocol::PeerStatus {
+        peer_id: peer_id as u32,
+        state: state as i32,
+        attempt,
+        retry_in_ms: retry_in.as_millis() as u32,
+        address: addr.to_string(),
+    }
+}
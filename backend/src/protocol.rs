@@ -1,18 +1,33 @@
+use crate::config;
+use crate::outbox::Outbox;
 use crate::state::{Doc, NodeKey};
+use crate::transport::{ClientSender, PeerLink};
 use crate::types::PeerId;
-use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
-use tokio::sync::mpsc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
 
 mod generated {
     include!(concat!(env!("OUT_DIR"), "/dte.rs"));
+
+    /// Messages of the peer protocol, defined in `proto/peer.proto`.
+    pub mod peer {
+        include!(concat!(env!("OUT_DIR"), "/dte.peer.rs"));
+    }
+
+    /// Trace file records, defined in `proto/trace.proto`.
+    pub mod trace {
+        include!(concat!(env!("OUT_DIR"), "/dte.trace.rs"));
+    }
 }
+pub use generated::peer as wire;
 pub use generated::*;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PeerBeacon {
     pub id: PeerId,
     pub tcp_port: u16,
+    pub room: String,
 }
 
 pub enum NodeEvent {
@@ -20,31 +35,133 @@ pub enum NodeEvent {
 
     Local(ClientCommand),
 
-    Sync(PeerSyncOp),
+    /// A client connected over IPC; its events go out through `events`.
+    Attach {
+        events: ClientSender,
+    },
+
+    Sync {
+        from: PeerId,
+        op: PeerSyncOp,
+    },
+
+    /// The config file changed on disk.
+    ConfigChanged,
 }
 
 pub enum PeerEvent {
+    /// A beacon was heard, from any room.
     Discovered {
         id: PeerId,
         addr: SocketAddr,
+        room: String,
     },
     Connection {
-        stream: tokio::net::TcpStream,
+        stream: Box<dyn PeerLink>,
     },
     Connected {
         id: PeerId,
-        sender: mpsc::Sender<PeerSyncOp>,
+        addr: SocketAddr,
+        role: config::Role,
+        name: String,
+        fingerprint: String,
+        outbound: bool,
+        outbox: Outbox,
+        closer: CancellationToken,
     },
     Disconnected {
         id: PeerId,
+        addr: SocketAddr,
+    },
+    /// The peer sent something oversized or malformed. Sent just before
+    /// the link's `Disconnected`.
+    Misbehaved {
+        id: PeerId,
+        addr: SocketAddr,
+    },
+    Dial {
+        addr: SocketAddr,
+        persistent: bool,
+    },
+    DialEnded {
+        addr: SocketAddr,
+    },
+    Rtt {
+        id: PeerId,
+        addr: SocketAddr,
+        rtt: Duration,
+    },
+    Redial {
+        addr: SocketAddr,
     },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Identifies an edit as it is relayed across the mesh. `seq` starts at a
+/// random value on every run, so IDs stay unique across restarts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OpId {
+    pub origin: PeerId,
+    pub seq: u64,
+}
+
+#[derive(Debug, Clone)]
 pub enum PeerSyncOp {
-    Insert { char_id: Vec<NodeKey>, value: u8 },
+    Insert {
+        op_id: OpId,
+        char_id: Vec<NodeKey>,
+        value: u8,
+    },
+
+    Remove {
+        op_id: OpId,
+        char_id: Vec<NodeKey>,
+    },
+
+    FullSync {
+        state: Doc,
+    },
 
-    Remove { char_id: Vec<NodeKey> },
+    /// Per-bucket hashes from `Doc::digest`, sent periodically.
+    Digest {
+        buckets: Vec<u64>,
+    },
+
+    /// Entries of the buckets a digest exchange found to differ. The side
+    /// that spotted the mismatch sets `reply` to get the peer's half back.
+    RangeSync {
+        buckets: Vec<u32>,
+        state: Doc,
+        reply: bool,
+    },
+}
+
+impl PeerSyncOp {
+    /// Edits carry an ID and are relayed; state exchanges only concern one
+    /// link.
+    pub fn op_id(&self) -> Option<OpId> {
+        match self {
+            PeerSyncOp::Insert { op_id, .. } | PeerSyncOp::Remove { op_id, .. } => Some(*op_id),
+            _ => None,
+        }
+    }
+}
+
+impl From<Role> for config::Role {
+    fn from(role: Role) -> Self {
+        match role {
+            Role::Owner => Self::Owner,
+            Role::Editor => Self::Editor,
+            Role::Viewer => Self::Viewer,
+        }
+    }
+}
 
-    FullSync { state: Doc },
+impl From<config::Role> for Role {
+    fn from(role: config::Role) -> Self {
+        match role {
+            config::Role::Owner => Self::Owner,
+            config::Role::Editor => Self::Editor,
+            config::Role::Viewer => Self::Viewer,
+        }
+    }
 }
@@ -0,0 +1,84 @@
+use crate::protocol;
+use crate::transport::handshake::MAX_ROOM_LEN;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How many beacon intervals a node stays listed after its last beacon.
+const MISSED_BEACONS: u32 = 3;
+
+/// Beacons aren't authenticated, so anyone on the network could make up
+/// rooms and nodes without end. Past these, only known ones are refreshed.
+pub const MAX_ROOMS: usize = 64;
+pub const MAX_ROOM_NODES: usize = 256;
+
+/// Rooms announced on the network, going by the discovery beacons heard
+/// lately. Nodes that stop beaconing drop out after a few intervals.
+pub struct RoomDirectory {
+    mine: String,
+    ttl: Duration,
+    /// When each beacon sender was last heard, by room.
+    heard: BTreeMap<String, HashMap<SocketAddr, Instant>>,
+}
+
+impl RoomDirectory {
+    pub fn new(mine: String, beacon_interval: Duration) -> Self {
+        Self {
+            mine,
+            ttl: beacon_interval.saturating_mul(MISSED_BEACONS),
+            heard: BTreeMap::new(),
+        }
+    }
+
+    /// Records a beacon; true if it came from our own room. Rooms with
+    /// names too long to join aren't listed.
+    pub fn on_beacon(&mut self, room: String, addr: SocketAddr) -> bool {
+        let now = Instant::now();
+        self.prune(now);
+        let ours = room == self.mine;
+        if room.len() > MAX_ROOM_LEN {
+            return false;
+        }
+        if !ours && self.heard.len() >= MAX_ROOMS && !self.heard.contains_key(&room) {
+            return false;
+        }
+        let nodes = self.heard.entry(room).or_default();
+        if nodes.len() < MAX_ROOM_NODES || nodes.contains_key(&addr) {
+            nodes.insert(addr, now);
+        }
+        ours
+    }
+
+    /// Forgets nodes not heard from within the TTL, and rooms left empty.
+    fn prune(&mut self, now: Instant) {
+        let ttl = self.ttl;
+        self.heard.retain(|_, nodes| {
+            nodes.retain(|_, heard| now.duration_since(*heard) < ttl);
+            !nodes.is_empty()
+        });
+    }
+
+    /// Every room heard of, ours included, by name.
+    pub fn list(&mut self) -> Vec<protocol::RoomInfo> {
+        self.prune(Instant::now());
+        let mut rooms: Vec<_> = self
+            .heard
+            .iter()
+            .map(|(name, nodes)| protocol::RoomInfo {
+                name: name.clone(),
+                peers: nodes.len() as u32,
+                joined: *name == self.mine,
+            })
+            .collect();
+        if !self.heard.contains_key(&self.mine) {
+            rooms.push(protocol::RoomInfo {
+                name: self.mine.clone(),
+                peers: 0,
+                joined: true,
+            });
+            rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        }
+        rooms
+    }
+}
@@ -1,70 +1,333 @@
+use crate::gossip::Gossip;
+use crate::outbox::Push;
+use crate::peers::{self, PeerManager, Redial};
+use crate::rooms::RoomDirectory;
 use crate::session::Session;
+use crate::state::DIGEST_BUCKETS;
+use crate::trace::Trace;
 use crate::types::PeerId;
-use crate::{config, protocol, select_loop, transport};
-use std::collections::HashMap;
+use crate::{cli, config, debug, error, info, logging, protocol, select_loop, transport, warn};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::mpsc;
-use tokio_util::codec::{FramedWrite, LengthDelimitedCodec};
 use tokio_util::sync::CancellationToken;
 
-pub async fn run(config: config::NodeConfig) -> Result<(), ()> {
+/// What a node runs on: how it reaches peers, where its frontend is, and
+/// where it keeps its files. Tests swap these for in-memory ones to run
+/// several nodes in one process.
+pub struct Runtime {
+    pub transport: Arc<dyn transport::Transport>,
+    pub frontend: Option<Frontend>,
+    pub data_dir: PathBuf,
+    /// Where the config came from. The file is watched, and reloaded when it
+    /// changes or a client asks; without one, the node keeps its config.
+    pub config_source: Option<cli::Args>,
+}
+
+/// Streams of length-delimited `ClientCommand`s in and `ServerEvent`s out.
+pub struct Frontend {
+    pub commands: Box<dyn AsyncRead + Send + Unpin>,
+    pub events: Box<dyn AsyncWrite + Send + Unpin>,
+}
+
+impl Runtime {
+    /// TCP peers and a frontend on stdin/stdout, or none in relay mode.
+    pub fn native(config: &config::NodeConfig, source: cli::Args) -> Self {
+        let frontend = (!config.relay.enabled).then(|| Frontend {
+            commands: Box::new(tokio::io::stdin()),
+            events: Box::new(tokio::io::stdout()),
+        });
+        Self {
+            transport: Arc::new(transport::TcpTransport::new(config.tcp_port)),
+            frontend,
+            data_dir: config
+                .data_dir
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(config::DEFAULT_DATA_DIR)),
+            config_source: Some(source),
+        }
+    }
+}
+
+pub async fn run(config: config::NodeConfig, runtime: Runtime) -> Result<(), ()> {
+    let handshake = match transport::HandshakeConfig::try_from(&config) {
+        Ok(handshake) => handshake,
+        Err(e) => {
+            error!("Invalid peer identity: {}", e);
+            return Err(());
+        }
+    };
+    info!("Node fingerprint: {}", handshake.static_key.fingerprint());
+
     let (tx, rx) = mpsc::channel(255);
     let token = CancellationToken::new();
 
-    let tx_stdin = tx.clone();
-    let token_stdin = token.clone();
+    if config.relay.enabled {
+        info!("Running headless as a relay on port {}", config.tcp_port);
+        tokio::spawn(close_on_signal(tx.clone()));
+    }
+
+    let writer = match runtime.frontend {
+        Some(Frontend { commands, events }) => {
+            let tx_frontend = tx.clone();
+            let token_frontend = token.clone();
+            tokio::spawn(async move {
+                if let Err(e) = transport::run_command_listener(
+                    commands,
+                    tx_frontend,
+                    token_frontend.clone(),
+                    false,
+                )
+                .await
+                {
+                    error!("Frontend listener crashed: {}", e);
+                    token_frontend.cancel();
+                }
+            });
+            transport::event_writer(events)
+        }
+        None => transport::event_writer(tokio::io::sink()),
+    };
+
+    if config.ipc.enabled {
+        spawn_ipc_listener(config.ipc.socket_path(&runtime.data_dir), &tx, &token);
+    }
+
+    if config.discovery.enabled {
+        let tx_discovery = tx.clone();
+        let token_discovery = token.clone();
+        let config_discovery = config.clone();
+        tokio::spawn(async move {
+            if let Err(e) =
+                transport::run_discovery(tx_discovery, token_discovery.clone(), config_discovery)
+                    .await
+            {
+                error!("Discovery crashed: {}", e);
+                token_discovery.cancel();
+            }
+        });
+    } else {
+        info!("Broadcast discovery disabled");
+    }
+
+    for address in &config.static_peers {
+        tokio::spawn(resolve_peer(
+            address.clone(),
+            true,
+            tx.clone(),
+            token.clone(),
+        ));
+    }
+
+    let websocket = config.websocket.enabled.then(|| {
+        let websocket = transport::WebSocketTransport::new(config.websocket.port, &config.limits);
+        spawn_listener("WebSocket listener", &websocket, &tx, &token);
+        websocket
+    });
+    spawn_listener("Peer listener", runtime.transport.as_ref(), &tx, &token);
+
+    if let Some(source) = &runtime.config_source {
+        spawn_config_watcher(source.config_path(), &tx, &token);
+    }
+
+    let trace = if config.trace.enabled {
+        let path = config.trace.path(&runtime.data_dir);
+        match Trace::open(&path) {
+            Ok(trace) => {
+                info!("Recording a trace in {}", path.display());
+                trace
+            }
+            Err(e) => {
+                error!("Failed to open {}: {}", path.display(), e);
+                return Err(());
+            }
+        }
+    } else {
+        Trace::default()
+    };
+    let link = transport::LinkConfig {
+        handshake,
+        heartbeat: config.heartbeat.clone(),
+        limits: config.limits.clone(),
+        transport: runtime.transport,
+        trace,
+    };
+    let source = runtime.config_source;
+    handle_events(
+        rx,
+        tx,
+        token,
+        link,
+        websocket,
+        writer,
+        &config,
+        source,
+        runtime.data_dir,
+    )
+    .await
+}
+
+/// How often the config file is checked for changes.
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Tells the event loop whenever the contents of the file at `path` change.
+/// Contents are compared rather than modification times, which can miss two
+/// writes in quick succession.
+fn spawn_config_watcher(
+    path: PathBuf,
+    tx: &mpsc::Sender<protocol::NodeEvent>,
+    token: &CancellationToken,
+) {
+    let (tx, token) = (tx.clone(), token.clone());
     tokio::spawn(async move {
-        if let Err(e) = transport::run_stdin_listener(tx_stdin, token_stdin.clone()).await {
-            eprintln!("Stdin listener crashed: {}", e);
-            token_stdin.cancel();
+        let contents = || std::fs::read(&path).ok();
+        let mut last = contents();
+        let mut poll = tokio::time::interval(CONFIG_POLL_INTERVAL);
+        select_loop! {
+            _ = token.cancelled() => return,
+
+            _ = poll.tick() => {
+                let now = contents();
+                if now != last {
+                    last = now;
+                    if tx.send(protocol::NodeEvent::ConfigChanged).await.is_err() {
+                        return;
+                    }
+                }
+            }
         }
     });
+}
 
-    let tx_discovery = tx.clone();
-    let token_discovery = token.clone();
-    let config_discovery = config.clone();
+/// Runs `transport`'s listener, shutting the node down if it fails.
+fn spawn_listener(
+    name: &'static str,
+    transport: &dyn transport::Transport,
+    tx: &mpsc::Sender<protocol::NodeEvent>,
+    token: &CancellationToken,
+) {
+    let listener = transport.listen(tx.clone(), token.clone());
+    let token = token.clone();
     tokio::spawn(async move {
-        if let Err(e) =
-            transport::run_discovery(tx_discovery, token_discovery.clone(), config_discovery).await
-        {
-            eprintln!("Discovery crashed: {}", e);
-            token_discovery.cancel();
+        if let Err(e) = listener.await {
+            error!("{} crashed: {}", name, e);
+            token.cancel();
         }
     });
+}
 
-    let tx_tcp = tx.clone();
-    let token_tcp = token.clone();
+#[cfg(unix)]
+fn spawn_ipc_listener(
+    path: PathBuf,
+    tx: &mpsc::Sender<protocol::NodeEvent>,
+    token: &CancellationToken,
+) {
+    let listener = transport::ipc::run_ipc_listener(path, tx.clone(), token.clone());
+    let token = token.clone();
     tokio::spawn(async move {
-        if let Err(e) =
-            transport::run_tcp_listener(tx_tcp, token_tcp.clone(), config.tcp_port).await
-        {
-            eprintln!("TCP Server crashed: {}", e);
-            token_tcp.cancel();
+        if let Err(e) = listener.await {
+            error!("IPC listener crashed: {}", e);
+            token.cancel();
         }
     });
+}
 
-    handle_events(rx, tx, token, config.peer_id).await
+#[cfg(not(unix))]
+fn spawn_ipc_listener(
+    _path: PathBuf,
+    _tx: &mpsc::Sender<protocol::NodeEvent>,
+    _token: &CancellationToken,
+) {
+    warn!("IPC sockets are only supported on Unix, ignoring [ipc]");
 }
 
+/// Shuts a headless node down cleanly, document saved, on Ctrl-C or SIGTERM.
+async fn close_on_signal(tx: mpsc::Sender<protocol::NodeEvent>) {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => {
+            if let Err(e) = result {
+                error!("Failed to listen for Ctrl-C: {}", e);
+                return;
+            }
+        }
+        _ = terminate => {}
+    }
+    info!("Shutting down");
+    let close = protocol::ClientCommand {
+        variant: Some(protocol::client_command::Variant::Close(
+            protocol::CloseApplication {},
+        )),
+    };
+    let _ = tx.send(protocol::NodeEvent::Local(close)).await;
+}
+
+/// A timer first firing one `period` from now, and skipping ticks it missed.
+fn ticker(period_ms: u64) -> tokio::time::Interval {
+    let period = Duration::from_millis(period_ms);
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    ticker
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn handle_events(
     mut rx: tokio::sync::mpsc::Receiver<protocol::NodeEvent>,
     tx_loopback: mpsc::Sender<protocol::NodeEvent>,
     token: tokio_util::sync::CancellationToken,
-    my_id: PeerId,
+    mut link: transport::LinkConfig,
+    websocket: Option<transport::WebSocketTransport>,
+    mut writer: transport::EventWriter,
+    config: &config::NodeConfig,
+    source: Option<cli::Args>,
+    data_dir: PathBuf,
 ) -> Result<(), ()> {
-    let save_path = "./native/doc.bin";
+    let mut current = config.clone();
+    let my_id = link.handshake.my_id;
+    let mut my_role = config
+        .roles
+        .role_of(&link.handshake.static_key.fingerprint());
+    let save_path = data_dir.join("doc.bin").to_string_lossy().into_owned();
+    let save_path = save_path.as_str();
     let mut session = Session::from(my_id, save_path);
-    let mut writer = FramedWrite::new(tokio::io::stdout(), LengthDelimitedCodec::new());
-    let mut peers: HashMap<PeerId, mpsc::Sender<protocol::PeerSyncOp>> = HashMap::new();
+    session.set_default_role(config.roles.default);
+    link.trace
+        .start(my_id, &session.get_doc_snapshot(), config.roles.default);
+    let mut autosave = config
+        .relay
+        .enabled
+        .then(|| ticker(config.relay.save_interval_ms));
+    let mut peers = PeerManager::new(my_id);
+    let mut rooms = RoomDirectory::new(
+        config.room.clone(),
+        std::time::Duration::from_millis(config.discovery.interval_ms),
+    );
+    let mut gossip = Gossip::new();
+    let mut anti_entropy = ticker(config.anti_entropy_interval_ms);
 
     tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
-    let init_sync = protocol::ServerEvent {
-        variant: Some(protocol::server_event::Variant::State(
-            protocol::FullState {
-                content: session.get_doc_ascii(),
-            },
-        )),
-    };
-    transport::send_server_event(&init_sync, &mut writer).await;
+    info!("Joined as {:?}", my_role);
+    for event in greeting(&session, my_role) {
+        transport::send_server_event(&event, &mut writer).await;
+    }
 
     select_loop! {
         'main_loop:
@@ -73,118 +336,578 @@ async fn handle_events(
             return Err(());
         }
 
+        _ = anti_entropy.tick() => {
+            broadcast(&peers, &session, &gossip.digest(&session), None);
+        }
+
+        _ = async { autosave.as_mut().unwrap().tick().await }, if autosave.is_some() => {
+            if let Err(e) = session.save_bytes(save_path) {
+                error!("Failed to write {}: {}", save_path, e);
+            }
+        }
+
         event = rx.recv() => {
             let Some(event) = event else {
-                eprintln!("Event channel closed");
+                warn!("Event channel closed");
                 break;
             };
             use protocol::NodeEvent;
+            let mut reload = false;
             match event {
                 NodeEvent::Net(event) => {
-                    handle_peer_event(event, &mut peers, &session, &tx_loopback, &token, my_id);
+                    // A stale duplicate link closing leaves the live one's
+                    // sync state alone.
+                    if let protocol::PeerEvent::Disconnected { id, addr } = &event
+                        && peers.is_linked(*id, *addr)
+                    {
+                        gossip.forget(*id);
+                    }
+                    let connected = match &event {
+                        protocol::PeerEvent::Connected { id, addr, outbox, .. } => Some((*id, *addr, outbox.clone())),
+                        _ => None,
+                    };
+                    // Nodes in other rooms are listed, never dialed.
+                    if let protocol::PeerEvent::Discovered { addr, room, .. } = &event
+                        && !rooms.on_beacon(room.clone(), *addr)
+                    {
+                        continue;
+                    }
+                    handle_peer_event(event, &mut peers, &mut session, &tx_loopback, &token, &link, &mut writer).await;
+                    // Goes out before anything else queued for the link, if
+                    // it was kept.
+                    if let Some((id, addr, outbox)) = connected
+                        && peers.is_linked(id, addr)
+                    {
+                        outbox.push(gossip.initial_sync(&session, id), || session.get_doc_snapshot());
+                    }
                 },
-                NodeEvent::Local(protocol::ClientCommand{variant}) => {
-                    match variant.unwrap() {
-                        protocol::client_command::Variant::Edit(local_op) => handle_local_op(&mut session, local_op, &peers, &mut writer).await,
+                NodeEvent::Local(command) => {
+                    link.trace.command(&command);
+                    let Some(variant) = command.variant else {
+                        warn!("Ignoring empty command from the frontend");
+                        continue;
+                    };
+                    match variant {
+                        protocol::client_command::Variant::Edit(_) if !my_role.can_edit() => {
+                            warn!("Ignoring edit, this node is a viewer");
+                            // Lets the frontend undo the edit it already shows.
+                            for event in greeting(&session, my_role) {
+                                transport::send_server_event(&event, &mut writer).await;
+                            }
+                        },
+                        protocol::client_command::Variant::Edit(local_op) => handle_local_op(&mut session, local_op, &peers, &mut gossip, &link.trace, &mut writer).await,
                         protocol::client_command::Variant::Save(protocol::SaveDocument{ filename }) => {
-                            eprintln!("{}", filename);
-                            if let Err(e)  = session.save_text(format!("./native/{}", filename).as_str()) {
-                                eprintln!("Failed to save file: {}", e)
+                            debug!("{}", filename);
+                            if let Err(e)  = session.save_text(&data_dir.join(filename).to_string_lossy()) {
+                                error!("Failed to save file: {}", e)
                             };
                         },
+                        protocol::client_command::Variant::Validate(protocol::ValidateDocument{ repair }) => {
+                            if repair && !my_role.can_repair() {
+                                warn!("Only owners may repair the document, validating only");
+                            }
+                            let repair = repair && my_role.can_repair();
+                            handle_validate(&mut session, repair, &peers, &link.trace, &mut writer).await;
+                        },
+                        protocol::client_command::Variant::Connect(protocol::ConnectPeer{ address }) => {
+                            tokio::spawn(resolve_peer(address, false, tx_loopback.clone(), token.clone()));
+                        },
+                        protocol::client_command::Variant::Stats(_) => {
+                            let server_event = protocol::ServerEvent {
+                                variant: Some(protocol::server_event::Variant::Stats(
+                                    protocol::PeerStatsReport { peers: peers.stats() },
+                                )),
+                            };
+                            transport::send_server_event(&server_event, &mut writer).await;
+                        },
+                        protocol::client_command::Variant::ListPeers(_) => {
+                            let server_event = protocol::ServerEvent {
+                                variant: Some(protocol::server_event::Variant::Roster(
+                                    protocol::PeerRoster { peers: peers.roster() },
+                                )),
+                            };
+                            transport::send_server_event(&server_event, &mut writer).await;
+                        },
+                        protocol::client_command::Variant::ListRooms(_) => {
+                            let server_event = protocol::ServerEvent {
+                                variant: Some(protocol::server_event::Variant::Rooms(
+                                    protocol::RoomList { rooms: rooms.list() },
+                                )),
+                            };
+                            transport::send_server_event(&server_event, &mut writer).await;
+                        },
+                        protocol::client_command::Variant::ReloadConfig(_) => reload = true,
                         protocol::client_command::Variant::Close(_) => {
                             token.cancel();
                             break 'main_loop;
                         },
                     }
                 }
-                NodeEvent::Sync(sync_op) => {
-                    if let Some(server_event) = session.apply_peer_sync_op(sync_op){
-                        transport::send_server_event(&server_event, &mut writer).await;
-                    }
+                NodeEvent::Attach { events } => {
+                    writer.attach(events, &greeting(&session, my_role));
                 }
+                NodeEvent::Sync { from, op } => {
+                    link.trace.received(from, &op);
+                    handle_sync_op(&mut session, from, op, &peers, &mut gossip, &mut writer).await;
+                }
+                NodeEvent::ConfigChanged => reload = true,
+            }
+            if !reload {
+                continue;
             }
+            let report = match reload_config(source.as_ref(), config, &current) {
+                Ok((new, changes)) => {
+                    logging::set_level(new.log_level);
+                    if changes.live.contains(&"anti_entropy_interval_ms") {
+                        anti_entropy = ticker(new.anti_entropy_interval_ms);
+                    }
+                    if changes.live.contains(&"relay.save_interval_ms") && autosave.is_some() {
+                        autosave = Some(ticker(new.relay.save_interval_ms));
+                    }
+                    apply_to_links(&current, &new, &mut link, &peers, &mut session, &tx_loopback, &token);
+                    if let Some(websocket) = &websocket {
+                        websocket.set_limits(&new.limits);
+                    }
+                    let role = new.roles.role_of(&link.handshake.static_key.fingerprint());
+                    if role != my_role {
+                        info!("Now joined as {:?}", role);
+                        my_role = role;
+                        let [_, role_event] = greeting(&session, my_role);
+                        transport::send_server_event(&role_event, &mut writer).await;
+                    }
+                    current = new;
+                    protocol::ConfigReloaded {
+                        applied: changes.live.iter().map(|key| key.to_string()).collect(),
+                        restart_needed: changes.restart.iter().map(|key| key.to_string()).collect(),
+                        error: String::new(),
+                    }
+                }
+                Err(e) => {
+                    warn!("Keeping the current config. {}", e);
+                    protocol::ConfigReloaded {
+                        error: e,
+                        ..Default::default()
+                    }
+                }
+            };
+            let server_event = protocol::ServerEvent {
+                variant: Some(protocol::server_event::Variant::ConfigReloaded(report)),
+            };
+            transport::send_server_event(&server_event, &mut writer).await;
         }
     }
 
     if let Err(e) = session.save_bytes(save_path) {
-        eprintln!("Failed to write {}: {}", save_path, e);
+        error!("Failed to write {}: {}", save_path, e);
     }
 
     Ok(())
 }
 
-fn handle_peer_event(
+/// Reloads the config from `source`. What can be applied now is what
+/// differs from `current`; what needs a restart, what differs from the
+/// `started` config.
+fn reload_config(
+    source: Option<&cli::Args>,
+    started: &config::NodeConfig,
+    current: &config::NodeConfig,
+) -> Result<(config::NodeConfig, config::Changes), String> {
+    let source = source.ok_or("This node wasn't started from a config file.")?;
+    let path = source.config_path();
+    let new = source
+        .reload(current)
+        .map_err(|e| format!("Failed to reload {}: {}", path.display(), e))?;
+    let changes = config::Changes {
+        live: config::changes(current, &new).live,
+        restart: config::changes(started, &new).restart,
+    };
+    if changes.live.is_empty() && changes.restart.is_empty() {
+        info!("Reloaded {}, nothing changed", path.display());
+    }
+    if !changes.live.is_empty() {
+        info!(
+            "Reloaded {}, applied {}",
+            path.display(),
+            changes.live.join(", ")
+        );
+    }
+    if !changes.restart.is_empty() {
+        warn!("Restart to apply changes to {}", changes.restart.join(", "));
+    }
+    Ok((new, changes))
+}
+
+/// Hands reloaded settings to links. Links opened from now on handshake with
+/// the new identity, trust and roles and run with the new heartbeat and
+/// limits. Open links are kept, but their peers take the role the new
+/// config gives them, and are dropped once no longer trusted. Added static
+/// peers are dialed.
+fn apply_to_links(
+    old: &config::NodeConfig,
+    new: &config::NodeConfig,
+    link: &mut transport::LinkConfig,
+    peers: &PeerManager,
+    session: &mut Session,
+    tx_loopback: &mpsc::Sender<protocol::NodeEvent>,
+    token: &CancellationToken,
+) {
+    link.handshake.name = new.display_name.clone().unwrap_or_default();
+    link.handshake.trusted_fingerprints = new.trusted_fingerprints.clone();
+    link.handshake.roles = new.roles.clone();
+    link.heartbeat = new.heartbeat.clone();
+    link.limits = new.limits.clone();
+
+    session.set_default_role(new.roles.default);
+    let trusted = &new.trusted_fingerprints;
+    let mut roles = Vec::new();
+    for (id, fingerprint) in peers.linked_fingerprints() {
+        if !trusted.is_empty() && !trusted.iter().any(|t| t == fingerprint) {
+            info!("Dropping peer {}, its fingerprint is no longer trusted", id);
+            peers.close(id);
+        } else {
+            let role = new.roles.role_of(fingerprint);
+            session.set_peer_role(id, role);
+            roles.push((id, role));
+        }
+    }
+    link.trace.roles(new.roles.default, &roles);
+    for address in &new.static_peers {
+        if !old.static_peers.contains(address) {
+            tokio::spawn(resolve_peer(
+                address.clone(),
+                true,
+                tx_loopback.clone(),
+                token.clone(),
+            ));
+        }
+    }
+}
+
+/// What a frontend needs first: the document and what it may do to it.
+fn greeting(session: &Session, role: config::Role) -> [protocol::ServerEvent; 2] {
+    [
+        protocol::ServerEvent {
+            variant: Some(protocol::server_event::Variant::State(
+                protocol::FullState {
+                    content: session.get_doc_ascii(),
+                },
+            )),
+        },
+        protocol::ServerEvent {
+            variant: Some(protocol::server_event::Variant::Role(
+                protocol::RoleAssignment {
+                    role: protocol::Role::from(role) as i32,
+                },
+            )),
+        },
+    ]
+}
+
+async fn handle_peer_event(
     event: protocol::PeerEvent,
-    peers: &mut HashMap<PeerId, mpsc::Sender<protocol::PeerSyncOp>>,
-    session: &Session,
+    peers: &mut PeerManager,
+    session: &mut Session,
     tx_loopback: &mpsc::Sender<protocol::NodeEvent>,
     token: &tokio_util::sync::CancellationToken,
-    my_id: PeerId,
+    link: &transport::LinkConfig,
+    writer: &mut transport::EventWriter,
 ) {
     use protocol::PeerEvent;
 
-    match event {
-        PeerEvent::Discovered { id, addr } => {
-            if !peers.contains_key(&id) && my_id < id {
-                let tx = tx_loopback.clone();
-                let tok = token.clone();
-                let doc_snapshot = session.get_doc_snapshot();
-
-                tokio::spawn(transport::connect_to_peer(
-                    addr,
-                    tx,
-                    tok,
-                    doc_snapshot,
-                    my_id,
-                ));
+    let status = match event {
+        PeerEvent::Discovered { id, addr, .. } => {
+            if peers.on_discovered(id, addr) {
+                dial(addr, tx_loopback, token, link);
             }
+            None
         }
         PeerEvent::Connection { stream } => {
+            if let Ok(addr) = stream.peer_addr()
+                && peers.banned_for(addr).is_some()
+            {
+                warn!("Refusing connection from banned address {}", addr);
+                return;
+            }
             let tx = tx_loopback.clone();
             let tok = token.clone();
-            let doc_snapshot = session.get_doc_snapshot();
+            let link = link.clone();
 
             tokio::spawn(async move {
-                transport::handle_connection(stream, tx, tok, doc_snapshot, my_id).await;
+                transport::handle_connection(stream, false, tx, tok, link).await;
             });
+            None
         }
-        PeerEvent::Connected { id, sender } => {
-            peers.insert(id, sender);
+        PeerEvent::Connected {
+            id,
+            addr,
+            role,
+            name,
+            fingerprint,
+            outbound,
+            outbox,
+            closer,
+        } => {
+            let status = peers.on_connected(id, addr, outbound, fingerprint, outbox, closer);
+            if peers.is_linked(id, addr) {
+                session.set_peer_role(id, role);
+                link.trace.connected(id, role);
+                peers.set_name(id, name);
+            }
+            status
         }
-        PeerEvent::Disconnected { id } => {
-            peers.remove(&id);
+        PeerEvent::Disconnected { id, addr } => {
+            peers.on_disconnected(id, addr).map(|(status, standby)| {
+                link.trace.disconnected(id);
+                for addr in standby {
+                    schedule_redial(addr, peers::backoff_delay(0), tx_loopback, token);
+                }
+                status
+            })
+        }
+        PeerEvent::Misbehaved { id, addr } => {
+            let duration = std::time::Duration::from_millis(link.limits.ban_ms);
+            warn!("Banning peer {} at {} for {:?}", id, addr.ip(), duration);
+            peers.ban(addr, duration);
+            None
+        }
+        PeerEvent::Dial { addr, persistent } => {
+            if peers.on_dial_request(addr, persistent) {
+                dial(addr, tx_loopback, token, link);
+            }
+            None
+        }
+        PeerEvent::DialEnded { addr } => match peers.on_dial_ended(addr) {
+            Some((Redial::After(delay), status)) => {
+                info!("Reconnecting to {} in {:?}", addr, delay);
+                schedule_redial(addr, delay, tx_loopback, token);
+                Some(status)
+            }
+            Some((Redial::GiveUp, status)) => {
+                warn!("Giving up on {} after {} attempts", addr, status.attempt);
+                Some(status)
+            }
+            None => None,
+        },
+        PeerEvent::Rtt { id, addr, rtt } => {
+            peers.on_rtt(id, addr, rtt);
+            None
         }
+        PeerEvent::Redial { addr } => {
+            if peers.on_redial(addr) {
+                dial(addr, tx_loopback, token, link);
+            }
+            None
+        }
+    };
+
+    if let Some(status) = status {
+        let roster_change = protocol::ServerEvent {
+            variant: Some(peers.roster_change(&status)),
+        };
+        let server_event = protocol::ServerEvent {
+            variant: Some(protocol::server_event::Variant::Status(status)),
+        };
+        transport::send_server_event(&server_event, writer).await;
+        transport::send_server_event(&roster_change, writer).await;
     }
 }
 
+fn schedule_redial(
+    addr: SocketAddr,
+    delay: std::time::Duration,
+    tx_loopback: &mpsc::Sender<protocol::NodeEvent>,
+    token: &tokio_util::sync::CancellationToken,
+) {
+    let tx = tx_loopback.clone();
+    let tok = token.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = tok.cancelled() => {}
+            _ = tokio::time::sleep(delay) => {
+                let event = protocol::PeerEvent::Redial { addr };
+                let _ = tx.send(protocol::NodeEvent::Net(event)).await;
+            }
+        }
+    });
+}
+
+/// Resolves a `host:port` string and asks the event loop to dial it. Static
+/// peers keep retrying, since their host may simply not be up yet.
+async fn resolve_peer(
+    address: String,
+    persistent: bool,
+    tx: mpsc::Sender<protocol::NodeEvent>,
+    token: tokio_util::sync::CancellationToken,
+) {
+    let mut attempt = 0;
+    loop {
+        match tokio::net::lookup_host(address.as_str()).await {
+            Ok(mut addrs) => match addrs.next() {
+                Some(addr) => {
+                    let event = protocol::PeerEvent::Dial { addr, persistent };
+                    let _ = tx.send(protocol::NodeEvent::Net(event)).await;
+                    return;
+                }
+                None => warn!("No addresses found for peer {}", address),
+            },
+            Err(e) => warn!("Failed to resolve peer {}: {}", address, e),
+        }
+        if !persistent {
+            return;
+        }
+        tokio::select! {
+            _ = token.cancelled() => return,
+            _ = tokio::time::sleep(peers::backoff_delay(attempt)) => attempt += 1,
+        }
+    }
+}
+
+fn dial(
+    addr: SocketAddr,
+    tx_loopback: &mpsc::Sender<protocol::NodeEvent>,
+    token: &tokio_util::sync::CancellationToken,
+    link: &transport::LinkConfig,
+) {
+    tokio::spawn(transport::connect_to_peer(
+        addr,
+        tx_loopback.clone(),
+        token.clone(),
+        link.clone(),
+    ));
+}
+
 async fn handle_local_op(
     session: &mut Session,
     local_op: protocol::LocalOp,
-    peers: &HashMap<PeerId, mpsc::Sender<protocol::PeerSyncOp>>,
-    writer: &mut FramedWrite<tokio::io::Stdout, LengthDelimitedCodec>,
+    peers: &PeerManager,
+    gossip: &mut Gossip,
+    trace: &Trace,
+    writer: &mut transport::EventWriter,
 ) {
-    match session.apply_local_op(local_op.clone()) {
+    match session.apply_local_op(local_op) {
         Some(remote_op) => {
+            trace.edit(&remote_op);
             let server_event = protocol::ServerEvent {
                 variant: Some(protocol::server_event::Variant::Op(local_op)),
             };
             transport::send_server_event(&server_event, writer).await;
 
-            for (peer_id, tx) in peers.iter() {
-                let tx = tx.clone();
-                let msg = remote_op.clone();
-                let peer_id = *peer_id;
-
-                tokio::spawn(async move {
-                    if let Err(_) = tx.send(msg).await {
-                        eprintln!("Failed to send to peer {}, channel closed", peer_id);
-                    }
-                });
-            }
+            gossip.local_op(&remote_op);
+            broadcast(peers, session, &remote_op, None);
         }
         None => {
-            panic!("Failed to apply operation");
+            warn!("Ignoring edit that doesn't fit the document");
+        }
+    }
+}
+
+/// Applies an op received from peer `from` and relays it to every other
+/// link, so edits reach peers we have no direct link to.
+async fn handle_sync_op(
+    session: &mut Session,
+    from: PeerId,
+    op: protocol::PeerSyncOp,
+    peers: &PeerManager,
+    gossip: &mut Gossip,
+    writer: &mut transport::EventWriter,
+) {
+    let outcome = gossip.receive(session, from, op);
+    if let Some(server_event) = outcome.event {
+        transport::send_server_event(&server_event, writer).await;
+    }
+    if !outcome.diverged.is_empty() {
+        warn!(
+            "Document diverged from peer {} in {} of {} ranges, reconciling",
+            from,
+            outcome.diverged.len(),
+            DIGEST_BUCKETS
+        );
+        let server_event = protocol::ServerEvent {
+            variant: Some(protocol::server_event::Variant::Divergence(
+                protocol::DivergenceReport {
+                    peer_id: from as u32,
+                    ranges: outcome.diverged,
+                    total_ranges: DIGEST_BUCKETS as u32,
+                },
+            )),
+        };
+        transport::send_server_event(&server_event, writer).await;
+    }
+    if let Some(reply) = outcome.reply {
+        send_to(peers, session, from, reply);
+    }
+    if let Some(relay) = outcome.relay {
+        broadcast(peers, session, &relay, Some(from));
+    }
+}
+
+/// Queues `op` for `peer_id`. A peer that can't keep up gets a snapshot of
+/// `session` instead of its backlog, and is dropped if even that doesn't
+/// get through.
+fn send_to(peers: &PeerManager, session: &Session, peer_id: PeerId, op: protocol::PeerSyncOp) {
+    let Some(outbox) = peers.link(peer_id) else {
+        return;
+    };
+    match outbox.push(op, || session.get_doc_snapshot()) {
+        Push::Queued => {}
+        Push::Resynced => {
+            info!("Peer {} fell behind, resyncing with a snapshot", peer_id);
+        }
+        Push::Overflowed => {
+            warn!(
+                "Peer {} still behind after a resync, dropping link",
+                peer_id
+            );
+            peers.close(peer_id);
         }
     }
 }
+
+fn broadcast(
+    peers: &PeerManager,
+    session: &Session,
+    op: &protocol::PeerSyncOp,
+    except: Option<PeerId>,
+) {
+    for peer_id in peers.links().map(|(id, _)| *id) {
+        if Some(peer_id) != except {
+            send_to(peers, session, peer_id, op.clone());
+        }
+    }
+}
+
+async fn handle_validate(
+    session: &mut Session,
+    repair: bool,
+    peers: &PeerManager,
+    trace: &Trace,
+    writer: &mut transport::EventWriter,
+) {
+    let report = session.validate_doc(repair);
+    for issue in &report.issues {
+        warn!("Validation: {}", issue);
+    }
+    let repaired = report.repaired;
+    let server_event = protocol::ServerEvent {
+        variant: Some(protocol::server_event::Variant::Report(report)),
+    };
+    transport::send_server_event(&server_event, writer).await;
+
+    if !repaired {
+        return;
+    }
+    trace.repair(&session.get_doc_snapshot());
+    let server_event = protocol::ServerEvent {
+        variant: Some(protocol::server_event::Variant::State(
+            protocol::FullState {
+                content: session.get_doc_ascii(),
+            },
+        )),
+    };
+    transport::send_server_event(&server_event, writer).await;
+
+    let msg = protocol::PeerSyncOp::FullSync {
+        state: session.get_doc_snapshot(),
+    };
+    broadcast(peers, session, &msg, None);
+}
@@ -1,33 +1,119 @@
+use crate::config::Role;
 use crate::protocol;
-use crate::state::{Doc, NodeKey};
+use crate::state::{self, Doc, NodeKey};
 use crate::types::PeerId;
+use crate::{debug, error, warn};
+use rand::Rng;
+use std::collections::HashMap;
 use std::sync::Arc;
 
 pub struct Session {
     doc: Doc,
     local_id: PeerId,
+    next_op_seq: u64,
+    /// Roles of linked peers, going by the key each proved it holds. Set
+    /// once a link is kept, so a peer can't claim another's ID to take its role.
+    roles: HashMap<PeerId, Role>,
+    /// Role of everyone else, such as the origins of relayed edits. Owner,
+    /// as in the config, unless set otherwise.
+    default_role: Role,
 }
 
 impl Session {
+    pub fn new(id: PeerId) -> Self {
+        Self::with_doc(id, Doc::new())
+    }
+
+    pub fn with_doc(id: PeerId, doc: Doc) -> Self {
+        Self {
+            doc,
+            local_id: id,
+            next_op_seq: rand::rng().random(),
+            roles: HashMap::new(),
+            default_role: Role::Owner,
+        }
+    }
+
+    pub fn local_id(&self) -> PeerId {
+        self.local_id
+    }
+
+    /// Replaces the document outright, as a repair does.
+    pub fn set_doc(&mut self, doc: Doc) {
+        self.doc = doc;
+    }
+
+    pub fn set_peer_role(&mut self, id: PeerId, role: Role) {
+        self.roles.insert(id, role);
+    }
+
+    pub fn set_default_role(&mut self, role: Role) {
+        self.default_role = role;
+    }
+
+    fn may_edit(&self, id: PeerId) -> bool {
+        self.roles
+            .get(&id)
+            .copied()
+            .unwrap_or(self.default_role)
+            .can_edit()
+    }
+
+    /// Whether `op` received from peer `from` may change the document. An
+    /// edit must come from, and have been made by, peers allowed to edit;
+    /// viewers' snapshots are never merged.
+    pub fn accepts(&self, from: PeerId, op: &protocol::PeerSyncOp) -> bool {
+        match op {
+            protocol::PeerSyncOp::Digest { .. } => true,
+            protocol::PeerSyncOp::Insert { op_id, .. }
+            | protocol::PeerSyncOp::Remove { op_id, .. } => {
+                self.may_edit(from) && self.may_edit(op_id.origin)
+            }
+            protocol::PeerSyncOp::FullSync { .. } | protocol::PeerSyncOp::RangeSync { .. } => {
+                self.may_edit(from)
+            }
+        }
+    }
+
     pub fn from(id: PeerId, path: &str) -> Self {
         let doc = match std::fs::read(path) {
             Ok(bytes) => Doc::load_bytes(&bytes).unwrap_or_else(|e| {
-                eprintln!("Failed to parse {}: {}", path, e);
+                error!("Failed to parse {}: {}", path, e);
                 Doc::new()
             }),
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Doc::new(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Self::new(id),
             Err(e) => {
-                eprintln!("Failed to read {}: {}", path, e);
+                error!("Failed to read {}: {}", path, e);
                 Doc::new()
             }
         };
-        Self { doc, local_id: id }
+        if let Err(issues) = doc.validate() {
+            warn!("Document {} failed validation:", path);
+            for issue in &issues {
+                warn!("  {}", issue);
+            }
+            warn!("Rebuilding document from its live contents");
+            return Self::with_doc(id, doc.repair(id));
+        }
+        Self::with_doc(id, doc)
     }
 
     pub fn get_doc_snapshot(&self) -> Doc {
         self.doc.clone()
     }
 
+    pub fn entry_counts(&self) -> (usize, usize) {
+        self.doc.entry_counts()
+    }
+
+    pub fn digest(&self) -> Vec<u64> {
+        self.doc.digest()
+    }
+
+    pub fn slice(&self, buckets: &[u32]) -> Doc {
+        self.doc.slice(buckets)
+    }
+
     pub fn get_doc_ascii(&self) -> Vec<u8> {
         self.doc.collect_ascii()
     }
@@ -41,39 +127,58 @@ impl Session {
         self.doc.save_text(path)
     }
 
+    pub fn validate_doc(&mut self, repair: bool) -> protocol::ValidationReport {
+        let issues = match self.doc.validate() {
+            Ok(()) => Vec::new(),
+            Err(issues) => issues.iter().map(ToString::to_string).collect(),
+        };
+        let repaired = repair && !issues.is_empty();
+        if repaired {
+            self.doc = self.doc.repair(self.local_id);
+        }
+        protocol::ValidationReport { issues, repaired }
+    }
+
     pub fn apply_local_op(&mut self, local_op: protocol::LocalOp) -> Option<protocol::PeerSyncOp> {
-        let ret = match local_op.op_type.unwrap() {
+        let Some(op_type) = local_op.op_type else {
+            warn!("Err: Edit without an operation received");
+            return None;
+        };
+        match op_type {
             protocol::local_op::OpType::Insert(insert) => {
                 self.apply_local_insert(local_op.position, insert)
             }
             protocol::local_op::OpType::Remove(_) => self.apply_local_remove(local_op.position),
-        };
-        ret
+        }
     }
 
     pub fn apply_peer_sync_op(
         &mut self,
+        from: PeerId,
         sync_op: protocol::PeerSyncOp,
     ) -> Option<protocol::ServerEvent> {
         use protocol::{PeerSyncOp, server_event};
 
+        if !self.accepts(from, &sync_op) {
+            warn!("Rejected change from read-only peer {}", from);
+            return None;
+        }
+
         let event_variant = match sync_op {
-            PeerSyncOp::Insert { char_id, value } => {
-                if let Some(event) = self.apply_remote_insert(char_id, value) {
-                    event
-                } else {
-                    return None;
-                }
-            }
-            PeerSyncOp::Remove { char_id } => {
-                if let Some(event) = self.apply_remote_remove(char_id) {
-                    event
-                } else {
-                    return None;
-                }
+            PeerSyncOp::Insert { char_id, value, .. } => {
+                self.apply_remote_insert(char_id, value)?
             }
-            PeerSyncOp::FullSync { state } => {
+            PeerSyncOp::Remove { char_id, .. } => self.apply_remote_remove(char_id)?,
+            PeerSyncOp::Digest { .. } => return None,
+            PeerSyncOp::FullSync { state } | PeerSyncOp::RangeSync { state, .. } => {
                 self.doc.merge_state(state);
+                #[cfg(debug_assertions)]
+                if let Err(issues) = self.doc.validate() {
+                    error!("Document invalid after merge:");
+                    for issue in &issues {
+                        error!("  {}", issue);
+                    }
+                }
                 server_event::Variant::State(protocol::FullState {
                     content: self.doc.collect_ascii(),
                 })
@@ -90,57 +195,71 @@ impl Session {
         insert: protocol::LocalInsert,
     ) -> Option<protocol::PeerSyncOp> {
         let Ok(value) = u8::try_from(insert.value) else {
-            eprintln!("Err: Invalid char code received: {}", insert.value);
+            warn!("Err: Invalid char code received: {}", insert.value);
             return None;
         };
-        eprintln!("Insert: {} ({:?})", value, char::from(value));
+        debug!("Insert: {} ({:?})", value, char::from(value));
 
         match self.doc.insert_absolute(self.local_id, pos as usize, value) {
             Ok(id) => {
-                eprintln!("Doc: {}", self.doc.collect_string());
+                debug!("Doc: {}", self.doc.collect_string());
                 Some(protocol::PeerSyncOp::Insert {
+                    op_id: self.next_op_id(),
                     char_id: id.to_vec(),
                     value,
                 })
             }
             Err(e) => {
-                eprintln!("Insert logic error: {}", e);
-                return None;
+                error!("Insert logic error: {}", e);
+                None
             }
         }
     }
 
     fn apply_local_remove(&mut self, pos: u32) -> Option<protocol::PeerSyncOp> {
-        eprintln!("Remove at position: {}", pos);
+        debug!("Remove at position: {}", pos);
         match self.doc.remove_absolute(pos as usize) {
             Ok(id) => {
-                eprintln!("Doc: {}", self.doc.collect_string());
+                debug!("Doc: {}", self.doc.collect_string());
                 Some(protocol::PeerSyncOp::Remove {
+                    op_id: self.next_op_id(),
                     char_id: id.to_vec(),
                 })
             }
             Err(e) => {
-                eprintln!("Remove logic error: {}", e);
+                error!("Remove logic error: {}", e);
                 None
             }
         }
     }
 
+    fn next_op_id(&mut self) -> protocol::OpId {
+        self.next_op_seq = self.next_op_seq.wrapping_add(1);
+        protocol::OpId {
+            origin: self.local_id,
+            seq: self.next_op_seq,
+        }
+    }
+
     fn apply_remote_insert(
         &mut self,
         key: Vec<NodeKey>,
         value: u8,
     ) -> Option<protocol::server_event::Variant> {
+        if !state::is_inner(&key) {
+            warn!("Ignoring insert outside of the document");
+            return None;
+        }
         let key: Arc<[NodeKey]> = key.into();
 
         if let Err(e) = self.doc.insert_id(key.clone(), value) {
-            eprintln!("Error while inserting character: {}", e);
+            error!("Error while inserting character: {}", e);
             return None;
         }
         let raw_pos = self.doc.get_position(key)?;
 
         // Bezpieczne odejmowanie
-        let ui_pos = raw_pos.checked_sub(1).unwrap_or(0);
+        let ui_pos = raw_pos.saturating_sub(1);
 
         Some(protocol::server_event::Variant::Op(protocol::LocalOp {
             position: ui_pos as u32,
@@ -152,13 +271,17 @@ impl Session {
     }
 
     fn apply_remote_remove(&mut self, id: Vec<NodeKey>) -> Option<protocol::server_event::Variant> {
+        if !state::is_inner(&id) {
+            warn!("Ignoring removal outside of the document");
+            return None;
+        }
         let id: Arc<[NodeKey]> = id.into();
 
         self.doc.insert_cmentary(id.clone());
         let pos = self.doc.get_position(id.clone())?;
 
         if let Err(e) = self.doc.remove_id(id) {
-            eprintln!("Error while deleting character: {}", e);
+            error!("Error while deleting character: {}", e);
             return None;
         }
 
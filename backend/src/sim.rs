@@ -0,0 +1,325 @@
+use crate::gossip::Gossip;
+use crate::protocol::{self, PeerSyncOp};
+use crate::session::Session;
+use crate::types::PeerId;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashSet};
+
+/// Which replicas have a direct link. Ops reach everyone else by relay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Topology {
+    FullMesh,
+    /// 0 - 1 - 2 - ... - n; every op but those between neighbours is relayed.
+    Line,
+}
+
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    pub seed: u64,
+    pub peers: usize,
+    pub steps: usize,
+    pub max_delay: u64,
+    pub duplicate_rate: f64,
+    pub partition_rate: f64,
+    pub remove_rate: f64,
+    pub topology: Topology,
+    /// Share of messages lost without a trace, like a failed channel send.
+    pub drop_rate: f64,
+    /// Steps between digest exchanges; 0 disables anti-entropy.
+    pub anti_entropy_period: u64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            peers: 3,
+            steps: 200,
+            max_delay: 20,
+            duplicate_rate: 0.05,
+            partition_rate: 0.02,
+            remove_rate: 0.3,
+            topology: Topology::FullMesh,
+            drop_rate: 0.0,
+            anti_entropy_period: 0,
+        }
+    }
+}
+
+struct Envelope {
+    deliver_at: u64,
+    seq: u64,
+    from: usize,
+    to: usize,
+    op: PeerSyncOp,
+}
+
+impl Envelope {
+    fn key(&self) -> (u64, u64) {
+        (self.deliver_at, self.seq)
+    }
+}
+
+impl PartialEq for Envelope {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Envelope {}
+
+impl PartialOrd for Envelope {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Reversed so that the BinaryHeap pops the earliest delivery first.
+impl Ord for Envelope {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.key().cmp(&self.key())
+    }
+}
+
+/// In-process network of `Session` replicas. Every source of nondeterminism
+/// (edit positions, link delays, duplication, partitions) is drawn from one
+/// seeded rng, so a failing seed reproduces the exact same schedule.
+pub struct Simulator {
+    config: SimConfig,
+    rng: StdRng,
+    replicas: Vec<Session>,
+    gossip: Vec<Gossip>,
+    in_flight: BinaryHeap<Envelope>,
+    partitions: HashSet<(usize, usize)>,
+    now: u64,
+    seq: u64,
+    settling: bool,
+}
+
+impl Simulator {
+    pub fn new(config: SimConfig) -> Self {
+        let replicas = (1..=config.peers)
+            .map(|id| Session::new(id as PeerId))
+            .collect();
+        let gossip = (0..config.peers).map(|_| Gossip::new()).collect();
+        Self {
+            gossip,
+            rng: StdRng::seed_from_u64(config.seed),
+            config,
+            replicas,
+            in_flight: BinaryHeap::new(),
+            partitions: HashSet::new(),
+            now: 0,
+            seq: 0,
+            settling: false,
+        }
+    }
+
+    pub fn texts(&self) -> Vec<Vec<u8>> {
+        self.replicas.iter().map(Session::get_doc_ascii).collect()
+    }
+
+    /// Runs the configured number of steps, heals every partition, delivers
+    /// everything still in flight and checks that all replicas converged.
+    /// With anti-entropy enabled, dropped messages are made up for by a few
+    /// more digest rounds, over links that no longer lose anything.
+    pub fn run(&mut self) -> Result<Vec<u8>, String> {
+        for _ in 0..self.config.steps {
+            self.step();
+        }
+        self.settling = true;
+        let partitioned: Vec<_> = self.partitions.iter().copied().collect();
+        // Healing resumes sync on both sides, and whatever that brings is
+        // relayed onwards like it is between real nodes.
+        for (a, b) in partitioned {
+            self.heal(a, b);
+        }
+        self.drain();
+        if self.config.anti_entropy_period > 0 {
+            for _ in 0..16 {
+                if self.check_converged().is_ok() {
+                    break;
+                }
+                self.exchange_digests();
+                self.drain();
+            }
+        }
+        self.check_converged()
+    }
+
+    fn drain(&mut self) {
+        while let Some(envelope) = self.in_flight.pop() {
+            self.now = envelope.deliver_at;
+            self.deliver(envelope);
+        }
+    }
+
+    pub fn check_converged(&mut self) -> Result<Vec<u8>, String> {
+        let texts = self.texts();
+        for (idx, text) in texts.iter().enumerate().skip(1) {
+            if *text != texts[0] {
+                return Err(format!(
+                    "seed {}: replica {} diverged\n  replica 0: {:?}\n  replica {}: {:?}",
+                    self.config.seed,
+                    idx,
+                    String::from_utf8_lossy(&texts[0]),
+                    idx,
+                    String::from_utf8_lossy(text),
+                ));
+            }
+        }
+        for (idx, replica) in self.replicas.iter_mut().enumerate() {
+            let report = replica.validate_doc(false);
+            if !report.issues.is_empty() {
+                return Err(format!(
+                    "seed {}: replica {} is invalid: {:?}",
+                    self.config.seed, idx, report.issues
+                ));
+            }
+        }
+        Ok(texts[0].clone())
+    }
+
+    fn step(&mut self) {
+        self.now += 1;
+        if self.config.anti_entropy_period > 0
+            && self.now.is_multiple_of(self.config.anti_entropy_period)
+        {
+            self.exchange_digests();
+        }
+
+        if self.config.peers > 1 && self.rng.random_bool(self.config.partition_rate) {
+            let a = self.rng.random_range(0..self.config.peers);
+            let neighbours = self.neighbours(a);
+            let b = neighbours[self.rng.random_range(0..neighbours.len())];
+            let link = (a.min(b), a.max(b));
+            if self.partitions.contains(&link) {
+                self.heal(link.0, link.1);
+            } else {
+                self.partitions.insert(link);
+                self.gossip[a].forget(b as PeerId + 1);
+                self.gossip[b].forget(a as PeerId + 1);
+            }
+        }
+
+        let origin = self.rng.random_range(0..self.config.peers);
+        if let Some(op) = self.random_local_op(origin) {
+            self.gossip[origin].local_op(&op);
+            self.relay(origin, None, op);
+        }
+
+        while self
+            .in_flight
+            .peek()
+            .is_some_and(|envelope| envelope.deliver_at <= self.now)
+        {
+            let envelope = self.in_flight.pop().unwrap();
+            self.deliver(envelope);
+        }
+    }
+
+    fn random_local_op(&mut self, origin: usize) -> Option<PeerSyncOp> {
+        let len = self.replicas[origin].get_doc_ascii().len();
+        let op_type = if len > 0 && self.rng.random_bool(self.config.remove_rate) {
+            protocol::local_op::OpType::Remove(protocol::LocalRemove {})
+        } else {
+            protocol::local_op::OpType::Insert(protocol::LocalInsert {
+                value: self.rng.random_range(b'a'..=b'z') as u32,
+            })
+        };
+        let position = match op_type {
+            protocol::local_op::OpType::Remove(_) => self.rng.random_range(1..=len),
+            protocol::local_op::OpType::Insert(_) => self.rng.random_range(0..=len),
+        };
+        self.replicas[origin].apply_local_op(protocol::LocalOp {
+            position: position as u32,
+            remote: false,
+            op_type: Some(op_type),
+        })
+    }
+
+    fn send(&mut self, from: usize, to: usize, op: PeerSyncOp) {
+        if self.config.drop_rate > 0.0
+            && !self.settling
+            && self.rng.random_bool(self.config.drop_rate)
+        {
+            return;
+        }
+        let copies = if self.rng.random_bool(self.config.duplicate_rate) {
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let delay = self.rng.random_range(0..=self.config.max_delay);
+            self.seq += 1;
+            self.in_flight.push(Envelope {
+                deliver_at: self.now + delay,
+                seq: self.seq,
+                from,
+                to,
+                op: op.clone(),
+            });
+        }
+    }
+
+    // A partition drops traffic like a broken TCP link would; healing it
+    // mirrors a reconnect, where both sides resume from the last digest.
+    fn deliver(&mut self, envelope: Envelope) {
+        let link = (
+            envelope.from.min(envelope.to),
+            envelope.from.max(envelope.to),
+        );
+        if self.partitions.contains(&link) {
+            return;
+        }
+        let to = envelope.to;
+        let outcome = self.gossip[to].receive(
+            &mut self.replicas[to],
+            envelope.from as PeerId + 1,
+            envelope.op,
+        );
+        if let Some(op) = outcome.reply {
+            self.send(to, envelope.from, op);
+        }
+        if let Some(op) = outcome.relay {
+            self.relay(to, Some(envelope.from), op);
+        }
+    }
+
+    fn exchange_digests(&mut self) {
+        for replica in 0..self.config.peers {
+            let digest = self.gossip[replica].digest(&self.replicas[replica]);
+            self.relay(replica, None, digest);
+        }
+    }
+
+    fn relay(&mut self, from: usize, except: Option<usize>, op: PeerSyncOp) {
+        for to in self.neighbours(from) {
+            if Some(to) != except {
+                self.send(from, to, op.clone());
+            }
+        }
+    }
+
+    fn neighbours(&self, replica: usize) -> Vec<usize> {
+        match self.config.topology {
+            Topology::FullMesh => (0..self.config.peers).filter(|to| *to != replica).collect(),
+            Topology::Line => [replica.checked_sub(1), Some(replica + 1)]
+                .into_iter()
+                .flatten()
+                .filter(|to| *to < self.config.peers)
+                .collect(),
+        }
+    }
+
+    fn heal(&mut self, a: usize, b: usize) {
+        self.partitions.remove(&(a, b));
+        let sync_a = self.gossip[a].initial_sync(&self.replicas[a], b as PeerId + 1);
+        let sync_b = self.gossip[b].initial_sync(&self.replicas[b], a as PeerId + 1);
+        self.send(a, b, sync_a);
+        self.send(b, a, sync_b);
+    }
+}
@@ -8,14 +8,27 @@ use num_traits::One;
 use rand::rngs::StdRng;
 use rand::{Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::cmp::min;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs::File;
 use std::io::Write;
 use std::sync::Arc;
 use std::vec;
 
 const SEED: [u8; 32] = [0; 32];
+const BOS_KEY: NodeKey = NodeKey::new(MIN_POSITION_DIGIT, RESERVED_PEER, 0);
+const EOS_KEY: NodeKey = NodeKey::new(MAX_POSITION_DIGIT, RESERVED_PEER, 0);
+/// Fills a zero digit under a shorter identifier. It only ever sits inside a
+/// longer identifier, never alone, and its timestamp keeps it apart from
+/// `BOS_KEY` so a key on its own tells padding from a sentinel.
+pub(crate) const PAD_KEY: NodeKey = NodeKey::new(MIN_POSITION_DIGIT, RESERVED_PEER, 1);
+
+/// Number of identifier buckets a document digest is split into. At most
+/// 64, as `Doc::slice` picks buckets by bitmask.
+pub const DIGEST_BUCKETS: usize = 64;
+const _: () = assert!(DIGEST_BUCKETS <= u64::BITS as usize);
 
 pub fn now_millis() -> u64 {
     std::time::SystemTime::now()
@@ -32,11 +45,80 @@ pub struct NodeKey {
 }
 
 impl NodeKey {
-    pub fn new(digit: Digit, peer_id: PeerId, time: Timestamp) -> Self {
+    pub const fn new(digit: Digit, peer_id: PeerId, time: Timestamp) -> Self {
         Self {
-            digit: digit,
-            peer_id: peer_id,
-            time: time,
+            digit,
+            peer_id,
+            time,
+        }
+    }
+
+    pub const fn digit(&self) -> Digit {
+        self.digit
+    }
+
+    pub const fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    pub const fn time(&self) -> Timestamp {
+        self.time
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DocIssue {
+    MissingBos,
+    MissingEos,
+    Unsorted { index: usize },
+    Duplicate { index: usize },
+    Tombstoned { index: usize },
+    MultipleNewDigits { index: usize, count: usize },
+}
+
+/// Whether `id` is the BOS or EOS sentinel.
+pub fn is_boundary(id: &[NodeKey]) -> bool {
+    id == [BOS_KEY] || id == [EOS_KEY]
+}
+
+/// Whether `id` can name a character: it sorts strictly between BOS and EOS.
+pub fn is_inner(id: &[NodeKey]) -> bool {
+    id > &[BOS_KEY][..] && id < &[EOS_KEY][..]
+}
+
+/// Bucket and hash of a digest entry. The bucket depends on the identifier
+/// only, so an entry and its tombstone land in the same bucket.
+fn entry_hash(id: &[NodeKey], value: Option<u8>) -> (usize, u64) {
+    let mut hasher = Sha256::new();
+    for key in id {
+        hasher.update(key.digit.to_be_bytes());
+        hasher.update([key.peer_id]);
+        hasher.update(key.time.to_be_bytes());
+    }
+    let bucket = hasher.clone().finalize()[0] as usize % DIGEST_BUCKETS;
+    match value {
+        Some(value) => hasher.update([1, value]),
+        None => hasher.update([0]),
+    }
+    let hash = hasher.finalize();
+    (bucket, u64::from_be_bytes(hash[..8].try_into().unwrap()))
+}
+
+impl fmt::Display for DocIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DocIssue::MissingBos => write!(f, "BOS node missing"),
+            DocIssue::MissingEos => write!(f, "EOS node missing"),
+            DocIssue::Unsorted { index } => write!(f, "identifier at {} is out of order", index),
+            DocIssue::Duplicate { index } => write!(f, "identifier at {} is duplicated", index),
+            DocIssue::Tombstoned { index } => {
+                write!(f, "identifier at {} is live but tombstoned", index)
+            }
+            DocIssue::MultipleNewDigits { index, count } => write!(
+                f,
+                "identifier at {} has {} new digits, expected at most one",
+                index, count
+            ),
         }
     }
 }
@@ -45,33 +127,31 @@ impl NodeKey {
 pub struct Doc {
     id_list: im::Vector<(Arc<[NodeKey]>, u8)>,
     cmentary: HashSet<Arc<[NodeKey]>>,
+    // Last timestamp handed out by construct_id; keeps keys from one peer
+    // unique even when several are generated within the same millisecond.
+    #[serde(skip)]
+    last_time: Timestamp,
 }
 
 impl Doc {
     pub fn new() -> Self {
         let mut id_list = im::Vector::new();
-        id_list.push_back((
-            Arc::from(vec![NodeKey::new(MIN_POSITION_DIGIT, RESERVED_PEER, 0)].into_boxed_slice()),
-            BOS_CHAR,
-        ));
-        id_list.push_back((
-            Arc::from(vec![NodeKey::new(MAX_POSITION_DIGIT, RESERVED_PEER, 0)].into_boxed_slice()),
-            EOS_CHAR,
-        ));
+        id_list.push_back((Arc::from(vec![BOS_KEY].into_boxed_slice()), BOS_CHAR));
+        id_list.push_back((Arc::from(vec![EOS_KEY].into_boxed_slice()), EOS_CHAR));
         Self {
-            id_list: id_list,
+            id_list,
             cmentary: HashSet::default(),
+            last_time: 0,
         }
     }
 
     pub fn load_bytes(bytes: &[u8]) -> std::io::Result<Self> {
-        let doc = bincode::deserialize(bytes)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let doc = bincode::deserialize(bytes).map_err(std::io::Error::other)?;
         Ok(doc)
     }
 
     pub fn save_bytes(&self) -> std::io::Result<Vec<u8>> {
-        bincode::serialize(self).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+        bincode::serialize(self).map_err(std::io::Error::other)
     }
 
     pub fn save_text(&self, path: &str) -> std::io::Result<()> {
@@ -113,6 +193,9 @@ impl Doc {
     }
 
     pub fn insert_id(&mut self, id: Arc<[NodeKey]>, data: u8) -> Result<(), &'static str> {
+        if self.cmentary.contains(&id) {
+            return Err("Inserted ID was already removed");
+        }
         match self
             .id_list
             .binary_search_by(|(probe_id, _)| probe_id.cmp(&id))
@@ -174,6 +257,9 @@ impl Doc {
         if absolute_position == 0 {
             return Err("Can't remove BOS");
         }
+        if absolute_position + 1 == self.id_list.len() {
+            return Err("Can't remove EOS");
+        }
 
         if absolute_position >= self.id_list.len() {
             return Err("missing position");
@@ -184,6 +270,78 @@ impl Doc {
         Ok(id)
     }
 
+    /// Live and tombstoned entry counts. Edits only add entries or move them
+    /// from the live list to the tombstones, so this changes iff the
+    /// document did.
+    pub fn entry_counts(&self) -> (usize, usize) {
+        (self.id_list.len(), self.cmentary.len())
+    }
+
+    /// Live entries in document order, BOS and EOS included.
+    pub fn entries(&self) -> impl Iterator<Item = &(Arc<[NodeKey]>, u8)> {
+        self.id_list.iter()
+    }
+
+    pub fn tombstones(&self) -> impl Iterator<Item = &Arc<[NodeKey]>> {
+        self.cmentary.iter()
+    }
+
+    /// Rebuilds a document from what [`Doc::entries`] and
+    /// [`Doc::tombstones`] returned. Entries must already be in order.
+    pub fn from_parts(
+        entries: impl IntoIterator<Item = (Arc<[NodeKey]>, u8)>,
+        tombstones: impl IntoIterator<Item = Arc<[NodeKey]>>,
+    ) -> Doc {
+        Doc {
+            id_list: entries.into_iter().collect(),
+            cmentary: tombstones.into_iter().collect(),
+            last_time: 0,
+        }
+    }
+
+    /// Order-independent summary of the document. Every live entry and
+    /// tombstone is hashed into one of `DIGEST_BUCKETS` buckets by its
+    /// identifier, so replicas holding the same entries produce the same
+    /// digest and a mismatch pins down which buckets differ.
+    pub fn digest(&self) -> Vec<u64> {
+        let mut buckets = vec![0u64; DIGEST_BUCKETS];
+        for (id, value) in self.id_list.iter().filter(|(id, _)| !is_boundary(id)) {
+            let (bucket, hash) = entry_hash(id, Some(*value));
+            buckets[bucket] = buckets[bucket].wrapping_add(hash);
+        }
+        for id in &self.cmentary {
+            let (bucket, hash) = entry_hash(id, None);
+            buckets[bucket] = buckets[bucket].wrapping_add(hash);
+        }
+        buckets
+    }
+
+    /// Partial document holding only the entries and tombstones that fall
+    /// into `buckets`. Merging it into another replica reconciles just
+    /// those buckets.
+    pub fn slice(&self, buckets: &[u32]) -> Doc {
+        let mask = buckets
+            .iter()
+            .filter(|&&bucket| (bucket as usize) < DIGEST_BUCKETS)
+            .fold(0u64, |mask, bucket| mask | 1 << bucket);
+        let wanted = |id: &[NodeKey]| mask & 1 << entry_hash(id, None).0 != 0;
+        Doc {
+            id_list: self
+                .id_list
+                .iter()
+                .filter(|(id, _)| is_boundary(id) || wanted(id))
+                .cloned()
+                .collect(),
+            cmentary: self
+                .cmentary
+                .iter()
+                .filter(|id| wanted(id))
+                .cloned()
+                .collect(),
+            last_time: 0,
+        }
+    }
+
     pub fn merge_state(&mut self, other: Self) {
         self.cmentary.extend(other.cmentary);
 
@@ -197,6 +355,97 @@ impl Doc {
             .collect();
     }
 
+    pub fn validate(&self) -> Result<(), Vec<DocIssue>> {
+        let mut issues = Vec::new();
+
+        if !matches!(self.id_list.front(), Some((id, BOS_CHAR)) if **id == [BOS_KEY]) {
+            issues.push(DocIssue::MissingBos);
+        }
+        if !matches!(self.id_list.back(), Some((id, EOS_CHAR)) if **id == [EOS_KEY]) {
+            issues.push(DocIssue::MissingEos);
+        }
+
+        for (index, ((prev, _), (next, _))) in self.id_list.iter().tuple_windows().enumerate() {
+            match prev.cmp(next) {
+                std::cmp::Ordering::Less => {}
+                std::cmp::Ordering::Equal => issues.push(DocIssue::Duplicate { index: index + 1 }),
+                std::cmp::Ordering::Greater => issues.push(DocIssue::Unsorted { index: index + 1 }),
+            }
+        }
+
+        for (index, (id, _)) in self.id_list.iter().enumerate() {
+            if self.cmentary.contains(id) {
+                issues.push(DocIssue::Tombstoned { index });
+            }
+        }
+
+        // A digit is new when no other identifier, live or removed, carries the
+        // same key at the same depth. construct_id creates exactly one per path,
+        // not counting reserved padding keys.
+        let live: HashSet<&Arc<[NodeKey]>> = self.id_list.iter().map(|(id, _)| id).collect();
+        let mut key_uses: HashMap<(usize, NodeKey), usize> = HashMap::new();
+        let all_ids = self
+            .id_list
+            .iter()
+            .map(|(id, _)| id)
+            .chain(self.cmentary.iter().filter(|id| !live.contains(id)));
+        for id in all_ids {
+            for (depth, key) in id.iter().enumerate() {
+                *key_uses.entry((depth, *key)).or_default() += 1;
+            }
+        }
+        for (index, (id, _)) in self.id_list.iter().enumerate() {
+            let count = id
+                .iter()
+                .enumerate()
+                .filter(|(depth, key)| {
+                    key.peer_id != RESERVED_PEER && key_uses[&(*depth, **key)] == 1
+                })
+                .count();
+            if count > 1 {
+                issues.push(DocIssue::MultipleNewDigits { index, count });
+            }
+        }
+
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(issues)
+        }
+    }
+
+    /// Rebuilds the document from its visible text with freshly generated
+    /// identifiers. Old identifiers that didn't make it into the new list are
+    /// tombstoned, so merging the result into a peer's replica replaces its
+    /// copy instead of duplicating it.
+    pub fn repair(&self, peer_id: PeerId) -> Self {
+        let mut repaired = Self::new();
+        repaired.last_time = self.last_time;
+        let mut seen = HashSet::new();
+        for (id, byte) in self.id_list.iter() {
+            if **id == [BOS_KEY] || **id == [EOS_KEY] {
+                continue;
+            }
+            if !self.cmentary.contains(id) && seen.insert(id.clone()) {
+                let pos = repaired.id_list.len() - 2;
+                repaired
+                    .insert_absolute(peer_id, pos, *byte)
+                    .expect("EOS is always present in a new doc");
+            }
+        }
+
+        let rebuilt: HashSet<Arc<[NodeKey]>> =
+            repaired.id_list.iter().map(|(id, _)| id.clone()).collect();
+        repaired.cmentary = self
+            .cmentary
+            .iter()
+            .chain(self.id_list.iter().map(|(id, _)| id))
+            .filter(|id| !rebuilt.contains(*id))
+            .cloned()
+            .collect();
+        repaired
+    }
+
     pub(crate) fn generate_id(
         &mut self,
         p: &[NodeKey],
@@ -225,32 +474,56 @@ impl Doc {
             .chain(std::iter::repeat_n(0, depth.saturating_sub(len)))
             .rev()
             .collect::<Vec<Digit>>();
-        Self::construct_id(&digits, p, q, peer_id)
+        self.last_time = now_millis().max(self.last_time + 1);
+        Self::construct_id(&digits, p, q, peer_id, self.last_time)
     }
 
     fn find_interval(p: &[NodeKey], q: &[NodeKey]) -> (BigInt, BigInt, BigInt, usize) {
         let (mut p_it, mut q_it) = (p.iter(), q.iter());
         let (mut interval, mut p_pref, mut q_pref) = (BigInt::ZERO, BigInt::ZERO, BigInt::ZERO);
         let mut depth = 0;
+        let (mut same_path, mut diverged) = (true, false);
         while interval < BigInt::one() {
             depth += 1;
-            p_pref = (p_pref << 32) + p_it.next().map_or(0, |pos| pos.digit);
-            q_pref = (q_pref << 32) + q_it.next().map_or(0, |pos| pos.digit);
+            let p_pos = p_it.next();
+            let q_pos = q_it.next().filter(|_| !diverged);
+            p_pref = (p_pref << 32) + p_pos.map_or(0, |pos| pos.digit);
+            // Concurrent inserts from different peers can pick the same digit.
+            // Past that point there is no room between the digits themselves,
+            // so q is treated as p's next sibling and the new id goes below p.
+            q_pref = match (p_pos, q_pos) {
+                (Some(p_pos), Some(q_pos))
+                    if same_path && p_pos.digit == q_pos.digit && p_pos != q_pos =>
+                {
+                    diverged = true;
+                    &p_pref + 1
+                }
+                _ => (q_pref << 32) + q_pos.map_or(0, |pos| pos.digit),
+            };
+            same_path &= p_pos.is_some() && p_pos == q_pos;
             interval = &q_pref - &p_pref - 1;
         }
         (interval, p_pref, q_pref, depth)
     }
 
-    fn construct_id(r: &[Digit], p: &[NodeKey], q: &[NodeKey], peer_id: PeerId) -> Arc<[NodeKey]> {
+    fn construct_id(
+        r: &[Digit],
+        p: &[NodeKey],
+        q: &[NodeKey],
+        peer_id: PeerId,
+        time: Timestamp,
+    ) -> Arc<[NodeKey]> {
         let mut once = true;
-        let time = now_millis();
         let (mut p_it, mut q_it) = (p.iter(), q.iter());
+        let mut on_q_path = true;
         let mut id = Vec::new();
-        for digit in r {
+        for (depth, digit) in r.iter().enumerate() {
             let (p_opt, q_opt) = (p_it.next(), q_it.next());
             let pos = match (p_opt, q_opt) {
-                (Some(p), _) if *digit == p.digit => p.clone(),
-                (_, Some(q)) if *digit == q.digit => q.clone(),
+                (Some(p), _) if *digit == p.digit => *p,
+                (_, Some(q)) if on_q_path && *digit == q.digit => *q,
+                // Zero padding below a shorter p is structural, not a new position.
+                (None, _) if *digit == MIN_POSITION_DIGIT && depth + 1 < r.len() => PAD_KEY,
                 _ => {
                     once = if once {
                         false
@@ -264,26 +537,27 @@ impl Doc {
                     }
                 }
             };
+            on_q_path &= q_opt == Some(&pos);
             id.push(pos);
         }
         id.into()
     }
 
-    // #[cfg(test)]
-    // pub(crate) fn bos_id(&self) -> Arc<[NodeKey]> {
-    //     self.id_list
-    //         .first_key_value()
-    //         .expect("Error: BOS node missing")
-    //         .0
-    //         .clone()
-    // }
-
-    // #[cfg(test)]
-    // pub(crate) fn eos_id(&self) -> Arc<[NodeKey]> {
-    //     self.id_list
-    //         .last_key_value()
-    //         .expect("Error: EOS node missing")
-    //         .0
-    //         .clone()
-    // }
+    #[cfg(test)]
+    pub(crate) fn bos_id(&self) -> Arc<[NodeKey]> {
+        self.id_list
+            .front()
+            .expect("Error: BOS node missing")
+            .0
+            .clone()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn eos_id(&self) -> Arc<[NodeKey]> {
+        self.id_list
+            .back()
+            .expect("Error: EOS node missing")
+            .0
+            .clone()
+    }
 }
@@ -1,10 +1,45 @@
-use crate::state::{Doc, NodeKey};
+use crate::cli;
+use crate::config::{
+    self, DiscoveryConfig, HeartbeatConfig, IpcConfig, LimitsConfig, NodeConfig, RelayConfig, Role,
+    RolesConfig, TraceConfig,
+};
+use crate::gossip::{Gossip, SeenOps};
+use crate::logging::LogLevel;
+use crate::outbox::{Outbox, Push};
+use crate::peers::{self, PeerManager, Redial, RttStats};
+use crate::protocol::{
+    self, ClientCommand, CloseApplication, ListPeers, LocalInsert, LocalOp, LocalRemove, NodeEvent,
+    OpId, PeerBeacon, PeerEvent, PeerSyncOp, ServerEvent, client_command, local_op, peer_status,
+    server_event, wire,
+};
+use crate::rooms::{MAX_ROOM_NODES, MAX_ROOMS, RoomDirectory};
+use crate::service;
+use crate::session::Session;
+use crate::sim::{SimConfig, Simulator, Topology};
+use crate::state::{DIGEST_BUCKETS, Doc, DocIssue, NodeKey, PAD_KEY};
+use crate::trace::{self, Trace};
+use crate::transport::codec::{self, Frame, PeerSyncOpCodec};
+use crate::transport::handshake::Features;
+use crate::transport::memory::MemoryNetwork;
+use crate::transport::websocket::{self, WebSocketLink};
+use crate::transport::{self, HandshakeConfig, LinkConfig, StaticKey, handshake};
 use crate::types::{Digit, PeerId};
+use bytes::BytesMut;
+use futures::{SinkExt, StreamExt};
+use prost::Message;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
 use std::iter;
-use std::rc::Rc;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::codec::{Decoder, Encoder, Framed, FramedRead, FramedWrite, LengthDelimitedCodec};
+use tokio_util::sync::CancellationToken;
 
-fn from_digits(digits: &[Digit]) -> Rc<[NodeKey]> {
+fn from_digits(digits: &[Digit]) -> Arc<[NodeKey]> {
     digits
         .iter()
         .map(|digit| NodeKey::new(*digit, 0, 0))
@@ -15,11 +50,7 @@ fn from_digits(digits: &[Digit]) -> Rc<[NodeKey]> {
 pub fn id_test() {
     let peer_id: PeerId = 123;
     let mut doc = Doc::new();
-    let id = doc.generate_id(
-        &from_digits(&[0, std::u32::MAX]),
-        &from_digits(&[1]),
-        peer_id,
-    ); // digits are close on purpose
+    let id = doc.generate_id(&from_digits(&[0, u32::MAX]), &from_digits(&[1]), peer_id); // digits are close on purpose
     println!("{:?}", id);
 }
 
@@ -36,17 +67,17 @@ pub fn insert_delete_collect_test() -> Result<(), &'static str> {
         println!("after: {:?}", new_id);
         new_id = doc.generate_id(&new_id, &eos, peer_id);
         println!("new_id: {:?}\n", new_id);
-        doc.insert_id(new_id.clone(), ch)?;
+        doc.insert_id(new_id.clone(), ch as u8)?;
         ids.push(new_id.clone());
     }
-    let doc_str = doc.collect_string();
-    assert_eq!(test_str, doc_str);
+    let doc_str = doc.collect_ascii();
+    assert_eq!(test_str.as_bytes(), doc_str);
     for (id, ch) in iter::zip(ids, test_str.chars()) {
         println!("removing: {}", ch);
         doc.remove_id(id)?;
     }
-    let doc_str = doc.collect_string();
-    assert_eq!("", doc_str);
+    let doc_str = doc.collect_ascii();
+    assert!(doc_str.is_empty());
     Ok(())
 }
 
@@ -54,11 +85,11 @@ pub fn insert_delete_collect_test() -> Result<(), &'static str> {
 pub fn insert_absolute_test() -> Result<(), &'static str> {
     let peer_id: PeerId = 123;
     let mut doc = Doc::new();
-    doc.insert_absolute(peer_id, 0, 'a')?;
-    doc.insert_absolute(peer_id, 1, 'c')?;
-    doc.insert_absolute(peer_id, 1, 'b')?;
-    let doc_str = doc.collect_string();
-    assert_eq!("abc", doc_str);
+    doc.insert_absolute(peer_id, 0, b'a')?;
+    doc.insert_absolute(peer_id, 1, b'c')?;
+    doc.insert_absolute(peer_id, 1, b'b')?;
+    let doc_str = doc.collect_ascii();
+    assert_eq!(b"abc", doc_str.as_slice());
     Ok(())
 }
 
@@ -74,15 +105,15 @@ pub fn remove_absolute_test() -> Result<(), &'static str> {
         println!("ch: {}", ch);
         new_id = doc.generate_id(&new_id, &eos, peer_id);
         println!("new_id: {:?}\n", &new_id);
-        doc.insert_id(new_id.clone(), ch)?;
+        doc.insert_id(new_id.clone(), ch as u8)?;
         ids.push(new_id.clone());
     }
     (0..=test_str.len())
         .rev()
         .filter(|i| i % 2 == 1)
         .try_for_each(|i| doc.remove_absolute(i).map(drop))?;
-    let doc_str = doc.collect_string();
-    assert_eq!("abcdefg", doc_str);
+    let doc_str = doc.collect_ascii();
+    assert_eq!(b"abcdefg", doc_str.as_slice());
     Ok(())
 }
 
@@ -90,21 +121,2482 @@ pub fn remove_absolute_test() -> Result<(), &'static str> {
 pub fn insert_remove_absolute_test() -> Result<(), &'static str> {
     let peer_id: PeerId = 123;
     let mut doc = Doc::new();
-    doc.insert_absolute(peer_id, 0, 'a')?;
-    doc.insert_absolute(peer_id, 1, 'b')?;
-    doc.insert_absolute(peer_id, 2, 'c')?;
-    doc.insert_absolute(peer_id, 3, 'd')?;
-    doc.insert_absolute(peer_id, 4, 'e')?;
+    doc.insert_absolute(peer_id, 0, b'a')?;
+    doc.insert_absolute(peer_id, 1, b'b')?;
+    doc.insert_absolute(peer_id, 2, b'c')?;
+    doc.insert_absolute(peer_id, 3, b'd')?;
+    doc.insert_absolute(peer_id, 4, b'e')?;
     doc.remove_absolute(1)?; // bcde
     doc.remove_absolute(4)?; // bcd
     doc.remove_absolute(1)?; // cd
     doc.remove_absolute(2)?; // c
     // doc.remove_absolute(0); // EOS could be removed
-    let doc_str = doc.collect_string();
-    assert_eq!("c", doc_str);
+    let doc_str = doc.collect_ascii();
+    assert_eq!(b"c", doc_str.as_slice());
+    Ok(())
+}
+
+#[test]
+pub fn validate_test() -> Result<(), &'static str> {
+    let peer_id: PeerId = 123;
+    let mut doc = Doc::new();
+    assert_eq!(doc.validate(), Ok(()));
+    for (pos, ch) in "hello".bytes().enumerate() {
+        doc.insert_absolute(peer_id, pos, ch)?;
+    }
+    doc.remove_absolute(2)?;
+    assert_eq!(doc.validate(), Ok(()));
+
+    let id = doc.insert_absolute(peer_id, 1, b'x')?;
+    doc.insert_cmentary(id);
+    assert_eq!(doc.validate(), Err(vec![DocIssue::Tombstoned { index: 2 }]));
+
+    let mut doc = Doc::new();
+    let id: Arc<[NodeKey]> = [NodeKey::new(5, peer_id, 1), NodeKey::new(7, peer_id, 2)].into();
+    doc.insert_id(id, b'a')?;
+    assert_eq!(
+        doc.validate(),
+        Err(vec![DocIssue::MultipleNewDigits { index: 1, count: 2 }])
+    );
+    Ok(())
+}
+
+#[test]
+pub fn repair_test() -> Result<(), &'static str> {
+    let peer_id: PeerId = 123;
+    let mut doc = Doc::new();
+    for (pos, ch) in "hello".bytes().enumerate() {
+        doc.insert_absolute(peer_id, pos, ch)?;
+    }
+    let mut peer = doc.clone();
+    let id = doc.insert_absolute(peer_id, 5, b'!')?;
+    doc.insert_cmentary(id);
+    assert!(doc.validate().is_err());
+
+    let repaired = doc.repair(peer_id);
+    assert_eq!(repaired.validate(), Ok(()));
+    assert_eq!(repaired.collect_ascii(), b"hello");
+
+    peer.merge_state(repaired);
+    assert_eq!(peer.validate(), Ok(()));
+    assert_eq!(peer.collect_ascii(), b"hello");
     Ok(())
 }
 
+#[test]
+pub fn simulated_convergence_test() {
+    for seed in 0..64 {
+        let mut sim = Simulator::new(SimConfig {
+            seed,
+            peers: 2 + (seed as usize % 4),
+            max_delay: seed % 30,
+            ..SimConfig::default()
+        });
+        if let Err(e) = sim.run() {
+            panic!("{}", e);
+        }
+    }
+}
+
+#[test]
+pub fn simulated_regression_test() {
+    // Seeds that used to trip the "More than one new position" safeguard.
+    for seed in [411, 763] {
+        let mut sim = Simulator::new(SimConfig {
+            seed,
+            peers: 2 + (seed as usize % 4),
+            max_delay: seed % 30,
+            ..SimConfig::default()
+        });
+        if let Err(e) = sim.run() {
+            panic!("{}", e);
+        }
+    }
+}
+
+#[test]
+pub fn concurrent_same_digit_test() -> Result<(), &'static str> {
+    let mut doc_a = Doc::new();
+    let mut doc_b = Doc::new();
+    doc_a.insert_absolute(1, 0, b'a')?;
+    doc_b.insert_absolute(2, 0, b'b')?;
+    doc_a.merge_state(doc_b);
+    assert_eq!(doc_a.collect_ascii(), b"ab");
+
+    doc_a.insert_absolute(1, 1, b'c')?;
+    assert_eq!(doc_a.collect_ascii(), b"acb");
+    assert_eq!(doc_a.validate(), Ok(()));
+    Ok(())
+}
+
+#[test]
+pub fn id_padding_test() -> Result<(), &'static str> {
+    let mut doc = Doc::new();
+    let p: Arc<[NodeKey]> = [NodeKey::new(5, 1, 10)].into();
+    let q: Arc<[NodeKey]> = [
+        NodeKey::new(5, 1, 10),
+        NodeKey::new(0, 2, 20),
+        NodeKey::new(1, 2, 21),
+    ]
+    .into();
+    doc.insert_id(p.clone(), b'p')?;
+    doc.insert_id(q.clone(), b'q')?;
+
+    // No digit fits between p and q until below q's third key, so the new
+    // identifier pads the depth p doesn't reach instead of adding a second
+    // new key there.
+    let id = doc.generate_id(&p, &q, 3);
+    assert!(
+        p < id && id < q,
+        "{:?} isn't between {:?} and {:?}",
+        id,
+        p,
+        q
+    );
+    assert_eq!(id[..2], q[..2]);
+    assert_eq!(id[2], PAD_KEY);
+    assert_ne!(id[2], doc.bos_id()[0]);
+    doc.insert_id(id, b'x')?;
+    assert_eq!(doc.validate(), Ok(()));
+    assert_eq!(doc.collect_ascii(), b"pxq");
+    Ok(())
+}
+
+#[test]
+pub fn id_timestamp_test() -> Result<(), &'static str> {
+    let peer_id: PeerId = 7;
+    let mut doc = Doc::new();
+    let mut times = Vec::new();
+    for pos in 0..100 {
+        let id = doc.insert_absolute(peer_id, pos / 2, b'a')?;
+        let new_keys: Vec<_> = id.iter().filter(|key| key.peer_id() == peer_id).collect();
+        times.push(
+            new_keys
+                .last()
+                .expect("an inserted id has a new key")
+                .time(),
+        );
+    }
+    // Generated within the same few milliseconds, yet every key is unique.
+    assert!(times.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(doc.validate(), Ok(()));
+    Ok(())
+}
+
+#[test]
+pub fn simulated_partition_test() {
+    let mut sim = Simulator::new(SimConfig {
+        seed: 7,
+        peers: 4,
+        steps: 500,
+        partition_rate: 0.2,
+        duplicate_rate: 0.2,
+        ..SimConfig::default()
+    });
+    let text = sim.run().expect("replicas diverged");
+    assert!(!text.is_empty());
+}
+
+#[test]
+pub fn simulated_relay_test() {
+    // In a line, the two ends only ever hear of each other's edits by relay.
+    for seed in 0..16 {
+        let mut sim = Simulator::new(SimConfig {
+            seed,
+            peers: 5,
+            topology: Topology::Line,
+            partition_rate: 0.05,
+            duplicate_rate: 0.1,
+            ..SimConfig::default()
+        });
+        if let Err(e) = sim.run() {
+            panic!("{}", e);
+        }
+    }
+}
+
+#[test]
+pub fn digest_slice_test() -> Result<(), &'static str> {
+    let mut doc_a = Doc::new();
+    let mut doc_b = Doc::new();
+    for (pos, value) in b"shared".iter().enumerate() {
+        let id = doc_a.insert_absolute(1, pos, *value)?;
+        doc_b.insert_id(id, *value)?;
+    }
+    assert_eq!(doc_a.digest(), doc_b.digest());
+
+    doc_a.insert_absolute(1, 0, b'>')?;
+    doc_b.remove_absolute(1)?;
+    let diverged: Vec<u32> = (0..DIGEST_BUCKETS)
+        .filter(|&idx| doc_a.digest()[idx] != doc_b.digest()[idx])
+        .map(|idx| idx as u32)
+        .collect();
+    assert!(!diverged.is_empty() && diverged.len() <= 2);
+
+    let slice_a = doc_a.slice(&diverged);
+    doc_a.merge_state(doc_b.slice(&diverged));
+    doc_b.merge_state(slice_a);
+    assert_eq!(doc_a.digest(), doc_b.digest());
+    assert_eq!(doc_a.collect_ascii(), b">hared");
+    assert_eq!(doc_b.collect_ascii(), b">hared");
+    Ok(())
+}
+
+#[test]
+pub fn anti_entropy_test() {
+    let mut a = Session::new(1);
+    let mut b = Session::new(2);
+    let (mut gossip_a, mut gossip_b) = (Gossip::new(), Gossip::new());
+    let insert = |position, value| LocalOp {
+        position,
+        remote: false,
+        op_type: Some(local_op::OpType::Insert(LocalInsert { value })),
+    };
+    // b never hears of a's edit, as if the send had failed.
+    a.apply_local_op(insert(0, b'x' as u32));
+    b.apply_local_op(insert(0, b'y' as u32));
+
+    // A single mismatch could be an edit in flight; only the second counts.
+    let first = gossip_b.receive(&mut b, 1, gossip_a.digest(&a));
+    assert!(first.reply.is_none() && first.diverged.is_empty());
+    let second = gossip_b.receive(&mut b, 1, gossip_a.digest(&a));
+    assert!(!second.diverged.is_empty());
+
+    let back = gossip_a.receive(&mut a, 2, second.reply.unwrap());
+    assert!(back.event.is_some());
+    assert!(matches!(
+        back.reply,
+        Some(PeerSyncOp::RangeSync { reply: false, .. })
+    ));
+    let done = gossip_b.receive(&mut b, 1, back.reply.unwrap());
+    assert!(done.reply.is_none());
+    assert_eq!(a.digest(), b.digest());
+    assert_eq!(a.get_doc_ascii(), b.get_doc_ascii());
+
+    let settled = gossip_b.receive(&mut b, 1, gossip_a.digest(&a));
+    assert!(settled.reply.is_none() && settled.diverged.is_empty());
+}
+
+#[test]
+pub fn resume_sync_test() {
+    let mut a = Session::new(1);
+    let mut b = Session::new(2);
+    let (mut gossip_a, mut gossip_b) = (Gossip::new(), Gossip::new());
+    let insert = |position, value: u8| LocalOp {
+        position,
+        remote: false,
+        op_type: Some(local_op::OpType::Insert(LocalInsert {
+            value: value as u32,
+        })),
+    };
+    for position in 0..40 {
+        a.apply_local_op(insert(position, b'a'));
+    }
+
+    // Strangers exchange snapshots, then digests as anti-entropy would.
+    let to_b = gossip_a.initial_sync(&a, 2);
+    assert!(matches!(to_b, PeerSyncOp::FullSync { .. }));
+    gossip_b.receive(&mut b, 1, to_b);
+    gossip_a.receive(&mut a, 2, gossip_b.initial_sync(&b, 1));
+    gossip_a.receive(&mut a, 2, gossip_b.digest(&b));
+    gossip_b.receive(&mut b, 1, gossip_a.digest(&a));
+    gossip_a.forget(2);
+    gossip_b.forget(1);
+
+    // After a disconnect with an edit on each side, only what changed since
+    // the last digest goes either way.
+    a.apply_local_op(insert(0, b'<'));
+    b.apply_local_op(insert(40, b'>'));
+    let to_b = gossip_a.initial_sync(&a, 2);
+    let to_a = gossip_b.initial_sync(&b, 1);
+    for op in [&to_b, &to_a] {
+        match op {
+            PeerSyncOp::RangeSync {
+                buckets,
+                state,
+                reply,
+            } => {
+                assert!(*reply);
+                assert_eq!(buckets.len(), 1);
+                assert!(state.entry_counts().0 < 20);
+            }
+            other => panic!("expected a resume, got {:?}", other),
+        }
+    }
+    let at_b = gossip_b.receive(&mut b, 1, to_b);
+    let at_a = gossip_a.receive(&mut a, 2, to_a);
+    gossip_a.receive(&mut a, 2, at_b.reply.unwrap());
+    gossip_b.receive(&mut b, 1, at_a.reply.unwrap());
+    assert_eq!(a.get_doc_ascii(), b.get_doc_ascii());
+    assert_eq!(a.get_doc_ascii().len(), 42);
+
+    // b comes back empty-handed after a restart. Its snapshot in answer to
+    // a's resume gets it a snapshot back.
+    let mut b = Session::new(2);
+    let mut gossip_b = Gossip::new();
+    gossip_a.forget(2);
+    let to_b = gossip_a.initial_sync(&a, 2);
+    let to_a = gossip_b.initial_sync(&b, 1);
+    gossip_b.receive(&mut b, 1, to_b);
+    let at_a = gossip_a.receive(&mut a, 2, to_a);
+    assert!(matches!(at_a.reply, Some(PeerSyncOp::FullSync { .. })));
+    gossip_b.receive(&mut b, 1, at_a.reply.unwrap());
+    assert_eq!(a.get_doc_ascii(), b.get_doc_ascii());
+}
+
+#[test]
+pub fn simulated_anti_entropy_test() {
+    let mut lossy_diverged = 0;
+    for seed in 0..6 {
+        let config = SimConfig {
+            seed,
+            peers: 4,
+            partition_rate: 0.0,
+            drop_rate: 0.1,
+            anti_entropy_period: 25,
+            topology: if seed % 2 == 0 {
+                Topology::Line
+            } else {
+                Topology::FullMesh
+            },
+            ..SimConfig::default()
+        };
+        if let Err(e) = Simulator::new(config.clone()).run() {
+            panic!("{}", e);
+        }
+        let without = Simulator::new(SimConfig {
+            anti_entropy_period: 0,
+            ..config
+        })
+        .run();
+        lossy_diverged += without.is_err() as usize;
+    }
+    // Make sure the drops actually left something to repair.
+    assert!(lossy_diverged > 0);
+}
+
+#[test]
+pub fn seen_ops_test() {
+    let mut seen = SeenOps::new(2);
+    let op = |seq| OpId { origin: 1, seq };
+    assert!(seen.insert(op(1)));
+    assert!(!seen.insert(op(1)));
+    assert!(seen.insert(OpId { origin: 2, seq: 1 }));
+    assert!(seen.insert(op(2)));
+    // op(1) fell out of the window.
+    assert!(seen.insert(op(1)));
+    assert!(!seen.insert(op(2)));
+}
+
+#[test]
+pub fn simulated_remove_before_insert_test() {
+    let mut sim = Simulator::new(SimConfig {
+        seed: 3,
+        peers: 3,
+        max_delay: 100,
+        remove_rate: 0.5,
+        partition_rate: 0.0,
+        ..SimConfig::default()
+    });
+    sim.run().expect("replicas diverged");
+}
+
+async fn run_handshake(
+    a: HandshakeConfig,
+    b: HandshakeConfig,
+) -> (
+    std::io::Result<handshake::Established>,
+    std::io::Result<handshake::Established>,
+) {
+    // Each side drops its end when done, as a real link would on failure.
+    let (mut stream_a, mut stream_b) = tokio::io::duplex(1024);
+    tokio::join!(
+        async move { handshake::perform(&mut stream_a, &a).await },
+        async move { handshake::perform(&mut stream_b, &b).await }
+    )
+}
+
+fn handshake_config(my_id: PeerId, secret: Option<&str>) -> HandshakeConfig {
+    HandshakeConfig {
+        my_id,
+        secret: secret.map(String::from),
+        static_key: StaticKey::generate(),
+        trusted_fingerprints: Vec::new(),
+        features: Features::SUPPORTED,
+        roles: RolesConfig::default(),
+        name: format!("node {}", my_id),
+        room: "default".to_string(),
+    }
+}
+
+#[tokio::test]
+pub async fn handshake_test() {
+    let (a, b) = run_handshake(handshake_config(1, None), handshake_config(2, None)).await;
+    let (a, b) = (a.unwrap(), b.unwrap());
+    assert_eq!((a.peer_id, a.name.as_str()), (2, "node 2"));
+    assert_eq!((b.peer_id, b.name.as_str()), (1, "node 1"));
+
+    let mut long = handshake_config(1, None);
+    long.name = "é".repeat(handshake::MAX_NAME_LEN);
+    let (_, b) = run_handshake(long, handshake_config(2, None)).await;
+    assert_eq!(b.unwrap().name, "é".repeat(handshake::MAX_NAME_LEN / 2));
+
+    let (a, b) = run_handshake(
+        handshake_config(1, Some("hunter2")),
+        handshake_config(2, Some("hunter2")),
+    )
+    .await;
+    assert_eq!(a.unwrap().peer_id, 2);
+    assert_eq!(b.unwrap().peer_id, 1);
+}
+
+#[tokio::test]
+pub async fn handshake_rejects_wrong_secret_test() {
+    let (a, b) = run_handshake(
+        handshake_config(1, Some("hunter2")),
+        handshake_config(2, Some("letmein")),
+    )
+    .await;
+    assert_eq!(
+        a.err().unwrap().kind(),
+        std::io::ErrorKind::PermissionDenied
+    );
+    assert_eq!(
+        b.err().unwrap().kind(),
+        std::io::ErrorKind::PermissionDenied
+    );
+
+    let (a, b) = run_handshake(
+        handshake_config(1, Some("hunter2")),
+        handshake_config(2, None),
+    )
+    .await;
+    assert_eq!(
+        a.err().unwrap().kind(),
+        std::io::ErrorKind::PermissionDenied
+    );
+    assert_eq!(
+        b.err().unwrap().kind(),
+        std::io::ErrorKind::PermissionDenied
+    );
+}
+
+#[tokio::test]
+pub async fn handshake_negotiation_test() {
+    let mut a = handshake_config(1, None);
+    a.features = Features::COMPRESSION | Features::DELTAS;
+    let mut b = handshake_config(2, None);
+    b.features = Features::DELTAS | Features::PRESENCE;
+    let (a, b) = run_handshake(a, b).await;
+    let (a, b) = (a.unwrap(), b.unwrap());
+    assert_eq!(a.features, Features::DELTAS);
+    assert_eq!(b.features, Features::DELTAS);
+    assert_eq!(a.version, handshake::PROTOCOL_VERSION);
+}
+
+#[tokio::test]
+pub async fn handshake_supported_features_test() {
+    // Compression is the only feature links act on, so a peer offering
+    // everything must not get anything else out of a default node.
+    let mut b = handshake_config(2, None);
+    b.features = Features::COMPRESSION | Features::DELTAS | Features::PRESENCE;
+    let (a, b) = run_handshake(handshake_config(1, None), b).await;
+    let (a, b) = (a.unwrap(), b.unwrap());
+    assert_eq!(a.features, Features::COMPRESSION);
+    assert_eq!(b.features, Features::COMPRESSION);
+    assert!(Features::COMPRESSION.contains(Features::SUPPORTED));
+}
+
+#[tokio::test]
+pub async fn handshake_rejects_incompatible_peer_test() {
+    use tokio::io::AsyncWriteExt;
+
+    let future_version = handshake::PROTOCOL_VERSION + 1;
+    let mut hello = b"DTE\0".to_vec();
+    hello.extend_from_slice(&future_version.to_be_bytes());
+    hello.extend_from_slice(&future_version.to_be_bytes());
+    hello.extend_from_slice(&[2, 0, 0, 0, 0, 0]);
+    let (mut stream, mut peer) = tokio::io::duplex(1024);
+    peer.write_all(&hello).await.unwrap();
+    let result = handshake::perform(&mut stream, &handshake_config(1, None)).await;
+    assert_eq!(
+        result.err().unwrap().kind(),
+        std::io::ErrorKind::Unsupported
+    );
+
+    let (mut stream, mut peer) = tokio::io::duplex(1024);
+    peer.write_all(&[2, 0]).await.unwrap();
+    peer.write_all(&[0; 32]).await.unwrap();
+    let result = handshake::perform(&mut stream, &handshake_config(1, None)).await;
+    assert_eq!(
+        result.err().unwrap().kind(),
+        std::io::ErrorKind::InvalidData
+    );
+}
+
+#[tokio::test]
+pub async fn handshake_fingerprint_test() {
+    let a = handshake_config(1, None);
+    let mut b = handshake_config(2, None);
+    b.trusted_fingerprints = vec![a.static_key.fingerprint()];
+    let (a_result, b_result) = run_handshake(a.clone(), b.clone()).await;
+    let a_result = a_result.unwrap();
+    assert_eq!(
+        a_result.channel.remote_fingerprint,
+        b.static_key.fingerprint()
+    );
+    assert!(b_result.is_ok());
+
+    let stranger = handshake_config(3, None);
+    let (_, b_result) = run_handshake(stranger, b).await;
+    assert_eq!(
+        b_result.err().unwrap().kind(),
+        std::io::ErrorKind::PermissionDenied
+    );
+}
+
+#[tokio::test]
+pub async fn handshake_room_test() {
+    let mut a = handshake_config(1, None);
+    let mut b = handshake_config(2, None);
+    a.room = "design".to_string();
+    b.room = "design".to_string();
+    let (a_result, b_result) = run_handshake(a.clone(), b.clone()).await;
+    assert!(a_result.is_ok() && b_result.is_ok());
+
+    b.room = "ops".to_string();
+    let (a_result, b_result) = run_handshake(a, b).await;
+    assert_eq!(
+        a_result.err().unwrap().kind(),
+        std::io::ErrorKind::PermissionDenied
+    );
+    assert_eq!(
+        b_result.err().unwrap().kind(),
+        std::io::ErrorKind::PermissionDenied
+    );
+}
+
+#[tokio::test]
+pub async fn encrypted_codec_test() -> Result<(), Box<dyn std::error::Error>> {
+    let (a, b) = run_handshake(handshake_config(1, None), handshake_config(2, None)).await;
+    let mut encoder = PeerSyncOpCodec::new(a?.channel.encryptor);
+    let mut decoder = PeerSyncOpCodec::new(b?.channel.decryptor);
+
+    let secret = b"top secret meeting notes";
+    let mut doc = Doc::new();
+    for pos in 0..5_000 {
+        doc.insert_absolute(1, pos, secret[pos % secret.len()])?;
+    }
+    let mut wire = BytesMut::new();
+    encoder.encode(
+        PeerSyncOp::FullSync { state: doc.clone() }.into(),
+        &mut wire,
+    )?;
+    assert!(wire.len() > 65535);
+    assert!(!wire.windows(secret.len()).any(|window| window == secret));
+    let plain_len = wire.len();
+
+    match decoder.decode(&mut wire)? {
+        Some(Frame::Op(PeerSyncOp::FullSync { state })) => {
+            assert_eq!(state.collect_ascii().len(), 5_000);
+            assert!(state.collect_ascii().starts_with(secret));
+        }
+        _ => panic!("expected FullSync"),
+    }
+
+    let (a, b) = run_handshake(handshake_config(1, None), handshake_config(2, None)).await;
+    let mut encoder = PeerSyncOpCodec::new(a?.channel.encryptor).with_compression(true);
+    let mut decoder = PeerSyncOpCodec::new(b?.channel.decryptor).with_compression(true);
+    let mut compressed = BytesMut::new();
+    encoder.encode(PeerSyncOp::FullSync { state: doc }.into(), &mut compressed)?;
+    assert!(compressed.len() < plain_len);
+    encoder.encode(Frame::Ping { nonce: 1 }, &mut compressed)?;
+    match decoder.decode(&mut compressed)? {
+        Some(Frame::Op(PeerSyncOp::FullSync { state })) => {
+            assert_eq!(state.collect_ascii().len(), 5_000);
+        }
+        _ => panic!("expected FullSync"),
+    }
+    assert!(matches!(
+        decoder.decode(&mut compressed)?,
+        Some(Frame::Ping { nonce: 1 })
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+pub async fn frame_limits_test() -> Result<(), Box<dyn std::error::Error>> {
+    let limits = LimitsConfig {
+        max_frame_bytes: 4096,
+        max_identifier_depth: 8,
+        max_snapshot_keys: 100,
+        ..LimitsConfig::default()
+    };
+    let (a, b) = run_handshake(handshake_config(1, None), handshake_config(2, None)).await;
+    let mut encoder = PeerSyncOpCodec::new(a?.channel.encryptor);
+    let mut decoder = PeerSyncOpCodec::new(b?.channel.decryptor).with_limits(limits.clone());
+    let mut rejected = |frame: Frame| -> std::io::Result<()> {
+        let mut wire = BytesMut::new();
+        encoder.encode(frame, &mut wire)?;
+        let err = decoder.decode(&mut wire).expect_err("frame accepted");
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        Ok(())
+    };
+
+    // Deep identifiers, whether sent whole or through shared prefixes.
+    let deep = |depth: u64| (0..depth).map(|time| NodeKey::new(1, 1, time)).collect();
+    rejected(
+        PeerSyncOp::Remove {
+            op_id: OpId { origin: 1, seq: 1 },
+            char_id: deep(9),
+        }
+        .into(),
+    )?;
+    let mut doc = Doc::new();
+    doc.insert_id(deep(9).into(), b'x')?;
+    rejected(PeerSyncOp::FullSync { state: doc }.into())?;
+
+    // A snapshot that is small on the wire but expands past the limit.
+    let mut doc = Doc::new();
+    for time in 0..20 {
+        let mut id: Vec<NodeKey> = deep(7);
+        id.push(NodeKey::new(2, 1, time));
+        doc.insert_id(id.into(), b'x')?;
+    }
+    rejected(PeerSyncOp::FullSync { state: doc }.into())?;
+
+    rejected(
+        PeerSyncOp::FullSync {
+            state: typed_doc(1_000, 1),
+        }
+        .into(),
+    )?;
+
+    // Bucket lists longer than a digest, or naming buckets it doesn't have.
+    rejected(
+        PeerSyncOp::Digest {
+            buckets: vec![0; DIGEST_BUCKETS + 1],
+        }
+        .into(),
+    )?;
+    for buckets in [vec![DIGEST_BUCKETS as u32], vec![0; DIGEST_BUCKETS + 1]] {
+        rejected(
+            PeerSyncOp::RangeSync {
+                buckets,
+                state: Doc::new(),
+                reply: true,
+            }
+            .into(),
+        )?;
+    }
+
+    // Senders hold themselves to the same limit.
+    let (a, _) = run_handshake(handshake_config(1, None), handshake_config(2, None)).await;
+    let mut encoder = PeerSyncOpCodec::new(a?.channel.encryptor).with_limits(limits);
+    let big = PeerSyncOp::FullSync {
+        state: typed_doc(1_000, 1),
+    };
+    assert!(encoder.encode(big.into(), &mut BytesMut::new()).is_err());
+
+    let huge = vec![0u8; 1 << 20];
+    assert!(codec::decompress(&codec::compress(&huge), 1 << 16).is_err());
+    Ok(())
+}
+
+/// Snapshots too large for a frame go out as range syncs that fit, and
+/// together carry the whole document.
+#[tokio::test]
+pub async fn frame_split_test() -> Result<(), Box<dyn std::error::Error>> {
+    let limits = LimitsConfig {
+        max_frame_bytes: 4096,
+        ..LimitsConfig::default()
+    };
+    let (a, b) = run_handshake(handshake_config(1, None), handshake_config(2, None)).await;
+    let mut encoder = PeerSyncOpCodec::new(a?.channel.encryptor).with_limits(limits.clone());
+    let mut decoder = PeerSyncOpCodec::new(b?.channel.decryptor).with_limits(limits);
+    let doc = typed_doc(1_000, 1);
+    let frames = encoder.fit(PeerSyncOp::FullSync { state: doc.clone() }.into());
+    assert!(frames.len() > 1);
+
+    let mut merged = Doc::new();
+    for frame in frames {
+        let mut wire = BytesMut::new();
+        encoder.encode(frame, &mut wire)?;
+        match decoder.decode(&mut wire)? {
+            Some(Frame::Op(PeerSyncOp::RangeSync { state, reply, .. })) => {
+                assert!(!reply);
+                merged.merge_state(state);
+            }
+            _ => panic!("expected RangeSync"),
+        }
+    }
+    assert_eq!(merged.collect_ascii(), doc.collect_ascii());
+    assert_eq!(merged.digest(), doc.digest());
+
+    let small = PeerSyncOp::FullSync {
+        state: typed_doc(10, 1),
+    };
+    assert!(matches!(
+        encoder.fit(small.into())[..],
+        [Frame::Op(PeerSyncOp::FullSync { .. })]
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+pub async fn peer_ban_test() -> Result<(), Box<dyn std::error::Error>> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let token = CancellationToken::new();
+    let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut link = link_config(2, 1_000, 5_000);
+        link.limits.max_identifier_depth = 4;
+        transport::handle_connection(stream, false, tx, token, link).await;
+    });
+    let mut stream = tokio::net::TcpStream::connect(addr).await?;
+    let established = handshake::perform(&mut stream, &handshake_config(1, None)).await?;
+    let compression = established.features.contains(Features::COMPRESSION);
+    let codec = PeerSyncOpCodec::new(established.channel.encryptor).with_compression(compression);
+    let mut framed = Framed::new(stream, codec);
+    let char_id = (0..5).map(|time| NodeKey::new(1, 1, time)).collect();
+    let insert = PeerSyncOp::Insert {
+        op_id: OpId { origin: 1, seq: 1 },
+        char_id,
+        value: b'x',
+    };
+    framed.send(insert.into()).await?;
+
+    let mut misbehaved = None;
+    loop {
+        match tokio::time::timeout(Duration::from_secs(5), rx.recv()).await? {
+            Some(NodeEvent::Net(PeerEvent::Misbehaved { id, addr })) => {
+                misbehaved = Some((id, addr))
+            }
+            Some(NodeEvent::Net(PeerEvent::Disconnected { .. })) => break,
+            Some(NodeEvent::Sync { .. }) => panic!("bad op was forwarded"),
+            _ => {}
+        }
+    }
+    server.await?;
+    let (id, remote) = misbehaved.expect("no Misbehaved before Disconnected");
+    assert_eq!(id, 1);
+
+    let mut manager = PeerManager::new(1);
+    manager.ban(remote, Duration::from_millis(200));
+    let other_port = SocketAddr::new(remote.ip(), 9);
+    assert!(manager.banned_for(other_port).is_some());
+    assert!(!manager.on_discovered(2, other_port));
+    assert!(!manager.on_dial_request(other_port, true));
+    assert!(manager.banned_for("10.0.0.1:9".parse()?).is_none());
+
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    assert!(manager.banned_for(other_port).is_none());
+    assert!(manager.on_dial_request(other_port, true));
+    Ok(())
+}
+
+#[test]
+pub fn wire_format_test() -> Result<(), Box<dyn std::error::Error>> {
+    let mut doc = Doc::new();
+    for (pos, c) in b"hello".iter().enumerate() {
+        doc.insert_absolute(1, pos, *c)?;
+    }
+    doc.remove_absolute(1)?;
+    let frame = Frame::Op(PeerSyncOp::FullSync { state: doc });
+    let bytes = wire::LinkFrame::from(&frame).encode_to_vec();
+    match Frame::try_from(wire::LinkFrame::decode(bytes.as_slice())?)? {
+        Frame::Op(PeerSyncOp::FullSync { state }) => {
+            assert_eq!(state.collect_ascii(), b"ello");
+            assert_eq!(state.entry_counts(), (6, 1));
+        }
+        _ => panic!("expected FullSync"),
+    }
+
+    // What another implementation would build from peer.proto.
+    let key = |digit, peer_id| wire::NodeKey {
+        digit,
+        peer_id,
+        time: 7,
+    };
+    let insert = |peer_id, shared| wire::LinkFrame {
+        variant: Some(wire::link_frame::Variant::Op(wire::SyncOp {
+            variant: Some(wire::sync_op::Variant::Insert(wire::Insert {
+                op_id: Some(wire::OpId { origin: 3, seq: 1 }),
+                char_id: Some(wire::Identifier {
+                    keys: vec![key(42, peer_id)],
+                    shared,
+                }),
+                value: b'x' as u32,
+            })),
+        })),
+    };
+    match Frame::try_from(insert(3, 0))? {
+        Frame::Op(PeerSyncOp::Insert {
+            op_id,
+            char_id,
+            value,
+        }) => {
+            assert_eq!(op_id, OpId { origin: 3, seq: 1 });
+            assert_eq!(char_id, vec![NodeKey::new(42, 3, 7)]);
+            assert_eq!(value, b'x');
+        }
+        _ => panic!("expected Insert"),
+    }
+    assert!(Frame::try_from(insert(256, 0)).is_err());
+    assert!(Frame::try_from(insert(3, 1)).is_err());
+    assert!(Frame::try_from(wire::LinkFrame { variant: None }).is_err());
+
+    let entry = |digit, shared| wire::Entry {
+        id: Some(wire::Identifier {
+            keys: vec![key(digit, 1)],
+            shared,
+        }),
+        value: b'a' as u32,
+    };
+    let snapshot = |entries| wire::Snapshot {
+        entries,
+        tombstones: vec![],
+    };
+    let doc = Doc::try_from(snapshot(vec![entry(1, 0), entry(2, 1)]))?;
+    let ids: Vec<_> = doc.entries().map(|(id, _)| id.to_vec()).collect();
+    assert_eq!(ids[1], [NodeKey::new(1, 1, 7), NodeKey::new(2, 1, 7)]);
+    assert!(Doc::try_from(snapshot(vec![entry(2, 0), entry(1, 0)])).is_err());
+    assert!(Doc::try_from(snapshot(vec![entry(1, 0), entry(2, 2)])).is_err());
+
+    let beacon = PeerBeacon {
+        id: 4,
+        tcp_port: 1234,
+        room: "design review".to_string(),
+    };
+    assert_eq!(
+        codec::decode_beacon(&codec::encode_beacon(&beacon))?,
+        beacon
+    );
+    Ok(())
+}
+
+#[test]
+pub fn sentinel_id_test() -> Result<(), Box<dyn std::error::Error>> {
+    let doc = Doc::new();
+    let (bos, eos) = (doc.bos_id().to_vec(), doc.eos_id().to_vec());
+    let past_eos = [eos.clone(), vec![NodeKey::new(1, 1, 1)]].concat();
+    let decoded = |op: &PeerSyncOp| PeerSyncOp::try_from(wire::SyncOp::from(op));
+
+    // Edits of the sentinels, or of anything sorting outside them.
+    for char_id in [bos.clone(), eos.clone(), past_eos.clone()] {
+        let op_id = OpId { origin: 2, seq: 1 };
+        let remove = PeerSyncOp::Remove {
+            op_id,
+            char_id: char_id.clone(),
+        };
+        let err = decoded(&remove).expect_err("remove accepted");
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        let insert = PeerSyncOp::Insert {
+            op_id,
+            char_id,
+            value: b'x',
+        };
+        assert!(decoded(&insert).is_err());
+
+        // Ops that don't come off the wire get the same treatment.
+        let mut session = Session::new(1);
+        assert!(session.apply_peer_sync_op(2, remove).is_none());
+        assert!(session.apply_peer_sync_op(2, insert).is_none());
+        assert_eq!(session.validate_doc(false).issues, Vec::<String>::new());
+        assert!(session.get_doc_ascii().is_empty());
+    }
+
+    // Snapshots tombstoning a sentinel, or holding entries past EOS.
+    for tombstone in [bos, eos] {
+        let mut doc = Doc::new();
+        doc.insert_absolute(1, 0, b'a')?;
+        doc.insert_cmentary(tombstone.into());
+        let err = Doc::try_from(wire::Snapshot::from(&doc)).expect_err("snapshot accepted");
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+    let mut doc = Doc::new();
+    doc.insert_id(past_eos.into(), b'x')?;
+    assert!(Doc::try_from(wire::Snapshot::from(&doc)).is_err());
+
+    let mut doc = Doc::new();
+    doc.insert_absolute(1, 0, b'a')?;
+    let decoded = Doc::try_from(wire::Snapshot::from(&doc))?;
+    assert_eq!(decoded.collect_ascii(), b"a");
+    Ok(())
+}
+
+/// A document written the way people type: bursts of characters at one
+/// cursor, with the cursor jumping around now and then and some backspacing,
+/// by a few peers taking turns.
+fn typed_doc(len: usize, seed: u64) -> Doc {
+    let text = b"The quick brown fox jumps over the lazy dog. ";
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut doc = Doc::new();
+    let (mut typed, mut visible) = (0, 0);
+    while visible < len {
+        let peer_id = rng.random_range(1..=3);
+        let mut cursor = rng.random_range(0..=visible);
+        for _ in 0..rng.random_range(1..60) {
+            doc.insert_absolute(peer_id, cursor, text[typed % text.len()])
+                .unwrap();
+            cursor += 1;
+            typed += 1;
+            visible += 1;
+        }
+        if rng.random_bool(0.3) {
+            for _ in 0..rng.random_range(1..8).min(cursor) {
+                doc.remove_absolute(cursor).unwrap();
+                cursor -= 1;
+                visible -= 1;
+            }
+        }
+    }
+    doc
+}
+
+/// Snapshot sizes on typed documents. For 10k characters this comes to about
+/// 1.1 MB with the old bincode dump, 236 kB as protobuf with shared prefixes
+/// (1.2 MB without them) and 114 kB once compressed. Run with `--nocapture`
+/// to see the numbers.
+#[test]
+pub fn snapshot_size_test() -> Result<(), Box<dyn std::error::Error>> {
+    for len in [1_000, 10_000] {
+        let doc = typed_doc(len, len as u64);
+        let bincode = doc.save_bytes()?.len();
+        let protobuf = wire::Snapshot::from(&doc).encode_to_vec();
+        let compressed = codec::compress(&protobuf);
+        println!(
+            "{:>6} chars, {:>6} tombstones: bincode {:>8} B, protobuf {:>7} B, lz4 {:>7} B",
+            len,
+            doc.entry_counts().1,
+            bincode,
+            protobuf.len(),
+            compressed.len()
+        );
+        assert!(protobuf.len() * 4 < bincode);
+        assert!(compressed.len() * 2 < protobuf.len());
+
+        let decoded = Doc::try_from(wire::Snapshot::decode(
+            codec::decompress(&compressed, usize::MAX)?.as_ref(),
+        )?)?;
+        assert_eq!(decoded.collect_ascii(), doc.collect_ascii());
+        assert_eq!(decoded.digest(), doc.digest());
+    }
+    Ok(())
+}
+
+#[tokio::test]
+pub async fn encrypted_loopback_test() -> Result<(), Box<dyn std::error::Error>> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let (a, b) = (
+        handshake_config(1, Some("hunter2")),
+        handshake_config(2, Some("hunter2")),
+    );
+
+    let server = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        let established = handshake::perform(&mut stream, &b).await?;
+        let mut framed = Framed::new(stream, PeerSyncOpCodec::new(established.channel.decryptor));
+        framed.next().await.unwrap()
+    });
+    let mut stream = tokio::net::TcpStream::connect(addr).await?;
+    let established = handshake::perform(&mut stream, &a).await?;
+    let mut framed = Framed::new(stream, PeerSyncOpCodec::new(established.channel.encryptor));
+    framed
+        .send(
+            PeerSyncOp::Insert {
+                op_id: OpId { origin: 1, seq: 1 },
+                char_id: vec![NodeKey::new(42, 1, 1)],
+                value: b'x',
+            }
+            .into(),
+        )
+        .await?;
+
+    match server.await?? {
+        Frame::Op(PeerSyncOp::Insert { value, .. }) => assert_eq!(value, b'x'),
+        _ => panic!("expected Insert"),
+    }
+    Ok(())
+}
+
+#[test]
+pub fn backoff_delay_test() {
+    let mut previous_cap = Duration::ZERO;
+    for attempt in 0..20 {
+        let cap = Duration::from_millis(500)
+            .saturating_mul(1 << attempt.min(16))
+            .min(Duration::from_secs(30));
+        let delay = peers::backoff_delay(attempt);
+        assert!(
+            delay >= cap / 2 && delay <= cap,
+            "attempt {}: {:?}",
+            attempt,
+            delay
+        );
+        assert!(cap >= previous_cap);
+        previous_cap = cap;
+    }
+}
+
+#[test]
+pub fn peer_manager_reconnect_test() {
+    let addr: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+    let outbox = Outbox::default();
+    let mut manager = PeerManager::new(1);
+
+    assert!(!PeerManager::new(3).on_discovered(2, addr));
+    assert!(manager.on_discovered(2, addr));
+    assert!(!manager.on_discovered(2, addr));
+
+    let status = manager
+        .on_connected(
+            2,
+            addr,
+            true,
+            "fp".to_string(),
+            outbox.clone(),
+            CancellationToken::new(),
+        )
+        .unwrap();
+    assert_eq!(status.state, peer_status::State::Connected as i32);
+    assert_eq!(manager.links().count(), 1);
+
+    let (status, standby) = manager.on_disconnected(2, addr).unwrap();
+    assert_eq!(status.state, peer_status::State::Disconnected as i32);
+    assert_eq!(status.address, "127.0.0.1:7000");
+    assert!(standby.is_empty());
+    assert!(manager.on_disconnected(2, addr).is_none());
+
+    // A dropped link retries immediately-ish; each failure backs off further.
+    let (redial, status) = manager.on_dial_ended(addr).unwrap();
+    assert!(matches!(redial, Redial::After(d) if d <= Duration::from_millis(500)));
+    assert_eq!(status.state, peer_status::State::Reconnecting as i32);
+    assert_eq!(status.attempt, 1);
+    assert!(!manager.on_discovered(2, addr));
+    for attempt in 1..=10 {
+        assert!(manager.on_redial(addr));
+        assert!(!manager.on_redial(addr));
+        let (redial, status) = manager.on_dial_ended(addr).unwrap();
+        if attempt < 10 {
+            assert!(matches!(redial, Redial::After(_)));
+            assert_eq!(status.attempt, attempt + 1);
+        } else {
+            assert!(matches!(redial, Redial::GiveUp));
+            assert_eq!(status.state, peer_status::State::Disconnected as i32);
+        }
+    }
+    assert!(manager.on_dial_ended(addr).is_none());
+
+    // Discovery starts the cycle over once we've given up.
+    assert!(manager.on_discovered(2, addr));
+    manager.on_connected(
+        2,
+        addr,
+        true,
+        "fp".to_string(),
+        outbox,
+        CancellationToken::new(),
+    );
+    let (_, status) = manager.on_dial_ended(addr).unwrap();
+    assert_eq!(status.attempt, 1);
+}
+
+#[test]
+pub fn peer_manager_static_peer_test() {
+    let addr: SocketAddr = "10.0.0.2:2137".parse().unwrap();
+    let inbound: SocketAddr = "10.0.0.2:51234".parse().unwrap();
+    let outbox = Outbox::default();
+    let mut manager = PeerManager::new(5);
+
+    // Static peers are dialed whatever their ID, and never given up on.
+    assert!(manager.on_dial_request(addr, true));
+    assert!(!manager.on_dial_request(addr, false));
+    for _ in 0..20 {
+        let (redial, _) = manager.on_dial_ended(addr).unwrap();
+        assert!(matches!(redial, Redial::After(d) if d <= Duration::from_secs(30)));
+        assert!(manager.on_redial(addr));
+    }
+
+    // Both sides dialed: peer 2 < 5, so the link peer 2 opened survives.
+    let outbound_closer = CancellationToken::new();
+    let inbound_closer = CancellationToken::new();
+    assert!(
+        manager
+            .on_connected(
+                2,
+                addr,
+                true,
+                "fp".to_string(),
+                outbox.clone(),
+                outbound_closer.clone()
+            )
+            .is_some()
+    );
+    assert!(
+        manager
+            .on_connected(
+                2,
+                inbound,
+                false,
+                "fp".to_string(),
+                outbox,
+                inbound_closer.clone()
+            )
+            .is_none()
+    );
+    assert!(outbound_closer.is_cancelled());
+    assert!(!inbound_closer.is_cancelled());
+    // The closed link going down is no disconnect of peer 2.
+    assert!(!manager.is_linked(2, addr));
+    assert!(manager.on_disconnected(2, addr).is_none());
+    assert_eq!(manager.links().count(), 1);
+
+    // Claiming a linked peer's ID with another key gets nowhere.
+    let impostor: SocketAddr = "10.0.0.9:40000".parse().unwrap();
+    let impostor_closer = CancellationToken::new();
+    assert!(
+        manager
+            .on_connected(
+                2,
+                impostor,
+                false,
+                "other".to_string(),
+                Outbox::default(),
+                impostor_closer.clone()
+            )
+            .is_none()
+    );
+    assert!(impostor_closer.is_cancelled());
+    assert!(manager.is_linked(2, inbound) && !manager.is_linked(2, impostor));
+    assert_eq!(
+        manager.linked_fingerprints().collect::<Vec<_>>(),
+        [(2, "fp")]
+    );
+
+    // The closed dial waits on standby until the surviving link drops.
+    assert!(manager.on_dial_ended(addr).is_none());
+    assert!(!manager.on_dial_request(addr, true));
+    let (_, standby) = manager.on_disconnected(2, inbound).unwrap();
+    assert_eq!(standby, vec![addr]);
+    assert!(manager.on_redial(addr));
+}
+
+#[tokio::test]
+pub async fn room_directory_test() {
+    let mut rooms = RoomDirectory::new("design".to_string(), Duration::from_millis(50));
+    let list = rooms.list();
+    assert_eq!(list.len(), 1);
+    assert_eq!(
+        (list[0].name.as_str(), list[0].peers, list[0].joined),
+        ("design", 0, true)
+    );
+
+    let addr = |port| SocketAddr::from(([10, 0, 0, 1], port));
+    assert!(rooms.on_beacon("design".to_string(), addr(1)));
+    assert!(!rooms.on_beacon("ops".to_string(), addr(2)));
+    assert!(!rooms.on_beacon("ops".to_string(), addr(3)));
+    assert!(!rooms.on_beacon("ops".to_string(), addr(3)));
+    let list: Vec<_> = rooms
+        .list()
+        .into_iter()
+        .map(|room| (room.name, room.peers, room.joined))
+        .collect();
+    assert_eq!(
+        list,
+        [
+            ("design".to_string(), 1, true),
+            ("ops".to_string(), 2, false)
+        ]
+    );
+
+    // Three missed beacons and a node is forgotten.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let list = rooms.list();
+    assert_eq!(list.len(), 1);
+    assert_eq!((list[0].peers, list[0].joined), (0, true));
+
+    // Made-up rooms and nodes can't grow the directory without end, nor
+    // keep our own room out of it.
+    assert!(!rooms.on_beacon("x".repeat(handshake::MAX_ROOM_LEN + 1), addr(1)));
+    for room in 0..MAX_ROOMS + 10 {
+        assert!(!rooms.on_beacon(format!("spam-{}", room), addr(1)));
+    }
+    for port in 0..MAX_ROOM_NODES + 10 {
+        rooms.on_beacon("spam-0".to_string(), addr(port as u16));
+    }
+    assert!(rooms.on_beacon("design".to_string(), addr(1)));
+    let list = rooms.list();
+    assert_eq!(list.len(), MAX_ROOMS + 1);
+    assert!(
+        list.iter()
+            .all(|room| room.name.len() <= handshake::MAX_ROOM_LEN)
+    );
+    assert!(
+        list.iter()
+            .all(|room| room.peers as usize <= MAX_ROOM_NODES)
+    );
+    assert!(list.iter().any(|room| room.joined && room.peers == 1));
+
+    // Expired rooms make way for new ones without the list being asked for.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(!rooms.on_beacon("late".to_string(), addr(2)));
+    let list = rooms.list();
+    assert_eq!(list.len(), 2);
+    assert_eq!((list[0].name.as_str(), list[0].peers), ("design", 0));
+    assert_eq!((list[1].name.as_str(), list[1].peers), ("late", 1));
+}
+
+#[test]
+pub fn config_defaults_test() {
+    let config: NodeConfig =
+        toml::from_str("peer_id = 1\ntcp_port = 2137\nudp_discovery_port = 9000\n").unwrap();
+    assert!(config.static_peers.is_empty());
+    assert_eq!(config.discovery, DiscoveryConfig::default());
+    assert_eq!(config.relay, RelayConfig::default());
+    assert_eq!(config.limits, LimitsConfig::default());
+    assert_eq!(config.roles, RolesConfig::default());
+    assert_eq!(config.room, "default");
+    assert!(!config.relay.enabled);
+
+    let config: NodeConfig = toml::from_str(
+        "peer_id = 1\ntcp_port = 2137\nudp_discovery_port = 9000\n\
+         static_peers = [\"10.0.0.2:2137\"]\n[discovery]\nenabled = false\n",
+    )
+    .unwrap();
+    assert_eq!(config.static_peers, ["10.0.0.2:2137"]);
+    assert!(!config.discovery.enabled);
+    assert_eq!(config.discovery.interval_ms, 1000);
+    assert_eq!(config.log_level, LogLevel::Info);
+}
+
+#[test]
+pub fn config_validation_test() {
+    let mut config = memory_config(1, 2137, &[]);
+    assert!(config.validate().is_ok());
+
+    config.peer_id = 0;
+    config.tcp_port = 0;
+    config.heartbeat.timeout_ms = config.heartbeat.interval_ms;
+    config.static_peers = vec!["10.0.0.2".to_string(), "relay.example.com:2137".to_string()];
+    config.trusted_fingerprints = vec!["3F1A:09BC".to_string()];
+    config.websocket.enabled = true;
+    config.websocket.port = 0;
+    let Err(config::ConfigError::Invalid(problems)) = config.validate() else {
+        panic!("invalid config accepted");
+    };
+    let keys: Vec<_> = problems
+        .iter()
+        .map(|problem| problem.split(':').next().unwrap())
+        .collect();
+    assert_eq!(
+        keys,
+        [
+            "peer_id",
+            "tcp_port",
+            "websocket.port",
+            "heartbeat.timeout_ms",
+            "static_peers",
+            "trusted_fingerprints"
+        ]
+    );
+}
+
+/// Old or hand-written files only need what differs from the defaults;
+/// what can't have a default is generated once and kept.
+#[test]
+pub fn config_load_defaults_test() {
+    let dir = memory_data_dir(10_052);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("config.toml");
+    let written = "# ours\nroom = \"team\"\nfuture_setting = 1\n[heartbeat]\nspeed = 2\n";
+    std::fs::write(&path, written).unwrap();
+
+    let config = config::load(&path).unwrap();
+    assert_eq!(config.room, "team");
+    assert_eq!(config.tcp_port, 2137);
+    assert_eq!(config.udp_discovery_port, 9000);
+    assert_ne!(config.peer_id, 0);
+    assert!(config.validate().is_ok());
+    let again = config::load(&path).unwrap();
+    assert_eq!(again.peer_id, config.peer_id);
+    assert_eq!(again.static_key, config.static_key);
+
+    // Only the generated keys are added, and only the owner can read them.
+    let content = std::fs::read_to_string(&path).unwrap();
+    assert!(content.ends_with(written), "{}", content);
+    assert_eq!(content.lines().count(), written.lines().count() + 2);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        std::fs::remove_file(&path).unwrap();
+        config::load_or_create(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    std::fs::write(&path, "tcp_port = \"2137\"\n").unwrap();
+    let err = config::load(&path).unwrap_err().to_string();
+    assert!(err.contains("tcp_port"), "{}", err);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+pub fn config_changes_test() {
+    let old = memory_config(1, 2137, &[2138]);
+    assert_eq!(config::changes(&old, &old), config::Changes::default());
+
+    let mut new = old.clone();
+    new.log_level = LogLevel::Debug;
+    new.relay.save_interval_ms = 1_000;
+    new.tcp_port = 2237;
+    new.static_peers = vec!["127.0.0.1:2139".to_string()];
+    let changes = config::changes(&old, &new);
+    assert_eq!(
+        changes.live,
+        ["log_level", "static_peers", "relay.save_interval_ms"]
+    );
+    assert_eq!(changes.restart, ["tcp_port", "static_peers"]);
+}
+
+fn cli_args(args: &[&str], env: &[(&str, &str)]) -> Result<cli::Args, String> {
+    let env: HashMap<String, String> = env
+        .iter()
+        .map(|(var, value)| (var.to_string(), value.to_string()))
+        .collect();
+    cli::Args::parse(args.iter().map(|arg| arg.to_string()), |var| {
+        env.get(var).cloned()
+    })
+}
+
+#[test]
+pub fn cli_args_test() {
+    let args = cli_args(&[], &[]).unwrap();
+    assert_eq!(args.mode, cli::Mode::Run);
+    assert_eq!(args.overrides, cli::Overrides::default());
+    assert_eq!(args.config_path(), Path::new("./native/config.toml"));
+
+    let args = cli_args(
+        &["--port", "4000", "--relay", "--print-config"],
+        &[
+            ("DTE_PORT", "3000"),
+            ("DTE_NAME", "alice"),
+            ("DTE_ROOM", ""),
+            ("DTE_LOG", "DEBUG"),
+            ("DTE_DATA_DIR", "/tmp/dte-a"),
+        ],
+    )
+    .unwrap();
+    assert_eq!(args.mode, cli::Mode::PrintConfig);
+    assert_eq!(args.overrides.tcp_port, Some(4000));
+    assert_eq!(args.overrides.display_name.as_deref(), Some("alice"));
+    assert_eq!(args.overrides.room, None);
+    assert_eq!(args.overrides.log_level, Some(LogLevel::Debug));
+    assert!(args.overrides.relay);
+    assert_eq!(args.config_path(), Path::new("/tmp/dte-a/config.toml"));
+
+    let args = cli_args(
+        &["--config", "b.toml", "--replay", "trace.bin"],
+        &[("DTE_CONFIG", "a.toml")],
+    )
+    .unwrap();
+    assert_eq!(args.config_path(), Path::new("b.toml"));
+    assert_eq!(args.mode, cli::Mode::Replay("trace.bin".into()));
+
+    assert!(cli_args(&["--port", "70000"], &[]).is_err());
+    assert!(cli_args(&["--port"], &[]).is_err());
+    assert!(cli_args(&["--log-level", "loud"], &[]).is_err());
+    assert!(cli_args(&["--bogus"], &[]).is_err());
+    assert_eq!(cli_args(&["--help"], &[]).unwrap().mode, cli::Mode::Help);
+    let err = cli_args(&[], &[("DTE_PEER_ID", "x")]).unwrap_err();
+    assert!(err.starts_with("DTE_PEER_ID"), "{}", err);
+}
+
+#[test]
+pub fn cli_load_test() {
+    let dir = memory_data_dir(10_051);
+    let _ = std::fs::remove_dir_all(&dir);
+
+    // Printing the config creates nothing, nor adds to a file.
+    let print = cli_args(
+        &["--data-dir", dir.to_str().unwrap(), "--print-config"],
+        &[],
+    )
+    .unwrap();
+    assert!(print.load().unwrap().static_key.is_some());
+    assert!(!dir.exists());
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("config.toml"), "room = \"file\"\n").unwrap();
+    assert_eq!(print.load().unwrap().room, "file");
+    assert_eq!(
+        std::fs::read_to_string(dir.join("config.toml")).unwrap(),
+        "room = \"file\"\n"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // Creates the data directory and a config in it, then resolves paths
+    // against it.
+    let args = cli_args(&["--data-dir", dir.to_str().unwrap()], &[]).unwrap();
+    let created = args.load().unwrap();
+    assert!(dir.join("config.toml").exists());
+    assert_eq!(created.data_dir.as_deref(), Some(dir.as_path()));
+    assert_eq!(created.ipc.socket_path, Some(dir.join("dte.sock")));
+    assert_eq!(created.trace.path, Some(dir.join("trace.bin")));
+
+    let mut file = created.clone();
+    file.data_dir = None;
+    file.ipc.socket_path = None;
+    file.trace.path = None;
+    file.tcp_port = 2137;
+    file.room = "file".to_string();
+    file.log_level = LogLevel::Warn;
+    std::fs::write(
+        dir.join("config.toml"),
+        toml::to_string_pretty(&file).unwrap(),
+    )
+    .unwrap();
+
+    // Flags beat variables, which beat the file.
+    let args = cli_args(
+        &[
+            "--config",
+            dir.join("config.toml").to_str().unwrap(),
+            "--port",
+            "4000",
+        ],
+        &[
+            ("DTE_PORT", "3000"),
+            ("DTE_ROOM", "env"),
+            ("DTE_PEER_ID", "7"),
+        ],
+    )
+    .unwrap();
+    let config = args.load().unwrap();
+    assert_eq!(config.tcp_port, 4000);
+    assert_eq!(config.room, "env");
+    assert_eq!(config.peer_id, 7);
+    assert_eq!(config.log_level, LogLevel::Warn);
+    assert_eq!(config.static_key, created.static_key);
+    assert_eq!(config.data_dir.as_deref(), Some(dir.as_path()));
+
+    let printed = cli::print_config(&config).unwrap();
+    assert!(printed.contains("tcp_port = 4000"), "{}", printed);
+    assert!(!printed.contains(created.static_key.as_deref().unwrap()));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+fn link_config(my_id: PeerId, interval_ms: u64, timeout_ms: u64) -> LinkConfig {
+    LinkConfig {
+        handshake: handshake_config(my_id, None),
+        heartbeat: HeartbeatConfig {
+            interval_ms,
+            timeout_ms,
+        },
+        limits: LimitsConfig::default(),
+        transport: Arc::new(transport::TcpTransport::new(0)),
+        trace: Trace::default(),
+    }
+}
+
+struct MemoryNode {
+    commands: FramedWrite<tokio::io::DuplexStream, LengthDelimitedCodec>,
+    events: FramedRead<tokio::io::DuplexStream, LengthDelimitedCodec>,
+    data_dir: std::path::PathBuf,
+    handle: tokio::task::JoinHandle<Result<(), ()>>,
+}
+
+/// Starts a full node on `network` at 127.0.0.1:`port`, dialing
+/// `static_peers`, with a frontend the test drives.
+fn memory_node(
+    network: &MemoryNetwork,
+    peer_id: PeerId,
+    port: u16,
+    static_peers: &[u16],
+) -> MemoryNode {
+    start_memory_node(network, memory_config(peer_id, port, static_peers), None)
+}
+
+fn memory_data_dir(port: u16) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("dte-{}-{}", std::process::id(), port))
+}
+
+fn memory_config(peer_id: PeerId, port: u16, static_peers: &[u16]) -> NodeConfig {
+    NodeConfig {
+        peer_id,
+        display_name: Some(format!("node {}", peer_id)),
+        room: "default".to_string(),
+        tcp_port: port,
+        udp_discovery_port: 0,
+        data_dir: None,
+        log_level: LogLevel::default(),
+        workspace_secret: None,
+        static_key: Some(StaticKey::generate().to_hex()),
+        trusted_fingerprints: Vec::new(),
+        static_peers: static_peers
+            .iter()
+            .map(|port| format!("127.0.0.1:{}", port))
+            .collect(),
+        discovery: DiscoveryConfig {
+            enabled: false,
+            ..DiscoveryConfig::default()
+        },
+        heartbeat: HeartbeatConfig::default(),
+        anti_entropy_interval_ms: 10_000,
+        relay: RelayConfig::default(),
+        websocket: Default::default(),
+        limits: LimitsConfig::default(),
+        roles: RolesConfig::default(),
+        ipc: IpcConfig::default(),
+        trace: TraceConfig::default(),
+    }
+}
+
+fn start_memory_node(
+    network: &MemoryNetwork,
+    config: NodeConfig,
+    config_source: Option<cli::Args>,
+) -> MemoryNode {
+    let port = config.tcp_port;
+    let data_dir = memory_data_dir(port);
+    std::fs::create_dir_all(&data_dir).unwrap();
+    let (commands, node_commands) = tokio::io::duplex(64 * 1024);
+    let (node_events, events) = tokio::io::duplex(64 * 1024);
+    let runtime = service::Runtime {
+        transport: Arc::new(network.transport(SocketAddr::from(([127, 0, 0, 1], port)))),
+        frontend: Some(service::Frontend {
+            commands: Box::new(node_commands),
+            events: Box::new(node_events),
+        }),
+        data_dir: data_dir.clone(),
+        config_source,
+    };
+    MemoryNode {
+        commands: FramedWrite::new(commands, LengthDelimitedCodec::new()),
+        events: FramedRead::new(events, LengthDelimitedCodec::new()),
+        data_dir,
+        handle: tokio::spawn(service::run(config, runtime)),
+    }
+}
+
+/// Reads the node's `ServerEvent`s until one matches.
+async fn wait_for_event(
+    node: &mut MemoryNode,
+    matches: impl Fn(&server_event::Variant) -> bool,
+) -> server_event::Variant {
+    let wait = async {
+        loop {
+            let bytes = node.events.next().await.unwrap().unwrap();
+            let event = ServerEvent::decode(bytes).unwrap().variant.unwrap();
+            if matches(&event) {
+                return event;
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), wait)
+        .await
+        .expect("timed out waiting for a server event")
+}
+
+/// Three whole nodes in a line, A - B - C, linked in memory: an edit typed
+/// at A shows up at C's frontend through B.
+#[tokio::test]
+pub async fn memory_network_test() {
+    let network = MemoryNetwork::new();
+    let mut a = memory_node(&network, 1, 10_001, &[]);
+    let b = memory_node(&network, 2, 10_002, &[10_001]);
+    let mut c = memory_node(&network, 3, 10_003, &[10_002]);
+
+    let connected = |event: &server_event::Variant| {
+        matches!(event, server_event::Variant::Status(status)
+            if status.state == peer_status::State::Connected as i32)
+    };
+    wait_for_event(&mut a, connected).await;
+    wait_for_event(&mut c, connected).await;
+
+    let edit = ClientCommand {
+        variant: Some(client_command::Variant::Edit(LocalOp {
+            position: 0,
+            remote: false,
+            op_type: Some(local_op::OpType::Insert(LocalInsert { value: b'x' as u32 })),
+        })),
+    };
+    a.commands.send(edit.encode_to_vec().into()).await.unwrap();
+
+    let event = wait_for_event(&mut c, |event| {
+        matches!(event, server_event::Variant::Op(_))
+    })
+    .await;
+    let server_event::Variant::Op(op) = event else {
+        unreachable!()
+    };
+    assert!(op.remote);
+    assert_eq!(op.position, 0);
+    assert_eq!(
+        op.op_type,
+        Some(local_op::OpType::Insert(LocalInsert { value: b'x' as u32 }))
+    );
+
+    let close = ClientCommand {
+        variant: Some(client_command::Variant::Close(CloseApplication {})),
+    };
+    for node in [a, b, c] {
+        let mut commands = node.commands;
+        commands.send(close.encode_to_vec().into()).await.unwrap();
+        assert_eq!(node.handle.await.unwrap(), Ok(()));
+        assert!(node.data_dir.join("doc.bin").exists());
+        std::fs::remove_dir_all(&node.data_dir).unwrap();
+    }
+}
+
+/// Joins and departures reach the frontend with the peer's display name, and
+/// ListPeers answers with whoever is linked right now.
+#[tokio::test]
+pub async fn peer_roster_test() {
+    let network = MemoryNetwork::new();
+    let mut a = memory_node(&network, 1, 10_011, &[]);
+    let b = memory_node(&network, 2, 10_012, &[10_011]);
+
+    let event = wait_for_event(&mut a, |event| {
+        matches!(event, server_event::Variant::Joined(_))
+    })
+    .await;
+    let server_event::Variant::Joined(joined) = event else {
+        unreachable!()
+    };
+    let peer = joined.peer.unwrap();
+    assert_eq!((peer.peer_id, peer.name.as_str()), (2, "node 2"));
+
+    let list = ClientCommand {
+        variant: Some(client_command::Variant::ListPeers(ListPeers {})),
+    };
+    a.commands.send(list.encode_to_vec().into()).await.unwrap();
+    let event = wait_for_event(&mut a, |event| {
+        matches!(event, server_event::Variant::Roster(_))
+    })
+    .await;
+    let server_event::Variant::Roster(roster) = event else {
+        unreachable!()
+    };
+    assert_eq!(roster.peers.len(), 1);
+    assert_eq!(roster.peers[0].name, "node 2");
+
+    let close = ClientCommand {
+        variant: Some(client_command::Variant::Close(CloseApplication {})),
+    };
+    let mut commands = b.commands;
+    commands.send(close.encode_to_vec().into()).await.unwrap();
+    assert_eq!(b.handle.await.unwrap(), Ok(()));
+    let event = wait_for_event(&mut a, |event| {
+        matches!(event, server_event::Variant::Left(_))
+    })
+    .await;
+    let server_event::Variant::Left(left) = event else {
+        unreachable!()
+    };
+    assert_eq!(left.peer.unwrap().name, "node 2");
+
+    a.commands.send(close.encode_to_vec().into()).await.unwrap();
+    assert_eq!(a.handle.await.unwrap(), Ok(()));
+    for dir in [a.data_dir, b.data_dir] {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+async fn next_ipc_event(
+    client: &mut Framed<tokio::net::UnixStream, LengthDelimitedCodec>,
+) -> server_event::Variant {
+    let bytes = tokio::time::timeout(Duration::from_secs(10), client.next())
+        .await
+        .expect("timed out waiting for a server event")
+        .unwrap()
+        .unwrap();
+    ServerEvent::decode(bytes).unwrap().variant.unwrap()
+}
+
+/// Clients attached over the IPC socket get the document first, then every
+/// event, whoever asked for it. Only the frontend can close the node.
+#[tokio::test]
+pub async fn ipc_socket_test() {
+    let network = MemoryNetwork::new();
+    let mut config = memory_config(1, 10_021, &[]);
+    let socket_path = memory_data_dir(10_021).join("dte.sock");
+    config.ipc = IpcConfig {
+        enabled: true,
+        socket_path: Some(socket_path.clone()),
+    };
+    let mut node = start_memory_node(&network, config, None);
+
+    let mut clients = Vec::new();
+    for _ in 0..2 {
+        let stream = loop {
+            match tokio::net::UnixStream::connect(&socket_path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+            }
+        };
+        let mut client = Framed::new(stream, LengthDelimitedCodec::new());
+        assert!(matches!(
+            next_ipc_event(&mut client).await,
+            server_event::Variant::State(_)
+        ));
+        assert!(matches!(
+            next_ipc_event(&mut client).await,
+            server_event::Variant::Role(_)
+        ));
+        clients.push(client);
+    }
+
+    let list = ClientCommand {
+        variant: Some(client_command::Variant::ListPeers(ListPeers {})),
+    };
+    clients[0].send(list.encode_to_vec().into()).await.unwrap();
+    for client in &mut clients {
+        assert!(matches!(
+            next_ipc_event(client).await,
+            server_event::Variant::Roster(_)
+        ));
+    }
+    wait_for_event(&mut node, |event| {
+        matches!(event, server_event::Variant::Roster(_))
+    })
+    .await;
+
+    let close = ClientCommand {
+        variant: Some(client_command::Variant::Close(CloseApplication {})),
+    };
+    // Clients closing only detach themselves, the frontend closes the node.
+    clients[1].send(close.encode_to_vec().into()).await.unwrap();
+    assert!(
+        tokio::time::timeout(Duration::from_secs(10), clients[1].next())
+            .await
+            .unwrap()
+            .is_none()
+    );
+    clients[0].send(list.encode_to_vec().into()).await.unwrap();
+    assert!(matches!(
+        next_ipc_event(&mut clients[0]).await,
+        server_event::Variant::Roster(_)
+    ));
+    assert!(!node.handle.is_finished());
+    node.commands
+        .send(close.encode_to_vec().into())
+        .await
+        .unwrap();
+    assert_eq!(node.handle.await.unwrap(), Ok(()));
+    std::fs::remove_dir_all(&node.data_dir).unwrap();
+}
+
+/// The IPC socket is private from the moment it appears, and whatever else
+/// is at its path is never removed.
+#[tokio::test]
+pub async fn ipc_socket_path_test() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = memory_data_dir(10_081);
+    std::fs::create_dir_all(&dir).unwrap();
+    let (tx, _rx) = tokio::sync::mpsc::channel(8);
+    let token = CancellationToken::new();
+    let document = dir.join("doc.bin");
+    std::fs::write(&document, "keep").unwrap();
+    assert!(
+        transport::ipc::run_ipc_listener(document.clone(), tx.clone(), token.clone())
+            .await
+            .is_err()
+    );
+    assert_eq!(std::fs::read_to_string(&document).unwrap(), "keep");
+
+    let path = dir.join("dte.sock");
+    let listener = tokio::spawn(transport::ipc::run_ipc_listener(
+        path.clone(),
+        tx,
+        token.clone(),
+    ));
+    while tokio::net::UnixStream::connect(&path).await.is_err() {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+
+    token.cancel();
+    listener.await.unwrap().unwrap();
+    assert!(!path.exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Scripted clients can send edits that don't fit the document; the node
+/// drops them and carries on.
+#[tokio::test]
+pub async fn invalid_edit_test() {
+    let mut doc = Doc::new();
+    assert!(doc.remove_absolute(0).is_err());
+    assert!(doc.remove_absolute(1).is_err());
+    assert_eq!(doc.entry_counts(), (2, 0));
+
+    let network = MemoryNetwork::new();
+    let mut node = memory_node(&network, 1, 10_031, &[]);
+    // Past the end, EOS itself, and edits or commands missing their body.
+    for (position, op_type) in [
+        (
+            7,
+            Some(local_op::OpType::Insert(LocalInsert { value: b'x' as u32 })),
+        ),
+        (7, Some(local_op::OpType::Remove(LocalRemove {}))),
+        (1, Some(local_op::OpType::Remove(LocalRemove {}))),
+        (0, None),
+    ] {
+        let edit = ClientCommand {
+            variant: Some(client_command::Variant::Edit(LocalOp {
+                position,
+                remote: false,
+                op_type,
+            })),
+        };
+        node.commands
+            .send(edit.encode_to_vec().into())
+            .await
+            .unwrap();
+    }
+    node.commands
+        .send(ClientCommand { variant: None }.encode_to_vec().into())
+        .await
+        .unwrap();
+    let list = ClientCommand {
+        variant: Some(client_command::Variant::ListPeers(ListPeers {})),
+    };
+    node.commands
+        .send(list.encode_to_vec().into())
+        .await
+        .unwrap();
+    let event = wait_for_event(&mut node, |event| {
+        matches!(
+            event,
+            server_event::Variant::Op(_) | server_event::Variant::Roster(_)
+        )
+    })
+    .await;
+    assert!(matches!(event, server_event::Variant::Roster(_)));
+
+    let close = ClientCommand {
+        variant: Some(client_command::Variant::Close(CloseApplication {})),
+    };
+    node.commands
+        .send(close.encode_to_vec().into())
+        .await
+        .unwrap();
+    assert_eq!(node.handle.await.unwrap(), Ok(()));
+    std::fs::remove_dir_all(&node.data_dir).unwrap();
+}
+
+/// A node's trace, replayed offline, ends with the document the node saved.
+#[tokio::test]
+pub async fn trace_replay_test() {
+    let network = MemoryNetwork::new();
+    let mut config = memory_config(1, 10_041, &[]);
+    let trace_path = memory_data_dir(10_041).join("trace.bin");
+    config.trace = TraceConfig {
+        enabled: true,
+        path: Some(trace_path.clone()),
+    };
+    let mut a = start_memory_node(&network, config, None);
+    let mut b = memory_node(&network, 2, 10_042, &[10_041]);
+
+    let connected = |event: &server_event::Variant| {
+        matches!(event, server_event::Variant::Status(status)
+            if status.state == peer_status::State::Connected as i32)
+    };
+    wait_for_event(&mut a, connected).await;
+    wait_for_event(&mut b, connected).await;
+
+    let insert = |position, value: u8| ClientCommand {
+        variant: Some(client_command::Variant::Edit(LocalOp {
+            position,
+            remote: false,
+            op_type: Some(local_op::OpType::Insert(LocalInsert {
+                value: value as u32,
+            })),
+        })),
+    };
+    for (position, value) in [(0, b'a'), (1, b'b')] {
+        a.commands
+            .send(insert(position, value).encode_to_vec().into())
+            .await
+            .unwrap();
+        b.commands
+            .send(
+                insert(position, value.to_ascii_uppercase())
+                    .encode_to_vec()
+                    .into(),
+            )
+            .await
+            .unwrap();
+    }
+    let remote =
+        |event: &server_event::Variant| matches!(event, server_event::Variant::Op(op) if op.remote);
+    wait_for_event(&mut a, remote).await;
+    wait_for_event(&mut a, remote).await;
+
+    let close = ClientCommand {
+        variant: Some(client_command::Variant::Close(CloseApplication {})),
+    };
+    for node in [&mut a, &mut b] {
+        node.commands
+            .send(close.encode_to_vec().into())
+            .await
+            .unwrap();
+    }
+    assert_eq!(a.handle.await.unwrap(), Ok(()));
+    assert_eq!(b.handle.await.unwrap(), Ok(()));
+    let saved = Session::from(1, &a.data_dir.join("doc.bin").to_string_lossy()).get_doc_ascii();
+    assert_eq!(saved.len(), 4);
+
+    let records = trace::read(&trace_path).unwrap();
+    use crate::protocol::trace::record::Event;
+    let count = |matches: fn(&Event) -> bool| {
+        records
+            .iter()
+            .filter(|record| record.event.as_ref().is_some_and(matches))
+            .count()
+    };
+    assert_eq!(count(|event| matches!(event, Event::Start(_))), 1);
+    assert_eq!(count(|event| matches!(event, Event::Edit(_))), 2);
+    assert_eq!(count(|event| matches!(event, Event::Connected(_))), 1);
+    assert!(count(|event| matches!(event, Event::Command(_))) >= 3);
+    assert!(count(|event| matches!(event, Event::Received(_))) >= 2);
+    assert!(count(|event| matches!(event, Event::Sent(_))) >= 2);
+
+    let sessions = trace::replay(records, false).unwrap();
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].get_doc_ascii(), saved);
+    for dir in [a.data_dir, b.data_dir] {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+/// Replays refuse what the node refused, going by the default role it
+/// started with and the roles a reload gave.
+#[test]
+pub fn trace_roles_test() {
+    let path = memory_data_dir(10_091).join("trace.bin");
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    let _ = std::fs::remove_file(&path);
+    let trace = Trace::open(&path).unwrap();
+    trace.start(2, &Doc::new(), Role::Viewer);
+    trace.received(3, &insert_op(1));
+    trace.roles(Role::Editor, &[(3, Role::Viewer)]);
+    trace.received(3, &insert_op(2));
+    trace.received(4, &insert_op(3));
+    drop(trace);
+
+    let sessions = trace::replay(trace::read(&path).unwrap(), false).unwrap();
+    assert_eq!(sessions[0].get_doc_ascii(), b"x");
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
+
+fn write_config(config: &NodeConfig, path: &Path) {
+    std::fs::write(path, toml::to_string_pretty(config).unwrap()).unwrap();
+}
+
+/// A running node picks up a changed config file, or one it's told to
+/// reload, keeping its links; settings it can't apply are reported instead.
+#[tokio::test]
+pub async fn config_reload_test() {
+    let network = MemoryNetwork::new();
+    let dir = memory_data_dir(10_061);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("config.toml");
+    write_config(&memory_config(1, 10_061, &[]), &path);
+    let args = cli_args(&["--data-dir", dir.to_str().unwrap()], &[]).unwrap();
+    let config = args.load().unwrap();
+    let mut a = start_memory_node(&network, config.clone(), Some(args));
+    let mut b = memory_node(&network, 2, 10_062, &[10_061]);
+
+    let status = |state: peer_status::State| {
+        move |event: &server_event::Variant| {
+            matches!(event, server_event::Variant::Status(status)
+                if status.state == state as i32)
+        }
+    };
+    wait_for_event(&mut a, status(peer_status::State::Connected)).await;
+    wait_for_event(&mut b, status(peer_status::State::Connected)).await;
+
+    let mut changed = config.clone();
+    changed.display_name = Some("renamed".to_string());
+    changed.anti_entropy_interval_ms = 5_000;
+    changed.room = "elsewhere".to_string();
+    changed.roles.default = Role::Viewer;
+    write_config(&changed, &path);
+    let reload = ClientCommand {
+        variant: Some(client_command::Variant::ReloadConfig(
+            protocol::ReloadConfig {},
+        )),
+    };
+    a.commands
+        .send(reload.encode_to_vec().into())
+        .await
+        .unwrap();
+    let reloaded = |event: &server_event::Variant| {
+        matches!(event, server_event::Variant::ConfigReloaded(report)
+            if !report.applied.is_empty() || !report.error.is_empty())
+    };
+    let role = wait_for_event(&mut a, |event| {
+        matches!(event, server_event::Variant::Role(_))
+    })
+    .await;
+    assert_eq!(
+        role,
+        server_event::Variant::Role(protocol::RoleAssignment {
+            role: protocol::Role::Viewer as i32
+        })
+    );
+    let server_event::Variant::ConfigReloaded(report) = wait_for_event(&mut a, reloaded).await
+    else {
+        unreachable!()
+    };
+    assert_eq!(report.error, "");
+    assert_eq!(
+        report.applied,
+        ["display_name", "roles", "anti_entropy_interval_ms"]
+    );
+    assert_eq!(report.restart_needed, ["room"]);
+
+    // Trusting only some other key drops B; the file is picked up unasked.
+    changed.trusted_fingerprints = vec![StaticKey::generate().fingerprint()];
+    write_config(&changed, &path);
+    let server_event::Variant::ConfigReloaded(report) = wait_for_event(&mut a, reloaded).await
+    else {
+        unreachable!()
+    };
+    assert_eq!(report.applied, ["trusted_fingerprints"]);
+    wait_for_event(&mut a, status(peer_status::State::Disconnected)).await;
+
+    // A broken file leaves the node running as it was.
+    std::fs::write(&path, "tcp_port = 0\n").unwrap();
+    let server_event::Variant::ConfigReloaded(report) = wait_for_event(&mut a, reloaded).await
+    else {
+        unreachable!()
+    };
+    assert!(
+        report.error.contains("tcp_port: must not be 0"),
+        "{}",
+        report.error
+    );
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "tcp_port = 0\n");
+
+    let close = ClientCommand {
+        variant: Some(client_command::Variant::Close(CloseApplication {})),
+    };
+    for node in [&mut a, &mut b] {
+        node.commands
+            .send(close.encode_to_vec().into())
+            .await
+            .unwrap();
+    }
+    assert_eq!(a.handle.await.unwrap(), Ok(()));
+    assert_eq!(b.handle.await.unwrap(), Ok(()));
+    for dir in [a.data_dir, b.data_dir] {
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[tokio::test]
+pub async fn heartbeat_rtt_test() -> Result<(), Box<dyn std::error::Error>> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let token = CancellationToken::new();
+    let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+
+    let (tx_server, token_server) = (tx.clone(), token.clone());
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let link = link_config(2, 20, 1_000);
+        transport::handle_connection(stream, false, tx_server, token_server, link).await;
+    });
+    let stream = tokio::net::TcpStream::connect(addr).await?;
+    let link = link_config(1, 20, 1_000);
+    tokio::spawn(transport::handle_connection(
+        stream,
+        true,
+        tx,
+        token.clone(),
+        link,
+    ));
+
+    let mut measured = HashSet::new();
+    while measured.len() < 2 {
+        match tokio::time::timeout(Duration::from_secs(5), rx.recv()).await? {
+            Some(NodeEvent::Net(PeerEvent::Rtt { id, rtt, .. })) => {
+                assert!(rtt < Duration::from_secs(1));
+                measured.insert(id);
+            }
+            Some(_) => {}
+            None => panic!("links closed before measuring RTT"),
+        }
+    }
+    token.cancel();
+    Ok(())
+}
+
+#[tokio::test]
+pub async fn websocket_link_test() -> Result<(), Box<dyn std::error::Error>> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let token = CancellationToken::new();
+    let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+
+    let (tx_server, token_server) = (tx.clone(), token.clone());
+    tokio::spawn(async move {
+        let (stream, remote) = listener.accept().await.unwrap();
+        let ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+        let link = WebSocketLink::new(ws, remote);
+        transport::handle_connection(
+            link,
+            false,
+            tx_server,
+            token_server,
+            link_config(2, 1_000, 5_000),
+        )
+        .await;
+    });
+    let stream = tokio::net::TcpStream::connect(addr).await?;
+    let (ws, _) = tokio_tungstenite::client_async(format!("ws://{}/", addr), stream).await?;
+    let mut doc = Doc::new();
+    doc.insert_absolute(1, 0, b'w')?;
+    tokio::spawn(transport::handle_connection(
+        WebSocketLink::new(ws, addr),
+        true,
+        tx,
+        token.clone(),
+        link_config(1, 1_000, 5_000),
+    ));
+
+    // Both ends finish the handshake and, as the service would, queue their
+    // initial snapshots. Connected names the far end, so peer 2 sends ours.
+    let (mut connected, mut synced) = (HashSet::new(), HashSet::new());
+    while connected.len() < 2 || synced.len() < 2 {
+        match tokio::time::timeout(Duration::from_secs(5), rx.recv()).await? {
+            Some(NodeEvent::Net(PeerEvent::Connected { id, outbox, .. })) => {
+                let state = if id == 2 { doc.clone() } else { Doc::new() };
+                outbox.push(PeerSyncOp::FullSync { state }, Doc::new);
+                connected.insert(id);
+            }
+            Some(NodeEvent::Sync {
+                from,
+                op: PeerSyncOp::FullSync { state },
+            }) => {
+                if from == 1 {
+                    assert_eq!(state.collect_ascii(), b"w");
+                }
+                synced.insert(from);
+            }
+            Some(_) => {}
+            None => panic!("links closed before syncing"),
+        }
+    }
+    token.cancel();
+    Ok(())
+}
+
+#[tokio::test]
+pub async fn websocket_limits_test() -> Result<(), Box<dyn std::error::Error>> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_tungstenite::tungstenite::Message;
+
+    let limits = LimitsConfig {
+        max_frame_bytes: 4096,
+        ..LimitsConfig::default()
+    };
+    let config = websocket::config(&limits);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let server = tokio::spawn(async move {
+        let mut reads = Vec::new();
+        for _ in 0..2 {
+            let (stream, remote) = listener.accept().await?;
+            let ws = tokio_tungstenite::accept_async_with_config(stream, Some(config))
+                .await
+                .map_err(std::io::Error::other)?;
+            let mut link = WebSocketLink::new(ws, remote);
+            let mut buf = vec![0u8; 10_000];
+            reads.push(link.read_exact(&mut buf).await.map(drop));
+        }
+        Ok::<_, std::io::Error>(reads)
+    });
+
+    // A peer held to the same limits splits what it writes to fit them.
+    let stream = tokio::net::TcpStream::connect(addr).await?;
+    let url = format!("ws://{}/", addr);
+    let (ws, _) =
+        tokio_tungstenite::client_async_with_config(url.clone(), stream, Some(config)).await?;
+    let mut link = WebSocketLink::new(ws, addr);
+    link.write_all(&[1u8; 10_000]).await?;
+    link.flush().await?;
+
+    // One sending everything as a single message is cut off before it's
+    // buffered.
+    let stream = tokio::net::TcpStream::connect(addr).await?;
+    let (mut ws, _) = tokio_tungstenite::client_async(url, stream).await?;
+    ws.send(Message::Binary(vec![1u8; 10_000].into())).await?;
+
+    let reads = server.await??;
+    assert!(reads[0].is_ok());
+    assert!(reads[1].is_err());
+
+    // Links a transport makes after a reload follow the new limits.
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await?;
+        let mut ws = tokio_tungstenite::accept_async(stream)
+            .await
+            .map_err(std::io::Error::other)?;
+        match ws.next().await {
+            Some(Ok(message)) => Ok(message.len()),
+            _ => Err(std::io::Error::other("no message")),
+        }
+    });
+    let dialer = transport::WebSocketTransport::new(0, &limits);
+    dialer.set_limits(&LimitsConfig::default());
+    let mut link = transport::Transport::connect(&dialer, addr).await?;
+    link.write_all(&[1u8; 10_000]).await?;
+    link.flush().await?;
+    assert_eq!(server.await??, 10_000);
+    Ok(())
+}
+
+#[tokio::test]
+pub async fn heartbeat_timeout_test() -> Result<(), Box<dyn std::error::Error>> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    // The far end completes the handshake, then goes to sleep like a laptop
+    // lid being closed: the socket stays open but nothing is ever read.
+    let asleep = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        handshake::perform(&mut stream, &handshake_config(2, None)).await?;
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        Ok::<_, std::io::Error>(stream)
+    });
+
+    let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+    let stream = tokio::net::TcpStream::connect(addr).await?;
+    let started = std::time::Instant::now();
+    tokio::spawn(transport::handle_connection(
+        stream,
+        true,
+        tx,
+        CancellationToken::new(),
+        link_config(1, 50, 300),
+    ));
+    loop {
+        match tokio::time::timeout(Duration::from_secs(5), rx.recv()).await? {
+            Some(NodeEvent::Net(PeerEvent::Disconnected { id, .. })) => {
+                assert_eq!(id, 2);
+                break;
+            }
+            Some(_) => {}
+            None => panic!("link task ended without reporting a disconnect"),
+        }
+    }
+    assert!(started.elapsed() >= Duration::from_millis(300));
+    assert!(started.elapsed() < Duration::from_secs(5));
+    asleep.abort();
+    Ok(())
+}
+
+#[test]
+pub fn rtt_stats_test() {
+    let mut stats = RttStats::default();
+    stats.record(Duration::from_millis(80));
+    assert_eq!(stats.smoothed, Duration::from_millis(80));
+    stats.record(Duration::from_millis(160));
+    assert_eq!(stats.last, Duration::from_millis(160));
+    assert_eq!(stats.smoothed, Duration::from_millis(90));
+    assert_eq!(stats.min, Duration::from_millis(80));
+    assert_eq!(stats.samples, 2);
+}
+
+fn insert_op(seq: u64) -> PeerSyncOp {
+    PeerSyncOp::Insert {
+        op_id: OpId { origin: 1, seq },
+        char_id: vec![NodeKey::new(seq as u32, 1, 1)],
+        value: b'x',
+    }
+}
+
+#[tokio::test]
+pub async fn peer_roles_test() {
+    let viewer_key = StaticKey::generate();
+    let roles: RolesConfig = toml::from_str(&format!(
+        "default = \"editor\"\n[peers]\n\"{}\" = \"viewer\"\n",
+        viewer_key.fingerprint()
+    ))
+    .unwrap();
+    assert_eq!(roles.role_of(&viewer_key.fingerprint()), Role::Viewer);
+    assert_eq!(RolesConfig::default().role_of("00:11"), Role::Owner);
+
+    let viewer = HandshakeConfig {
+        static_key: viewer_key,
+        ..handshake_config(2, None)
+    };
+    let editor = HandshakeConfig {
+        roles,
+        ..handshake_config(1, None)
+    };
+    let (a, b) = run_handshake(editor, viewer).await;
+    assert_eq!(a.unwrap().role, Role::Viewer);
+    assert_eq!(b.unwrap().role, Role::Owner);
+
+    let mut session = Session::new(1);
+    let mut gossip = Gossip::new();
+    session.set_peer_role(2, Role::Viewer);
+    session.set_peer_role(3, Role::Editor);
+    let edit = |origin, seq| PeerSyncOp::Insert {
+        op_id: OpId { origin, seq },
+        char_id: vec![NodeKey::new(seq as u32, origin, 1)],
+        value: b'x',
+    };
+
+    // Neither the viewer's own edits nor ones relayed as if by it get in.
+    for (from, origin) in [(2, 2), (2, 3), (3, 2)] {
+        let outcome = gossip.receive(&mut session, from, edit(origin, 10));
+        assert!(outcome.event.is_none() && outcome.relay.is_none());
+    }
+    let mut doc = Doc::new();
+    doc.insert_absolute(2, 0, b'v').unwrap();
+    let outcome = gossip.receive(&mut session, 2, PeerSyncOp::FullSync { state: doc });
+    assert!(outcome.relay.is_none());
+    assert!(session.get_doc_ascii().is_empty());
+
+    let outcome = gossip.receive(&mut session, 3, edit(3, 11));
+    assert!(outcome.event.is_some() && outcome.relay.is_some());
+    assert_eq!(session.get_doc_ascii(), b"x");
+
+    // Peers without a role of their own take the default one.
+    assert!(session.accepts(3, &edit(4, 12)));
+    session.set_default_role(Role::Viewer);
+    assert!(!session.accepts(3, &edit(4, 12)));
+    assert!(session.accepts(3, &edit(3, 12)));
+
+    // Viewers still get repaired by anti-entropy.
+    let stale = Session::new(2).digest();
+    gossip.receive(
+        &mut session,
+        2,
+        PeerSyncOp::Digest {
+            buckets: stale.clone(),
+        },
+    );
+    let outcome = gossip.receive(&mut session, 2, PeerSyncOp::Digest { buckets: stale });
+    assert!(matches!(
+        outcome.reply,
+        Some(PeerSyncOp::RangeSync { reply: true, .. })
+    ));
+}
+
+#[tokio::test]
+pub async fn viewer_edit_test() {
+    let network = MemoryNetwork::new();
+    let mut config = memory_config(1, 10_071, &[]);
+    config.roles.default = Role::Viewer;
+    let mut a = start_memory_node(&network, config, None);
+    let role = |event: &server_event::Variant| matches!(event, server_event::Variant::Role(_));
+    wait_for_event(&mut a, role).await;
+
+    let edit = ClientCommand {
+        variant: Some(client_command::Variant::Edit(LocalOp {
+            position: 0,
+            remote: false,
+            op_type: Some(local_op::OpType::Insert(LocalInsert { value: b'x' as u32 })),
+        })),
+    };
+    a.commands.send(edit.encode_to_vec().into()).await.unwrap();
+
+    // The edit is answered with the unchanged document and the role.
+    let state = wait_for_event(&mut a, |event| {
+        matches!(event, server_event::Variant::State(_))
+    })
+    .await;
+    assert_eq!(
+        state,
+        server_event::Variant::State(protocol::FullState {
+            content: Vec::new()
+        })
+    );
+    assert_eq!(
+        wait_for_event(&mut a, role).await,
+        server_event::Variant::Role(protocol::RoleAssignment {
+            role: protocol::Role::Viewer as i32
+        })
+    );
+
+    let close = ClientCommand {
+        variant: Some(client_command::Variant::Close(CloseApplication {})),
+    };
+    a.commands.send(close.encode_to_vec().into()).await.unwrap();
+    assert_eq!(a.handle.await.unwrap(), Ok(()));
+    std::fs::remove_dir_all(&a.data_dir).unwrap();
+}
+
+#[tokio::test]
+pub async fn outbox_order_test() {
+    let outbox = Outbox::new(16);
+    for seq in 0..10 {
+        assert_eq!(outbox.push(insert_op(seq), Doc::new), Push::Queued);
+    }
+    for seq in 0..10 {
+        assert_eq!(outbox.next().await.op_id().unwrap().seq, seq);
+    }
+
+    // Only the latest digest is worth sending, and a snapshot makes any
+    // edits still waiting redundant.
+    outbox.push(PeerSyncOp::Digest { buckets: vec![1] }, Doc::new);
+    outbox.push(insert_op(10), Doc::new);
+    outbox.push(PeerSyncOp::Digest { buckets: vec![2] }, Doc::new);
+    outbox.push(insert_op(11), Doc::new);
+    outbox.push(PeerSyncOp::FullSync { state: Doc::new() }, Doc::new);
+    assert_eq!(outbox.len(), 2);
+    assert!(matches!(outbox.pop(), Some(PeerSyncOp::Digest { buckets }) if buckets == vec![2]));
+    assert!(matches!(outbox.pop(), Some(PeerSyncOp::FullSync { .. })));
+    assert!(outbox.pop().is_none());
+}
+
+#[test]
+pub fn outbox_overflow_test() {
+    let outbox = Outbox::new(4);
+    for seq in 0..4 {
+        assert_eq!(outbox.push(insert_op(seq), || unreachable!()), Push::Queued);
+    }
+    assert_eq!(outbox.push(insert_op(4), Doc::new), Push::Resynced);
+    assert_eq!(outbox.len(), 1);
+
+    // Falling behind again before the snapshot went out drops the link...
+    for seq in 5..8 {
+        assert_eq!(outbox.push(insert_op(seq), Doc::new), Push::Queued);
+    }
+    assert!(matches!(outbox.pop(), Some(PeerSyncOp::FullSync { .. })));
+    assert_eq!(outbox.push(insert_op(8), Doc::new), Push::Queued);
+    assert_eq!(outbox.push(insert_op(9), Doc::new), Push::Overflowed);
+
+    // ...but a peer that caught up in between only gets resynced.
+    let outbox = Outbox::new(4);
+    for seq in 0..5 {
+        outbox.push(insert_op(seq), Doc::new);
+    }
+    while outbox.pop().is_some() {}
+    for seq in 5..9 {
+        assert_eq!(outbox.push(insert_op(seq), Doc::new), Push::Queued);
+    }
+    assert_eq!(outbox.push(insert_op(9), Doc::new), Push::Resynced);
+}
+
 #[derive(Debug, Deserialize)]
 #[allow(dead_code)]
 struct InsertOp {
@@ -150,7 +2642,7 @@ pub fn relative_insert_remove_test() {
 
     let mut doc = Doc::new();
     // ids maps op_index -> NodeKey. Use Option because Remove ops don't produce a NodeKey.
-    let mut ids: Vec<Option<Rc<[NodeKey]>>> = Vec::new();
+    let mut ids: Vec<Option<Arc<[NodeKey]>>> = Vec::new();
     let eos = doc.eos_id();
     let bos = doc.bos_id();
 
@@ -174,7 +2666,7 @@ pub fn relative_insert_remove_test() {
                 let peer_id = insert_op.peer_id as PeerId;
                 let new_id = doc.generate_id(&left_id, &right_id, peer_id);
                 ids.push(Some(new_id.clone()));
-                doc.insert_id(new_id, insert_op.char)
+                doc.insert_id(new_id, insert_op.char as u8)
                     .expect("Insert failed");
             }
             Operation::Remove(remove_op) => {
@@ -187,7 +2679,7 @@ pub fn relative_insert_remove_test() {
             }
         }
     }
-    let text = doc.collect_string();
+    let text = String::from_utf8(doc.collect_ascii()).expect("Document is not ASCII");
     println!("Final text: {}", text);
     assert_eq!(text, data_wrapper.result);
 }
@@ -0,0 +1,298 @@
+use crate::config::Role;
+use crate::error;
+use crate::gossip::Gossip;
+use crate::protocol::{self, PeerSyncOp, trace, wire};
+use crate::session::Session;
+use crate::state::{Doc, now_millis};
+use crate::types::PeerId;
+use prost::Message;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Error, ErrorKind, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Records what a node is told by its frontend and its peers, and what it
+/// sends them, to a trace file that `replay` can run through again. Cheap
+/// to clone; does nothing unless opened.
+#[derive(Clone, Default)]
+pub struct Trace {
+    file: Option<Arc<Mutex<BufWriter<File>>>>,
+}
+
+impl Trace {
+    /// Appends to the trace at `path`, creating it if needed.
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Some(Arc::new(Mutex::new(BufWriter::new(file)))),
+        })
+    }
+
+    /// Begins a run: the node's ID, the document it starts from, and the
+    /// role of peers without one of their own.
+    pub fn start(&self, peer_id: PeerId, doc: &Doc, default_role: Role) {
+        self.record(|| {
+            trace::record::Event::Start(trace::Start {
+                peer_id: peer_id as u32,
+                state: Some(doc.into()),
+                default_role: protocol::Role::from(default_role) as i32,
+            })
+        });
+    }
+
+    pub fn command(&self, command: &protocol::ClientCommand) {
+        self.record(|| trace::record::Event::Command(command.clone()));
+    }
+
+    /// The op a local edit produced.
+    pub fn edit(&self, op: &PeerSyncOp) {
+        self.record(|| {
+            trace::record::Event::Edit(trace::Edit {
+                op: Some(op.into()),
+            })
+        });
+    }
+
+    pub fn repair(&self, doc: &Doc) {
+        self.record(|| {
+            trace::record::Event::Repair(trace::Repair {
+                state: Some(doc.into()),
+            })
+        });
+    }
+
+    pub fn received(&self, from: PeerId, op: &PeerSyncOp) {
+        self.record(|| {
+            trace::record::Event::Received(trace::Received {
+                from: from as u32,
+                op: Some(op.into()),
+            })
+        });
+    }
+
+    pub fn sent(&self, to: PeerId, op: &PeerSyncOp) {
+        self.record(|| {
+            trace::record::Event::Sent(trace::Sent {
+                to: to as u32,
+                op: Some(op.into()),
+            })
+        });
+    }
+
+    pub fn connected(&self, peer_id: PeerId, role: Role) {
+        self.record(|| trace::record::Event::Connected(connected(peer_id, role)));
+    }
+
+    /// Roles as a reloaded config gives them.
+    pub fn roles(&self, default_role: Role, peers: &[(PeerId, Role)]) {
+        self.record(|| {
+            trace::record::Event::Roles(trace::Roles {
+                default_role: protocol::Role::from(default_role) as i32,
+                peers: peers
+                    .iter()
+                    .map(|&(peer_id, role)| connected(peer_id, role))
+                    .collect(),
+            })
+        });
+    }
+
+    pub fn disconnected(&self, peer_id: PeerId) {
+        self.record(|| {
+            trace::record::Event::Disconnected(trace::Disconnected {
+                peer_id: peer_id as u32,
+            })
+        });
+    }
+
+    /// Events are only built when tracing is on. Records are flushed one by
+    /// one, so a trace is complete up to whatever crashed the node.
+    fn record(&self, event: impl FnOnce() -> trace::record::Event) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        let record = trace::Record {
+            time_ms: now_millis(),
+            event: Some(event()),
+        };
+        let mut file = file.lock().unwrap();
+        let result = file
+            .write_all(&record.encode_length_delimited_to_vec())
+            .and_then(|()| file.flush());
+        if let Err(e) = result {
+            error!("Failed to write trace: {}", e);
+        }
+    }
+}
+
+fn connected(peer_id: PeerId, role: Role) -> trace::Connected {
+    trace::Connected {
+        peer_id: peer_id as u32,
+        role: protocol::Role::from(role) as i32,
+    }
+}
+
+pub fn read(path: &Path) -> std::io::Result<Vec<trace::Record>> {
+    let bytes = std::fs::read(path)?;
+    let mut buf = &bytes[..];
+    let mut records = Vec::new();
+    while !buf.is_empty() {
+        let record = trace::Record::decode_length_delimited(&mut buf)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+fn peer_id(id: u32) -> std::io::Result<PeerId> {
+    PeerId::try_from(id).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("peer ID out of range: {}", id),
+        )
+    })
+}
+
+fn op(op: Option<wire::SyncOp>) -> std::io::Result<PeerSyncOp> {
+    op.ok_or_else(|| Error::new(ErrorKind::InvalidData, "record without an op"))?
+        .try_into()
+}
+
+fn snapshot(state: Option<wire::Snapshot>) -> std::io::Result<Doc> {
+    state
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "record without a state"))?
+        .try_into()
+}
+
+fn describe(op: &PeerSyncOp) -> String {
+    match op {
+        PeerSyncOp::Insert { op_id, value, .. } => format!(
+            "insert {:?} (op {}:{})",
+            char::from(*value),
+            op_id.origin,
+            op_id.seq
+        ),
+        PeerSyncOp::Remove { op_id, .. } => {
+            format!("remove (op {}:{})", op_id.origin, op_id.seq)
+        }
+        PeerSyncOp::FullSync { state } => {
+            format!("full sync, {} characters", state.collect_ascii().len())
+        }
+        PeerSyncOp::Digest { .. } => "digest".to_string(),
+        PeerSyncOp::RangeSync { buckets, reply, .. } => {
+            format!("range sync of {} buckets, reply: {}", buckets.len(), reply)
+        }
+    }
+}
+
+/// Runs the recorded runs of a node through fresh sessions, in order,
+/// printing what happens when `log` is set. Returns the session of every
+/// run, as it was at the end of the trace.
+pub fn replay(records: Vec<trace::Record>, log: bool) -> std::io::Result<Vec<Session>> {
+    use trace::record::Event;
+
+    let mut runs: Vec<(Session, Gossip)> = Vec::new();
+    let mut started_ms = 0;
+    for (index, record) in records.into_iter().enumerate() {
+        let event = record
+            .event
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "record without an event"))?;
+        if let Event::Start(start) = event {
+            let peer_id = peer_id(start.peer_id)?;
+            let default_role = Role::from(start.default_role());
+            let mut session = Session::with_doc(peer_id, snapshot(start.state)?);
+            session.set_default_role(default_role);
+            if log {
+                println!(
+                    "#{} run {} as peer {}, {} characters, {:?} by default",
+                    index,
+                    runs.len() + 1,
+                    peer_id,
+                    session.get_doc_ascii().len(),
+                    default_role
+                );
+            }
+            runs.push((session, Gossip::new()));
+            started_ms = record.time_ms;
+            continue;
+        }
+        let (session, gossip) = runs.last_mut().ok_or_else(|| {
+            Error::new(ErrorKind::InvalidData, "trace doesn't begin with a start")
+        })?;
+        let line = match event {
+            Event::Start(_) => unreachable!(),
+            Event::Command(command) => match command.variant {
+                Some(variant) => format!("command {:?}", variant),
+                None => "empty command".to_string(),
+            },
+            Event::Edit(edit) => {
+                let op = op(edit.op)?;
+                let line = format!("edit: {}", describe(&op));
+                gossip.local_op(&op);
+                let local_id = session.local_id();
+                session.apply_peer_sync_op(local_id, op);
+                line
+            }
+            Event::Repair(repair) => {
+                session.set_doc(snapshot(repair.state)?);
+                "repaired".to_string()
+            }
+            Event::Received(received) => {
+                let from = peer_id(received.from)?;
+                let op = op(received.op)?;
+                let mut line = format!("from {}: {}", from, describe(&op));
+                let outcome = gossip.receive(session, from, op);
+                if !outcome.diverged.is_empty() {
+                    line += &format!(", diverged in {} ranges", outcome.diverged.len());
+                }
+                line
+            }
+            Event::Sent(sent) => format!("to {}: {}", sent.to, describe(&op(sent.op)?)),
+            Event::Connected(connected) => {
+                let peer_id = peer_id(connected.peer_id)?;
+                let role = Role::from(connected.role());
+                session.set_peer_role(peer_id, role);
+                format!("peer {} connected as {:?}", peer_id, role)
+            }
+            Event::Roles(roles) => {
+                let default_role = Role::from(roles.default_role());
+                session.set_default_role(default_role);
+                for connected in &roles.peers {
+                    session
+                        .set_peer_role(peer_id(connected.peer_id)?, Role::from(connected.role()));
+                }
+                format!("roles reloaded, {:?} by default", default_role)
+            }
+            Event::Disconnected(disconnected) => {
+                if let Ok(peer_id) = peer_id(disconnected.peer_id) {
+                    gossip.forget(peer_id);
+                }
+                format!("peer {} disconnected", disconnected.peer_id)
+            }
+        };
+        if log {
+            let elapsed = record.time_ms.saturating_sub(started_ms);
+            println!(
+                "#{} +{}.{:03}s {}",
+                index,
+                elapsed / 1000,
+                elapsed % 1000,
+                line
+            );
+        }
+    }
+    Ok(runs.into_iter().map(|(session, _)| session).collect())
+}
+
+/// `backend --replay`: replays the trace at `path`, then prints the
+/// document each run ended with.
+pub fn replay_file(path: &Path) -> std::io::Result<()> {
+    let sessions = replay(read(path)?, true)?;
+    for (run, mut session) in sessions.into_iter().enumerate() {
+        println!("run {} ended with:", run + 1);
+        println!("{}", String::from_utf8_lossy(&session.get_doc_ascii()));
+        for issue in session.validate_doc(false).issues {
+            println!("  invalid: {}", issue);
+        }
+    }
+    Ok(())
+}
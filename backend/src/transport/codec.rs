@@ -1,40 +1,192 @@
-use crate::protocol::{ClientCommand, PeerSyncOp, ServerEvent};
+use super::secure::{Cipher, MAX_CHUNK_LEN, TAG_LEN};
+use crate::config::LimitsConfig;
+use crate::protocol::{ClientCommand, PeerBeacon, PeerSyncOp, ServerEvent, wire};
+use crate::state::DIGEST_BUCKETS;
+use crate::warn;
 use bytes::{Bytes, BytesMut};
 use prost::Message;
+use std::borrow::Cow;
+use std::io::{Error, ErrorKind};
 use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec};
 
+/// Frames shorter than this are sent as they are; LZ4 saves next to nothing
+/// on a single edit.
+pub const COMPRESS_THRESHOLD: usize = 512;
+
+const RAW: u8 = 0;
+const LZ4: u8 = 1;
+
+/// Everything sent over an established peer link: document ops, plus
+/// keepalives that never leave the connection task.
+#[derive(Debug, Clone)]
+pub enum Frame {
+    Op(PeerSyncOp),
+    Ping { nonce: u64 },
+    Pong { nonce: u64 },
+}
+
+impl From<PeerSyncOp> for Frame {
+    fn from(op: PeerSyncOp) -> Self {
+        Frame::Op(op)
+    }
+}
+
 pub struct PeerSyncOpCodec {
     delegate: LengthDelimitedCodec,
+    cipher: Cipher,
+    compression: bool,
+    limits: LimitsConfig,
 }
 
 impl PeerSyncOpCodec {
-    pub fn new() -> Self {
+    pub fn new(cipher: Cipher) -> Self {
         Self {
             delegate: LengthDelimitedCodec::new(),
+            cipher,
+            compression: false,
+            limits: LimitsConfig::default(),
+        }
+        .with_limits(LimitsConfig::default())
+    }
+
+    /// Rejects frames breaking `limits`, in either direction, with an
+    /// `InvalidData` error.
+    pub fn with_limits(mut self, limits: LimitsConfig) -> Self {
+        self.delegate = LengthDelimitedCodec::builder()
+            .max_frame_length(limits.max_frame_bytes)
+            .new_codec();
+        self.limits = limits;
+        self
+    }
+
+    /// Switches to the framing of the compression feature. Both ends of a
+    /// link must agree on it, so only enable it once it was negotiated.
+    pub fn with_compression(mut self, enabled: bool) -> Self {
+        self.compression = enabled;
+        self
+    }
+
+    /// Splits a snapshot too large for the limits into range syncs of
+    /// fewer buckets each, which the peer merges one by one. A single bucket
+    /// too large to send is left as it is.
+    pub fn fit(&self, frame: Frame) -> Vec<Frame> {
+        let encoded = wire::LinkFrame::from(&frame);
+        if encoded.encoded_len() <= self.payload_limit()
+            && super::wire::check_limits(&encoded, &self.limits).is_ok()
+        {
+            return vec![frame];
+        }
+        let (buckets, state, reply) = match frame {
+            Frame::Op(PeerSyncOp::FullSync { state }) => {
+                ((0..DIGEST_BUCKETS as u32).collect(), state, false)
+            }
+            Frame::Op(PeerSyncOp::RangeSync {
+                buckets,
+                state,
+                reply,
+            }) if buckets.len() > 1 => (buckets, state, reply),
+            frame => return vec![frame],
+        };
+        let (low, high) = buckets.split_at(buckets.len() / 2);
+        [low, high]
+            .into_iter()
+            .flat_map(|half| {
+                self.fit(Frame::Op(PeerSyncOp::RangeSync {
+                    state: state.slice(half),
+                    buckets: half.to_vec(),
+                    reply,
+                }))
+            })
+            .collect()
+    }
+
+    /// Largest encoded frame that stays within `max_frame_bytes` once
+    /// flagged for compression and encrypted.
+    fn payload_limit(&self) -> usize {
+        let tags = (self.limits.max_frame_bytes / MAX_CHUNK_LEN + 1) * TAG_LEN;
+        self.limits.max_frame_bytes.saturating_sub(tags + 1)
+    }
+}
+
+/// Prefixes `data` with the compression flag, compressing it if that makes
+/// it shorter.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    if data.len() >= COMPRESS_THRESHOLD {
+        let block = lz4_flex::block::compress(data);
+        if block.len() + 4 < data.len() {
+            let mut out = Vec::with_capacity(block.len() + 5);
+            out.push(LZ4);
+            out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            out.extend_from_slice(&block);
+            return out;
+        }
+    }
+    let mut out = Vec::with_capacity(data.len() + 1);
+    out.push(RAW);
+    out.extend_from_slice(data);
+    out
+}
+
+/// Undoes `compress`, refusing to inflate anything beyond `max_len` bytes.
+pub fn decompress(data: &[u8], max_len: usize) -> std::io::Result<Cow<'_, [u8]>> {
+    match data.split_first() {
+        Some((&RAW, rest)) => Ok(Cow::Borrowed(rest)),
+        Some((&LZ4, rest)) if rest.len() >= 4 => {
+            let (len, block) = rest.split_at(4);
+            let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+            if len > max_len {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("compressed frame too large: {} bytes", len),
+                ));
+            }
+            let out = lz4_flex::block::decompress(block, len)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            if out.len() != len {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "compressed frame length mismatch",
+                ));
+            }
+            Ok(Cow::Owned(out))
         }
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            "invalid compression flag",
+        )),
     }
 }
 
-impl Encoder<PeerSyncOp> for PeerSyncOpCodec {
+impl Encoder<Frame> for PeerSyncOpCodec {
     type Error = std::io::Error;
 
-    fn encode(&mut self, item: PeerSyncOp, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let data = bincode::serialize(&item)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    fn encode(&mut self, item: Frame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let mut data = wire::LinkFrame::from(&item).encode_to_vec();
+        if self.compression {
+            data = compress(&data);
+        }
+        let data = self.cipher.encrypt(&data)?;
         self.delegate.encode(Bytes::from(data), dst)
     }
 }
 
 impl Decoder for PeerSyncOpCodec {
-    type Item = PeerSyncOp;
+    type Item = Frame;
     type Error = std::io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         match self.delegate.decode(src)? {
             Some(frame) => {
-                let msg = bincode::deserialize(&frame)
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-                Ok(Some(msg))
+                let frame = self.cipher.decrypt(&frame)?;
+                let frame = if self.compression {
+                    decompress(&frame, self.limits.max_frame_bytes)?
+                } else {
+                    Cow::Borrowed(frame.as_slice())
+                };
+                let msg = wire::LinkFrame::decode(frame.as_ref())
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                super::wire::check_limits(&msg, &self.limits)?;
+                Ok(Some(msg.try_into()?))
             }
             None => Ok(None),
         }
@@ -45,7 +197,7 @@ pub fn try_decode_op(bytes: bytes::BytesMut) -> Option<ClientCommand> {
     match ClientCommand::decode(bytes) {
         Ok(cmd) => Some(cmd),
         Err(e) => {
-            eprintln!("Invalid protobuf on stdin: {}", e);
+            warn!("Invalid protobuf from the frontend: {}", e);
             None
         }
     }
@@ -56,3 +208,13 @@ pub fn encode_protobuf(msg: &ServerEvent) -> Result<Bytes, prost::EncodeError> {
     msg.encode(&mut buf)?;
     Ok(buf.freeze())
 }
+
+pub fn encode_beacon(beacon: &PeerBeacon) -> Vec<u8> {
+    wire::Beacon::from(beacon).encode_to_vec()
+}
+
+pub fn decode_beacon(bytes: &[u8]) -> std::io::Result<PeerBeacon> {
+    wire::Beacon::decode(bytes)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?
+        .try_into()
+}
@@ -1,20 +1,81 @@
 use super::codec;
-use crate::types::PeerId;
-use crate::{config, protocol, select_loop, state};
+use super::handshake::{self, HandshakeConfig};
+use super::link::{PeerLink, Transport};
+use crate::outbox::Outbox;
+use crate::trace::Trace;
+use crate::{config, error, info, protocol, select_loop, warn};
+use bytes::Bytes;
 use futures::{SinkExt, StreamExt};
 use socket2::{Domain, Protocol, Socket, Type};
 use std::io::ErrorKind;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::mpsc;
+use tokio::time::Instant;
 use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 use tokio_util::sync::CancellationToken;
 
 type PacketSender = mpsc::Sender<protocol::NodeEvent>;
 
-pub async fn run_stdin_listener(tx: PacketSender, token: CancellationToken) -> std::io::Result<()> {
-    let stdin = tokio::io::stdin();
-    let mut framed = FramedRead::new(stdin, LengthDelimitedCodec::new());
+/// Encoded `ServerEvent`s on their way to one client attached over IPC.
+pub type ClientSender = mpsc::Sender<Bytes>;
+
+/// How many events may wait for an IPC client before it's detached.
+pub const CLIENT_QUEUE: usize = 256;
+
+/// Where `ServerEvent`s for frontends go: stdout, or nowhere when the node
+/// runs headless, plus every client attached over IPC.
+pub struct EventWriter {
+    output: FramedWrite<Box<dyn AsyncWrite + Send + Unpin>, LengthDelimitedCodec>,
+    clients: Vec<ClientSender>,
+}
+
+impl EventWriter {
+    /// Adds a client, sending it `greeting` before anything else.
+    pub fn attach(&mut self, client: ClientSender, greeting: &[protocol::ServerEvent]) {
+        for event in greeting {
+            let Ok(bytes) = codec::encode_protobuf(event) else {
+                error!("Protobuf encoding failed");
+                return;
+            };
+            if client.try_send(bytes).is_err() {
+                return;
+            }
+        }
+        self.clients.push(client);
+    }
+}
+
+pub fn event_writer(output: impl AsyncWrite + Send + Unpin + 'static) -> EventWriter {
+    EventWriter {
+        output: FramedWrite::new(Box::new(output), LengthDelimitedCodec::new()),
+        clients: Vec::new(),
+    }
+}
+
+pub(super) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Everything a peer link needs besides its socket.
+#[derive(Clone)]
+pub struct LinkConfig {
+    pub handshake: HandshakeConfig,
+    pub heartbeat: config::HeartbeatConfig,
+    pub limits: config::LimitsConfig,
+    pub transport: Arc<dyn Transport>,
+    pub trace: Trace,
+}
+
+/// Reads `ClientCommand`s from the frontend until its stream closes. Only
+/// the frontend the node runs for may close it; a `Close` from an IPC client
+/// (`ipc`) just ends that client's commands.
+pub async fn run_command_listener(
+    input: impl AsyncRead + Unpin,
+    tx: PacketSender,
+    token: CancellationToken,
+    ipc: bool,
+) -> std::io::Result<()> {
+    let mut framed = FramedRead::new(input, LengthDelimitedCodec::new());
 
     select_loop! {
         _ = token.cancelled() => return Ok(()),
@@ -22,14 +83,18 @@ pub async fn run_stdin_listener(tx: PacketSender, token: CancellationToken) -> s
         maybe_frame = framed.next() => {
             match maybe_frame {
                 Some(Ok(bytes)) => {
-                    if let Some(cmd) = codec::try_decode_op(bytes) {
-                        if let Err(e) = tx.send(protocol::NodeEvent::Local(cmd)).await {
-                            return Err(std::io::Error::new(ErrorKind::BrokenPipe, e));
-                        }
+                    let Some(cmd) = codec::try_decode_op(bytes) else {
+                        continue;
+                    };
+                    if ipc && matches!(cmd.variant, Some(protocol::client_command::Variant::Close(_))) {
+                        return Ok(());
+                    }
+                    if let Err(e) = tx.send(protocol::NodeEvent::Local(cmd)).await {
+                        return Err(std::io::Error::new(ErrorKind::BrokenPipe, e.to_string()));
                     }
                 }
                 Some(Err(e)) => {
-                    eprintln!("Stdin framing error: {}", e);
+                    warn!("Frontend framing error: {}", e);
                 }
                 None => return Ok(()),
             }
@@ -61,27 +126,33 @@ pub async fn run_discovery(
     let beacon = protocol::PeerBeacon {
         id: config.peer_id,
         tcp_port: config.tcp_port,
+        room: config.room.clone(),
     };
 
-    let msg_bytes =
-        bincode::serialize(&beacon).map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+    let msg_bytes = codec::encode_beacon(&beacon);
 
-    let broadcast_target = format!("255.255.255.255:{}", config.udp_discovery_port);
-    let mut buf = [0u8; 1024];
+    let broadcast_target = std::net::SocketAddr::new(
+        config.discovery.broadcast_address.into(),
+        config.udp_discovery_port,
+    );
+    let interval = std::time::Duration::from_millis(config.discovery.interval_ms);
+    // One spare byte tells an oversized datagram from one that just fits.
+    let mut buf = vec![0u8; config.limits.max_beacon_bytes + 1];
 
     select_loop! {
         _ = token.cancelled() => return Ok(()),
 
-        _ = tokio::time::sleep(std::time::Duration::from_secs(1)) => {
+        _ = tokio::time::sleep(interval) => {
             if let Err(e) = socket.send_to(&msg_bytes, &broadcast_target).await {
-                eprintln!("Discovery broadcast warn: {}", e);
+                warn!("Discovery broadcast warn: {}", e);
             }
         }
 
         recv_result = socket.recv_from(&mut buf) => {
             match recv_result {
+                Ok((len, _)) if len > config.limits.max_beacon_bytes => {}
                 Ok((len, remote_addr)) => {
-                    if let Ok(remote_beacon) = bincode::deserialize::<protocol::PeerBeacon>(&buf[..len]) {
+                    if let Ok(remote_beacon) = codec::decode_beacon(&buf[..len]) {
 
                         if remote_beacon.id == config.peer_id { continue; }
 
@@ -92,42 +163,16 @@ pub async fn run_discovery(
 
                         if let Err(e) = tx.send(protocol::NodeEvent::Net(protocol::PeerEvent::Discovered {
                             id: remote_beacon.id,
-                            addr: peer_tcp_addr
+                            addr: peer_tcp_addr,
+                            room: remote_beacon.room,
                         })).await {
-                            eprintln!("Failed to send PeerDiscovered: {}", e);
+                            error!("Failed to send PeerDiscovered: {}", e);
                         }
                     }
                 }
                 Err(e) => {
-                    eprintln!("Discovery recv warn: {}", e);
-                }
-            }
-        }
-    }
-}
-
-pub async fn run_tcp_listener(
-    tx: PacketSender,
-    token: CancellationToken,
-    tcp_port: u16,
-) -> std::io::Result<()> {
-    let addr_str = format!("0.0.0.0:{}", tcp_port);
-    let listener = TcpListener::bind(&addr_str).await?;
-    eprintln!("TCP listening on: {}", addr_str);
-
-    select_loop! {
-        _ = token.cancelled() => return Ok(()),
-
-        accept_result = listener.accept() => {
-            match accept_result {
-                Ok((stream, addr)) => {
-                    eprintln!("New incoming TCP connection from: {}", addr);
-                    if let Err(e) = tx.send(protocol::NodeEvent::Net(protocol::PeerEvent::Connection { stream })).await
-                    {
-                        eprintln!("Failed to send PeerConnection: {}", e);
-                    }
+                    warn!("Discovery recv warn: {}", e);
                 }
-                Err(e) => eprintln!("TCP accept error: {}", e),
             }
         }
     }
@@ -137,135 +182,252 @@ pub async fn connect_to_peer(
     addr: std::net::SocketAddr,
     tx: PacketSender,
     token: CancellationToken,
-    doc_state: state::Doc,
-    my_id: PeerId,
+    link: LinkConfig,
 ) {
-    eprintln!("Connecting to peer at {}", addr);
-    match TcpStream::connect(addr).await {
+    info!("Connecting to peer at {}", addr);
+    match link.transport.connect(addr).await {
         Ok(stream) => {
-            handle_connection(stream, tx, token, doc_state, my_id).await;
+            handle_connection(stream, true, tx.clone(), token, link).await;
         }
-        Err(e) => eprintln!("Failed to connect to {}: {}", addr, e),
+        Err(e) => warn!("Failed to connect to {}: {}", addr, e),
     }
+    let _ = tx
+        .send(protocol::NodeEvent::Net(protocol::PeerEvent::DialEnded {
+            addr,
+        }))
+        .await;
 }
 
-pub async fn handle_connection(
-    mut stream: TcpStream,
+/// Runs one peer link until it drops, the node shuts down, or the service
+/// closes it through the `closer` token handed out in `PeerEvent::Connected`.
+pub async fn handle_connection<S: PeerLink>(
+    mut stream: S,
+    outbound: bool,
     tx: PacketSender,
     token: CancellationToken,
-    doc_state: state::Doc,
-    my_id: PeerId,
+    link: LinkConfig,
 ) {
-    let peer_id = match async {
-        stream.write_all(&[my_id]).await?;
-        let mut buf = [0u8; 1];
-        stream.read_exact(&mut buf).await?;
-        Ok::<_, std::io::Error>(buf[0])
-    }
+    let addr = match stream.peer_addr() {
+        Ok(addr) => addr,
+        Err(e) => {
+            warn!("Connection lost before handshake: {}", e);
+            return;
+        }
+    };
+    let established = match tokio::time::timeout(
+        HANDSHAKE_TIMEOUT,
+        handshake::perform(&mut stream, &link.handshake),
+    )
     .await
     {
-        Ok(id) => {
-            eprintln!("Handshake successful. Connected with peer {}", id);
-            id
+        Ok(Ok(established)) => established,
+        Ok(Err(e)) => {
+            warn!("Handshake failed: {}", e);
+            return;
         }
-        Err(e) => {
-            eprintln!("Handshake failed: {}", e);
+        Err(_) => {
+            warn!("Handshake timed out");
             return;
         }
     };
+    let handshake::Established {
+        peer_id,
+        version,
+        features,
+        role,
+        name,
+        channel,
+    } = established;
+    info!(
+        "Handshake successful. Connected with peer {} {:?} ({}), protocol v{}, features: {}, role: {:?}",
+        peer_id, name, channel.remote_fingerprint, version, features, role
+    );
 
-    let (read_half, write_half) = stream.into_split();
-    let mut framed_read = FramedRead::new(read_half, codec::PeerSyncOpCodec::new());
-    let mut framed_write = FramedWrite::new(write_half, codec::PeerSyncOpCodec::new());
-
-    if let Err(e) = framed_write
-        .send(protocol::PeerSyncOp::FullSync { state: doc_state })
-        .await
-    {
-        eprintln!("Failed to send initial state to peer {}: {}", peer_id, e);
-        return;
-    }
+    let (read_half, write_half) = tokio::io::split(stream);
+    let compression = features.contains(handshake::Features::COMPRESSION);
+    let mut framed_read = FramedRead::new(
+        read_half,
+        codec::PeerSyncOpCodec::new(channel.decryptor)
+            .with_compression(compression)
+            .with_limits(link.limits.clone()),
+    );
+    let framed_write = FramedWrite::new(
+        write_half,
+        codec::PeerSyncOpCodec::new(channel.encryptor)
+            .with_compression(compression)
+            .with_limits(link.limits.clone()),
+    );
 
-    let (tx_peer, rx_peer) = mpsc::channel::<protocol::PeerSyncOp>(255);
+    let token = token.child_token();
+    let outbox = Outbox::default();
     if let Err(e) = tx
         .send(protocol::NodeEvent::Net(protocol::PeerEvent::Connected {
             id: peer_id,
-            sender: tx_peer,
+            addr,
+            role,
+            name,
+            fingerprint: channel.remote_fingerprint,
+            outbound,
+            outbox: outbox.clone(),
+            closer: token.clone(),
         }))
         .await
     {
-        eprintln!("Failed to send PeerConnected event: {}", e);
+        error!("Failed to send PeerConnected event: {}", e);
         return;
     }
 
+    // Pings and pongs skip the op queue so a backed-up link can't starve
+    // its own keepalive.
+    let (tx_control, rx_control) = mpsc::channel::<codec::Frame>(8);
     let write_token = token.clone();
+    let trace = link.trace.clone();
     tokio::spawn(async move {
-        run_writer_loop(framed_write, rx_peer, write_token, peer_id).await;
+        run_writer_loop(
+            framed_write,
+            outbox,
+            rx_control,
+            write_token,
+            peer_id,
+            trace,
+        )
+        .await;
     });
 
+    let timeout = Duration::from_millis(link.heartbeat.timeout_ms);
+    let mut heartbeat = tokio::time::interval(Duration::from_millis(link.heartbeat.interval_ms));
+    heartbeat.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut last_heard = Instant::now();
+    let mut ping: Option<(u64, Instant)> = None;
+    let mut nonce = 0u64;
+
     select_loop! {
         _ = token.cancelled() => break,
 
+        _ = tokio::time::sleep_until(last_heard + timeout) => {
+            warn!("Peer {} silent for {:?}, dropping link", peer_id, timeout);
+            break;
+        }
+
+        _ = heartbeat.tick() => {
+            // Only the latest ping is timed; a pong for an older one is ignored.
+            nonce += 1;
+            ping = Some((nonce, Instant::now()));
+            let _ = tx_control.try_send(codec::Frame::Ping { nonce });
+        }
+
         frame = framed_read.next() => {
             match frame {
-                Some(Ok(msg)) => {
-                    if let Err(e) = tx.send(protocol::NodeEvent::Sync(msg)).await {
-                        eprintln!("Failed to forward message from peer {}: {}", peer_id, e);
-                        break;
+                Some(Ok(frame)) => {
+                    last_heard = Instant::now();
+                    match frame {
+                        codec::Frame::Op(msg) => {
+                            if let Err(e) = tx.send(protocol::NodeEvent::Sync { from: peer_id, op: msg }).await {
+                                error!("Failed to forward message from peer {}: {}", peer_id, e);
+                                break;
+                            }
+                        }
+                        codec::Frame::Ping { nonce } => {
+                            let _ = tx_control.try_send(codec::Frame::Pong { nonce });
+                        }
+                        codec::Frame::Pong { nonce } => {
+                            let Some((sent, at)) = ping else { continue };
+                            if sent != nonce {
+                                continue;
+                            }
+                            ping = None;
+                            let rtt = protocol::PeerEvent::Rtt { id: peer_id, addr, rtt: at.elapsed() };
+                            let _ = tx.send(protocol::NodeEvent::Net(rtt)).await;
+                        }
                     }
                 }
+                Some(Err(e)) if e.kind() == ErrorKind::InvalidData => {
+                    // Oversized or malformed: not something an honest peer sends.
+                    warn!("Peer {} broke the protocol: {}", peer_id, e);
+                    let event = protocol::PeerEvent::Misbehaved { id: peer_id, addr };
+                    let _ = tx.send(protocol::NodeEvent::Net(event)).await;
+                    break;
+                }
                 Some(Err(e)) => {
-                    eprintln!("Read error from peer {}: {}", peer_id, e);
+                    warn!("Read error from peer {}: {}", peer_id, e);
                     break;
                 }
                 None => {
-                    eprintln!("Connection closed by peer {}", peer_id);
+                    info!("Connection closed by peer {}", peer_id);
                     break;
                 }
             }
         }
     }
 
-    eprintln!("Disconnected from peer {}", peer_id);
+    token.cancel();
+    info!("Disconnected from peer {}", peer_id);
     let _ = tx
         .send(protocol::NodeEvent::Net(
-            protocol::PeerEvent::Disconnected { id: peer_id },
+            protocol::PeerEvent::Disconnected { id: peer_id, addr },
         ))
         .await;
 }
 
-async fn run_writer_loop(
-    mut framed_write: FramedWrite<tokio::net::tcp::OwnedWriteHalf, codec::PeerSyncOpCodec>,
-    mut rx_peer: mpsc::Receiver<protocol::PeerSyncOp>,
+async fn run_writer_loop<S: PeerLink>(
+    mut framed_write: FramedWrite<tokio::io::WriteHalf<S>, codec::PeerSyncOpCodec>,
+    outbox: Outbox,
+    mut rx_control: mpsc::Receiver<codec::Frame>,
     token: CancellationToken,
     peer_id: u8,
+    trace: Trace,
 ) {
-    select_loop! {
-        _ = token.cancelled() => return,
+    loop {
+        let frame = tokio::select! {
+            biased;
+
+            _ = token.cancelled() => return,
+
+            Some(frame) = rx_control.recv() => frame,
 
-        msg = rx_peer.recv() => {
-            match msg {
-                Some(m) => {
-                    if let Err(e) = framed_write.send(m).await {
-                        eprintln!("Write error to peer {}: {}", peer_id, e);
+            op = outbox.next() => {
+                trace.sent(peer_id, &op);
+                codec::Frame::Op(op)
+            }
+        };
+        for frame in framed_write.encoder().fit(frame) {
+            // A peer that stopped reading must not wedge us in send(); the
+            // reader's timeout cancels the token and gets us out.
+            tokio::select! {
+                _ = token.cancelled() => return,
+
+                result = framed_write.send(frame) => {
+                    // Whatever is queued for the link would be lost, so the
+                    // link goes and the peer resyncs when it's back.
+                    if let Err(e) = result {
+                        warn!("Write error to peer {}: {}", peer_id, e);
+                        token.cancel();
                         return;
                     }
                 }
-                None => return,
             }
         }
     }
 }
 
-pub async fn send_server_event(
-    event: &protocol::ServerEvent,
-    writer: &mut FramedWrite<tokio::io::Stdout, LengthDelimitedCodec>,
-) {
+pub async fn send_server_event(event: &protocol::ServerEvent, writer: &mut EventWriter) {
     let Ok(bytes) = codec::encode_protobuf(event) else {
-        eprintln!("Protobuf encoding failed");
+        error!("Protobuf encoding failed");
         return;
     };
-    if let Err(e) = writer.send(bytes).await {
-        eprintln!("Failed to write to stdout: {}", e);
+    // A client that can't keep up is detached rather than holding up the
+    // event loop.
+    writer
+        .clients
+        .retain(|client| match client.try_send(bytes.clone()) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                warn!("IPC client fell behind, detaching it");
+                false
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        });
+    if let Err(e) = writer.output.send(bytes).await {
+        error!("Failed to write to the frontend: {}", e);
     }
 }
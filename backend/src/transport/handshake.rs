@@ -0,0 +1,389 @@
+use super::secure::{self, SecureChannel, StaticKey};
+use crate::config::{NodeConfig, Role, RolesConfig};
+use crate::types::PeerId;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const NONCE_LEN: usize = 32;
+const AUTH_NONE: u8 = 0;
+const AUTH_PSK: u8 = 1;
+
+/// Longest display name, in bytes; longer ones are cut short.
+pub const MAX_NAME_LEN: usize = 64;
+/// Longest room name, in bytes.
+pub const MAX_ROOM_LEN: usize = 64;
+
+const HELLO_MAGIC: &[u8; 4] = b"DTE\0";
+const HELLO_LEN: usize = 14;
+// v2: edits carry an OpId for multi-hop relay.
+// v3: Digest and RangeSync for anti-entropy.
+// v4: ops wrapped in link frames, with ping/pong keepalives.
+// v5: link frames and beacons encoded as protobuf (proto/peer.proto).
+// v6: snapshot identifiers share prefixes with their predecessor.
+// v7: display names exchanged at the end of the handshake.
+// v8: room names exchanged after the hello; beacons carry the room.
+pub const PROTOCOL_VERSION: u16 = 8;
+pub const MIN_PROTOCOL_VERSION: u16 = 8;
+
+/// Optional protocol features, advertised as a bit set in the hello.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Features(u32);
+
+impl Features {
+    pub const COMPRESSION: Self = Self(1 << 0);
+    pub const DELTAS: Self = Self(1 << 1);
+    pub const PRESENCE: Self = Self(1 << 2);
+
+    /// Everything this build knows how to speak. Deltas and presence are
+    /// reserved until implemented, so a peer never negotiates them with us.
+    pub const SUPPORTED: Self = Self::COMPRESSION;
+
+    const NAMES: [(Self, &'static str); 3] = [
+        (Self::COMPRESSION, "compression"),
+        (Self::DELTAS, "deltas"),
+        (Self::PRESENCE, "presence"),
+    ];
+
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for Features {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl fmt::Display for Features {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = Self::NAMES
+            .iter()
+            .filter(|(feature, _)| self.contains(*feature))
+            .map(|(_, name)| *name)
+            .collect();
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(", "))
+        }
+    }
+}
+
+/// First frame on every link. Fixed layout, so it stays readable across
+/// versions: magic, version and oldest compatible version (u16 BE each),
+/// peer ID, auth mode, features (u32 BE).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Hello {
+    version: u16,
+    min_version: u16,
+    peer_id: PeerId,
+    auth: u8,
+    features: Features,
+}
+
+impl Hello {
+    fn to_bytes(self) -> [u8; HELLO_LEN] {
+        let mut buf = [0u8; HELLO_LEN];
+        buf[..4].copy_from_slice(HELLO_MAGIC);
+        buf[4..6].copy_from_slice(&self.version.to_be_bytes());
+        buf[6..8].copy_from_slice(&self.min_version.to_be_bytes());
+        buf[8] = self.peer_id;
+        buf[9] = self.auth;
+        buf[10..].copy_from_slice(&self.features.0.to_be_bytes());
+        buf
+    }
+
+    fn from_bytes(buf: &[u8; HELLO_LEN]) -> std::io::Result<Self> {
+        if &buf[..4] != HELLO_MAGIC {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "peer is not a DTE node or predates protocol versioning",
+            ));
+        }
+        Ok(Self {
+            version: u16::from_be_bytes([buf[4], buf[5]]),
+            min_version: u16::from_be_bytes([buf[6], buf[7]]),
+            peer_id: buf[8],
+            auth: buf[9],
+            features: Features(u32::from_be_bytes([buf[10], buf[11], buf[12], buf[13]])),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HandshakeConfig {
+    pub my_id: PeerId,
+    pub secret: Option<String>,
+    pub static_key: StaticKey,
+    pub trusted_fingerprints: Vec<String>,
+    pub features: Features,
+    pub roles: RolesConfig,
+    /// Shown to peers; may be empty.
+    pub name: String,
+    /// Peers in any other room are turned away.
+    pub room: String,
+}
+
+impl TryFrom<&NodeConfig> for HandshakeConfig {
+    type Error = std::io::Error;
+
+    fn try_from(config: &NodeConfig) -> Result<Self, Self::Error> {
+        let static_key = config
+            .static_key
+            .as_deref()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "static_key missing from config"))?;
+        if config.room.len() > MAX_ROOM_LEN {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("room name longer than {} bytes", MAX_ROOM_LEN),
+            ));
+        }
+        Ok(Self {
+            my_id: config.peer_id,
+            secret: config.workspace_secret.clone(),
+            static_key: StaticKey::from_hex(static_key)?,
+            trusted_fingerprints: config.trusted_fingerprints.clone(),
+            features: Features::SUPPORTED,
+            roles: config.roles.clone(),
+            name: config.display_name.clone().unwrap_or_default(),
+            room: config.room.clone(),
+        })
+    }
+}
+
+pub struct Established {
+    pub peer_id: PeerId,
+    pub version: u16,
+    pub features: Features,
+    /// What the peer may do, going by its authenticated fingerprint.
+    pub role: Role,
+    pub name: String,
+    pub channel: SecureChannel,
+}
+
+/// Exchanges hellos and room names, sets up an encrypted channel and, when a
+/// workspace secret is configured, runs a challenge-response proving both
+/// sides know it. Display names are swapped last, over the encrypted channel.
+/// Nothing else is written to the stream until this returns Ok.
+pub async fn perform<S>(stream: &mut S, config: &HandshakeConfig) -> std::io::Result<Established>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let auth = if config.secret.is_some() {
+        AUTH_PSK
+    } else {
+        AUTH_NONE
+    };
+    let hello = Hello {
+        version: PROTOCOL_VERSION,
+        min_version: MIN_PROTOCOL_VERSION,
+        peer_id: config.my_id,
+        auth,
+        features: config.features,
+    };
+    stream.write_all(&hello.to_bytes()).await?;
+    let mut buf = [0u8; HELLO_LEN];
+    stream.read_exact(&mut buf).await?;
+    let peer_hello = Hello::from_bytes(&buf)?;
+    let peer_id = peer_hello.peer_id;
+
+    let version = PROTOCOL_VERSION.min(peer_hello.version);
+    if version < MIN_PROTOCOL_VERSION.max(peer_hello.min_version) {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            format!(
+                "peer {} speaks protocol v{}-v{}, we speak v{}-v{}",
+                peer_id,
+                peer_hello.min_version,
+                peer_hello.version,
+                MIN_PROTOCOL_VERSION,
+                PROTOCOL_VERSION
+            ),
+        ));
+    }
+    if peer_id == config.my_id {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("peer uses our own ID {}", peer_id),
+        ));
+    }
+    if peer_hello.auth != auth {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("peer {} disagrees on workspace authentication", peer_id),
+        ));
+    }
+
+    let room = exchange_rooms(stream, &config.room).await?;
+    if room != config.room {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "peer {} is in room {:?}, we are in {:?}",
+                peer_id, room, config.room
+            ),
+        ));
+    }
+
+    // The lower ID initiates; the prologue binds both plaintext hellos and
+    // the room into the Noise handshake so they can't be tampered with.
+    let initiator = config.my_id < peer_id;
+    let mut prologue = if initiator {
+        [hello.to_bytes(), peer_hello.to_bytes()]
+    } else {
+        [peer_hello.to_bytes(), hello.to_bytes()]
+    }
+    .concat();
+    prologue.extend_from_slice(room.as_bytes());
+    let mut channel = secure::establish(stream, &config.static_key, initiator, &prologue).await?;
+
+    if let Some(secret) = &config.secret {
+        authenticate(
+            stream,
+            secret,
+            config.my_id,
+            peer_id,
+            &channel.handshake_hash,
+        )
+        .await?;
+    }
+    if !config.trusted_fingerprints.is_empty()
+        && !config
+            .trusted_fingerprints
+            .contains(&channel.remote_fingerprint)
+    {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "peer {} has untrusted fingerprint {}",
+                peer_id, channel.remote_fingerprint
+            ),
+        ));
+    }
+    let name = exchange_names(stream, &mut channel, &config.name).await?;
+    Ok(Established {
+        peer_id,
+        version,
+        features: config.features.intersection(peer_hello.features),
+        role: config.roles.role_of(&channel.remote_fingerprint),
+        name,
+        channel,
+    })
+}
+
+/// Sends our room name and reads the peer's, each prefixed with its length
+/// as one byte. Room names aren't secret: beacons broadcast them anyway.
+async fn exchange_rooms<S>(stream: &mut S, room: &str) -> std::io::Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_u8(room.len() as u8).await?;
+    stream.write_all(room.as_bytes()).await?;
+
+    let len = stream.read_u8().await? as usize;
+    if len > MAX_ROOM_LEN {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("peer room name too long: {} bytes", len),
+        ));
+    }
+    let mut room = vec![0u8; len];
+    stream.read_exact(&mut room).await?;
+    String::from_utf8(room).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+/// Sends our name as the first encrypted message and reads the peer's,
+/// each prefixed with its length as a u16 BE.
+async fn exchange_names<S>(
+    stream: &mut S,
+    channel: &mut SecureChannel,
+    name: &str,
+) -> std::io::Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut end = name.len().min(MAX_NAME_LEN);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    let sealed = channel.encryptor.encrypt(&name.as_bytes()[..end])?;
+    stream
+        .write_all(&(sealed.len() as u16).to_be_bytes())
+        .await?;
+    stream.write_all(&sealed).await?;
+
+    let len = stream.read_u16().await? as usize;
+    if len > MAX_NAME_LEN + secure::TAG_LEN {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("peer name too long: {} bytes", len),
+        ));
+    }
+    let mut sealed = vec![0u8; len];
+    stream.read_exact(&mut sealed).await?;
+    let name = channel.decryptor.decrypt(&sealed)?;
+    Ok(String::from_utf8_lossy(&name).into_owned())
+}
+
+async fn authenticate<S>(
+    stream: &mut S,
+    secret: &str,
+    my_id: PeerId,
+    peer_id: PeerId,
+    channel_binding: &[u8],
+) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut my_nonce = [0u8; NONCE_LEN];
+    rand::rng().fill_bytes(&mut my_nonce);
+    stream.write_all(&my_nonce).await?;
+    let mut peer_nonce = [0u8; NONCE_LEN];
+    stream.read_exact(&mut peer_nonce).await?;
+
+    // Nonces are ordered differently for each direction, so a proof can't be
+    // reflected back at its sender. The Noise handshake hash ties the proof
+    // to this encrypted channel, so it can't be relayed into another one.
+    let proof = prove(secret, channel_binding, &peer_nonce, &my_nonce, my_id).finalize();
+    stream.write_all(&proof.into_bytes()).await?;
+    let mut peer_proof = [0u8; 32];
+    stream.read_exact(&mut peer_proof).await?;
+
+    prove(secret, channel_binding, &my_nonce, &peer_nonce, peer_id)
+        .verify_slice(&peer_proof)
+        .map_err(|_| {
+            Error::new(
+                ErrorKind::PermissionDenied,
+                format!("peer {} failed workspace authentication", peer_id),
+            )
+        })
+}
+
+fn prove(
+    secret: &str,
+    channel_binding: &[u8],
+    challenge: &[u8],
+    nonce: &[u8],
+    id: PeerId,
+) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(channel_binding);
+    mac.update(challenge);
+    mac.update(nonce);
+    mac.update(&[id]);
+    mac
+}
@@ -0,0 +1,112 @@
+use super::connection::{self, CLIENT_QUEUE};
+use crate::{info, protocol, select_loop, warn};
+use futures::SinkExt;
+use std::io::{Error, ErrorKind};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc;
+use tokio_util::codec::{FramedWrite, LengthDelimitedCodec};
+use tokio_util::sync::CancellationToken;
+
+/// Lets local clients attach to the node over a Unix domain socket at
+/// `path`, with the same framing as the frontend on stdin/stdout. Every
+/// client gets every `ServerEvent`, and its commands go to the event loop
+/// as if the frontend sent them, except that closing detaches the client
+/// instead of shutting down the node.
+pub async fn run_ipc_listener(
+    path: PathBuf,
+    tx: mpsc::Sender<protocol::NodeEvent>,
+    token: CancellationToken,
+) -> std::io::Result<()> {
+    remove_stale_socket(&path).await?;
+    let listener = bind_private(&path)?;
+    info!("IPC listening on: {}", path.display());
+
+    let result = accept_clients(&listener, &tx, &token).await;
+    let _ = std::fs::remove_file(&path);
+    result
+}
+
+/// A socket nobody answers on was left behind by a node that didn't shut
+/// down cleanly. One still in use makes the bind fail, and anything but a
+/// socket is left alone.
+async fn remove_stale_socket(path: &Path) -> std::io::Result<()> {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} exists and isn't a socket", path.display()),
+        ));
+    }
+    if UnixStream::connect(path).await.is_err() {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Binds a socket at `path` that only our user can ever connect to: it is
+/// bound in a private directory, restricted, and only then linked into
+/// place. Linking fails rather than replace anything already at `path`.
+fn bind_private(path: &Path) -> std::io::Result<UnixListener> {
+    let staging = path.with_file_name(format!(".ipc-{:08x}", rand::random::<u32>()));
+    std::fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join("s");
+    let result = UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::hard_link(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&staged);
+    let _ = std::fs::remove_dir(&staging);
+    result
+}
+
+async fn accept_clients(
+    listener: &UnixListener,
+    tx: &mpsc::Sender<protocol::NodeEvent>,
+    token: &CancellationToken,
+) -> std::io::Result<()> {
+    select_loop! {
+        _ = token.cancelled() => return Ok(()),
+
+        accept_result = listener.accept() => {
+            match accept_result {
+                Ok((stream, _)) => {
+                    info!("IPC client attached");
+                    let (commands, events) = stream.into_split();
+                    let (tx_events, rx_events) = mpsc::channel(CLIENT_QUEUE);
+                    if tx.send(protocol::NodeEvent::Attach { events: tx_events }).await.is_err() {
+                        return Ok(());
+                    }
+                    let forward = tokio::spawn(forward_events(rx_events, events));
+                    let (tx, token) = (tx.clone(), token.clone());
+                    tokio::spawn(async move {
+                        if let Err(e) = connection::run_command_listener(commands, tx, token, true).await {
+                            warn!("IPC client listener failed: {}", e);
+                        }
+                        // Hangs up on the client; the node drops it on its
+                        // next event.
+                        forward.abort();
+                        info!("IPC client detached");
+                    });
+                }
+                Err(e) => warn!("IPC accept error: {}", e),
+            }
+        }
+    }
+}
+
+async fn forward_events(
+    mut rx: mpsc::Receiver<bytes::Bytes>,
+    output: tokio::net::unix::OwnedWriteHalf,
+) {
+    let mut framed = FramedWrite::new(output, LengthDelimitedCodec::new());
+    while let Some(bytes) = rx.recv().await {
+        if framed.send(bytes).await.is_err() {
+            return;
+        }
+    }
+}
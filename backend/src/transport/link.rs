@@ -0,0 +1,89 @@
+use crate::{error, info, protocol, select_loop, warn};
+use futures::future::BoxFuture;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+/// A byte stream a peer link runs over. The handshake, encryption and
+/// framing on top are the same whatever carries the bytes.
+pub trait PeerLink: AsyncRead + AsyncWrite + Send + Unpin + 'static {
+    fn peer_addr(&self) -> std::io::Result<SocketAddr>;
+}
+
+impl PeerLink for TcpStream {
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+}
+
+impl PeerLink for Box<dyn PeerLink> {
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        (**self).peer_addr()
+    }
+}
+
+/// A way of reaching peers: TCP, WebSockets, or an in-memory network in
+/// tests. Peers are addressed by `SocketAddr` whatever the transport, so
+/// that the dialing and reconnect logic stays the same.
+pub trait Transport: Send + Sync + 'static {
+    /// Accepts links until `token` is cancelled, handing each one to the
+    /// event loop as a `PeerEvent::Connection`.
+    fn listen(
+        &self,
+        tx: mpsc::Sender<protocol::NodeEvent>,
+        token: CancellationToken,
+    ) -> BoxFuture<'static, std::io::Result<()>>;
+
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'static, std::io::Result<Box<dyn PeerLink>>>;
+}
+
+pub struct TcpTransport {
+    port: u16,
+}
+
+impl TcpTransport {
+    pub fn new(port: u16) -> Self {
+        Self { port }
+    }
+}
+
+impl Transport for TcpTransport {
+    fn listen(
+        &self,
+        tx: mpsc::Sender<protocol::NodeEvent>,
+        token: CancellationToken,
+    ) -> BoxFuture<'static, std::io::Result<()>> {
+        let port = self.port;
+        Box::pin(async move {
+            let addr_str = format!("0.0.0.0:{}", port);
+            let listener = TcpListener::bind(&addr_str).await?;
+            info!("TCP listening on: {}", addr_str);
+
+            select_loop! {
+                _ = token.cancelled() => return Ok(()),
+
+                accept_result = listener.accept() => {
+                    match accept_result {
+                        Ok((stream, addr)) => {
+                            info!("New incoming TCP connection from: {}", addr);
+                            let event = protocol::PeerEvent::Connection { stream: Box::new(stream) };
+                            if let Err(e) = tx.send(protocol::NodeEvent::Net(event)).await {
+                                error!("Failed to send PeerConnection: {}", e);
+                            }
+                        }
+                        Err(e) => warn!("TCP accept error: {}", e),
+                    }
+                }
+            }
+        })
+    }
+
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'static, std::io::Result<Box<dyn PeerLink>>> {
+        Box::pin(async move {
+            let stream = TcpStream::connect(addr).await?;
+            Ok(Box::new(stream) as Box<dyn PeerLink>)
+        })
+    }
+}
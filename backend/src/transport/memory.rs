@@ -0,0 +1,127 @@
+use super::link::{PeerLink, Transport};
+use crate::protocol;
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Links between nodes in one process. Every node listens on a made-up
+/// address, and dialing it opens an in-memory duplex pipe.
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    listeners: Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<MemoryLink>>>>,
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Transport for the node reachable at `addr`.
+    pub fn transport(&self, addr: SocketAddr) -> MemoryTransport {
+        MemoryTransport {
+            network: self.clone(),
+            addr,
+        }
+    }
+}
+
+pub struct MemoryTransport {
+    network: MemoryNetwork,
+    addr: SocketAddr,
+}
+
+impl Transport for MemoryTransport {
+    fn listen(
+        &self,
+        tx: mpsc::Sender<protocol::NodeEvent>,
+        token: CancellationToken,
+    ) -> BoxFuture<'static, std::io::Result<()>> {
+        let (tx_links, mut rx_links) = mpsc::channel(16);
+        let network = self.network.clone();
+        let addr = self.addr;
+        network.listeners.lock().unwrap().insert(addr, tx_links);
+        Box::pin(async move {
+            loop {
+                tokio::select! {
+                    _ = token.cancelled() => break,
+
+                    Some(link) = rx_links.recv() => {
+                        let event = protocol::PeerEvent::Connection { stream: Box::new(link) };
+                        if tx.send(protocol::NodeEvent::Net(event)).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+            network.listeners.lock().unwrap().remove(&addr);
+            Ok(())
+        })
+    }
+
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'static, std::io::Result<Box<dyn PeerLink>>> {
+        let listener = self.network.listeners.lock().unwrap().get(&addr).cloned();
+        let local = self.addr;
+        Box::pin(async move {
+            let refused = || Error::new(ErrorKind::ConnectionRefused, "nobody listening");
+            let listener = listener.ok_or_else(refused)?;
+            let (ours, theirs) = tokio::io::duplex(BUFFER_SIZE);
+            let theirs = MemoryLink {
+                stream: theirs,
+                peer: local,
+            };
+            listener.send(theirs).await.map_err(|_| refused())?;
+            Ok(Box::new(MemoryLink {
+                stream: ours,
+                peer: addr,
+            }) as Box<dyn PeerLink>)
+        })
+    }
+}
+
+pub struct MemoryLink {
+    stream: DuplexStream,
+    peer: SocketAddr,
+}
+
+impl PeerLink for MemoryLink {
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+}
+
+impl AsyncRead for MemoryLink {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MemoryLink {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
@@ -1,4 +1,17 @@
-mod codec;
+pub mod codec;
 mod connection;
+pub mod handshake;
+#[cfg(unix)]
+pub mod ipc;
+pub mod link;
+#[cfg(test)]
+pub mod memory;
+pub mod secure;
+pub mod websocket;
+mod wire;
 
 pub use connection::*;
+pub use handshake::HandshakeConfig;
+pub use link::{PeerLink, TcpTransport, Transport};
+pub use secure::StaticKey;
+pub use websocket::WebSocketTransport;
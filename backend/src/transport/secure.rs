@@ -0,0 +1,183 @@
+use sha2::{Digest, Sha256};
+use snow::params::DHChoice;
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+use snow::{Builder, StatelessTransportState};
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+const MAX_MESSAGE_LEN: usize = 65535;
+pub(super) const TAG_LEN: usize = 16;
+pub(super) const MAX_CHUNK_LEN: usize = MAX_MESSAGE_LEN - TAG_LEN;
+
+#[derive(Clone)]
+pub struct StaticKey {
+    private: Vec<u8>,
+    public: Vec<u8>,
+}
+
+impl StaticKey {
+    pub fn generate() -> Self {
+        let keypair = Builder::new(NOISE_PARAMS.parse().unwrap())
+            .generate_keypair()
+            .expect("default resolver supports Curve25519");
+        Self {
+            private: keypair.private,
+            public: keypair.public,
+        }
+    }
+
+    pub fn from_hex(hex_key: &str) -> std::io::Result<Self> {
+        let private = hex::decode(hex_key).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let mut dh = DefaultResolver
+            .resolve_dh(&DHChoice::Curve25519)
+            .expect("default resolver supports Curve25519");
+        if private.len() != dh.priv_len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("static key must be {} bytes", dh.priv_len()),
+            ));
+        }
+        dh.set(&private);
+        Ok(Self {
+            public: dh.pubkey().to_vec(),
+            private,
+        })
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(&self.private)
+    }
+
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public)
+    }
+}
+
+impl fmt::Debug for StaticKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "StaticKey({})", self.fingerprint())
+    }
+}
+
+/// Short, human-comparable digest of a public key, e.g. `3f1a:09bc:...`.
+pub fn fingerprint(public_key: &[u8]) -> String {
+    let digest = Sha256::digest(public_key);
+    digest[..16]
+        .chunks(2)
+        .map(hex::encode)
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// One direction of an established channel. Frames larger than a single
+/// Noise message are split into consecutive chunks, each with its own nonce.
+pub struct Cipher {
+    state: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+impl Cipher {
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> std::io::Result<Vec<u8>> {
+        let chunks = plaintext.len().div_ceil(MAX_CHUNK_LEN).max(1);
+        let mut ciphertext = vec![0u8; plaintext.len() + chunks * TAG_LEN];
+        let mut written = 0;
+        for idx in 0..chunks {
+            let end = ((idx + 1) * MAX_CHUNK_LEN).min(plaintext.len());
+            let chunk = &plaintext[idx * MAX_CHUNK_LEN..end];
+            written += self
+                .state
+                .write_message(self.nonce, chunk, &mut ciphertext[written..])
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            self.nonce += 1;
+        }
+        Ok(ciphertext)
+    }
+
+    pub fn decrypt(&mut self, ciphertext: &[u8]) -> std::io::Result<Vec<u8>> {
+        if ciphertext.len() < TAG_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "truncated frame"));
+        }
+        let mut plaintext = vec![0u8; ciphertext.len()];
+        let mut written = 0;
+        for chunk in ciphertext.chunks(MAX_MESSAGE_LEN) {
+            written += self
+                .state
+                .read_message(self.nonce, chunk, &mut plaintext[written..])
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            self.nonce += 1;
+        }
+        plaintext.truncate(written);
+        Ok(plaintext)
+    }
+}
+
+pub struct SecureChannel {
+    pub encryptor: Cipher,
+    pub decryptor: Cipher,
+    pub remote_fingerprint: String,
+    pub handshake_hash: Vec<u8>,
+}
+
+/// Runs a Noise XX handshake over `stream`. Both sides learn each other's
+/// static key; the prologue must match on both ends or the handshake fails.
+pub async fn establish<S>(
+    stream: &mut S,
+    key: &StaticKey,
+    initiator: bool,
+    prologue: &[u8],
+) -> std::io::Result<SecureChannel>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let builder = Builder::new(NOISE_PARAMS.parse().unwrap())
+        .local_private_key(&key.private)
+        .prologue(prologue);
+    let mut noise = if initiator {
+        builder.build_initiator()
+    } else {
+        builder.build_responder()
+    }
+    .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+
+    let mut buf = vec![0u8; MAX_MESSAGE_LEN];
+    while !noise.is_handshake_finished() {
+        if noise.is_my_turn() {
+            let len = noise
+                .write_message(&[], &mut buf)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            stream.write_all(&(len as u16).to_be_bytes()).await?;
+            stream.write_all(&buf[..len]).await?;
+        } else {
+            let len = stream.read_u16().await? as usize;
+            let mut message = vec![0u8; len];
+            stream.read_exact(&mut message).await?;
+            noise
+                .read_message(&message, &mut buf)
+                .map_err(|e| Error::new(ErrorKind::PermissionDenied, e))?;
+        }
+    }
+
+    let remote_fingerprint = fingerprint(
+        noise
+            .get_remote_static()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "peer sent no static key"))?,
+    );
+    let handshake_hash = noise.get_handshake_hash().to_vec();
+    let state = Arc::new(
+        noise
+            .into_stateless_transport_mode()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
+    );
+    Ok(SecureChannel {
+        encryptor: Cipher {
+            state: state.clone(),
+            nonce: 0,
+        },
+        decryptor: Cipher { state, nonce: 0 },
+        remote_fingerprint,
+        handshake_hash,
+    })
+}
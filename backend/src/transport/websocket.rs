@@ -0,0 +1,215 @@
+use super::connection::HANDSHAKE_TIMEOUT;
+use super::link::{PeerLink, Transport};
+use crate::config::LimitsConfig;
+use crate::{error, info, protocol, select_loop, warn};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::{Sink, Stream};
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_util::sync::CancellationToken;
+
+/// Length prefix in front of every frame on a link.
+const LENGTH_PREFIX: usize = 4;
+
+/// Message limits for links held to `limits`. A message may hold one whole
+/// frame but no more, so a peer can't make us buffer beyond what the codec
+/// would accept anyway.
+pub fn config(limits: &LimitsConfig) -> WebSocketConfig {
+    let max = limits.max_frame_bytes.saturating_add(LENGTH_PREFIX);
+    WebSocketConfig::default()
+        .max_message_size(Some(max))
+        .max_frame_size(Some(max))
+}
+
+/// Byte stream carried in binary WebSocket messages, so that browsers can
+/// run the same handshake and framing as TCP peers. Message boundaries mean
+/// nothing; a client may split or join the stream however it likes. Writes
+/// are split to fit the stream's own message limit.
+pub struct WebSocketLink<S> {
+    ws: WebSocketStream<S>,
+    addr: SocketAddr,
+    pending: Bytes,
+}
+
+impl<S> WebSocketLink<S> {
+    pub fn new(ws: WebSocketStream<S>, addr: SocketAddr) -> Self {
+        Self {
+            ws,
+            addr,
+            pending: Bytes::new(),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketLink<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.pending.is_empty() {
+                let len = this.pending.len().min(buf.remaining());
+                buf.put_slice(&this.pending.split_to(len));
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(Pin::new(&mut this.ws).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => this.pending = data,
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "text messages are not part of the protocol",
+                    )));
+                }
+                // Pings are answered by tungstenite itself.
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => {}
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Err(e)) => return Poll::Ready(Err(Error::other(e))),
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketLink<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let max = this.ws.get_config().max_message_size.unwrap_or(usize::MAX);
+        let buf = &buf[..buf.len().min(max)];
+        ready!(Pin::new(&mut this.ws).poll_ready(cx)).map_err(Error::other)?;
+        Pin::new(&mut this.ws)
+            .start_send(Message::Binary(Bytes::copy_from_slice(buf)))
+            .map_err(Error::other)?;
+        // Start sending right away; callers like the handshake write and
+        // then wait for an answer without flushing.
+        if let Poll::Ready(Err(e)) = Pin::new(&mut this.ws).poll_flush(cx) {
+            return Poll::Ready(Err(Error::other(e)));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().ws)
+            .poll_flush(cx)
+            .map_err(Error::other)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().ws)
+            .poll_close(cx)
+            .map_err(Error::other)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin + 'static> PeerLink for WebSocketLink<S> {
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+/// WebSocket peers, accepted on `port`. Once upgraded, a connection is
+/// handed to the event loop like any TCP one. Message limits follow
+/// `limits`, as last set, when a connection is upgraded.
+pub struct WebSocketTransport {
+    port: u16,
+    config: Arc<Mutex<WebSocketConfig>>,
+}
+
+impl WebSocketTransport {
+    pub fn new(port: u16, limits: &LimitsConfig) -> Self {
+        Self {
+            port,
+            config: Arc::new(Mutex::new(config(limits))),
+        }
+    }
+
+    /// Holds connections upgraded from now on to reloaded `limits`.
+    pub fn set_limits(&self, limits: &LimitsConfig) {
+        *self.config.lock().unwrap() = config(limits);
+    }
+}
+
+impl Transport for WebSocketTransport {
+    fn listen(
+        &self,
+        tx: mpsc::Sender<protocol::NodeEvent>,
+        token: CancellationToken,
+    ) -> BoxFuture<'static, std::io::Result<()>> {
+        let (port, config) = (self.port, self.config.clone());
+        Box::pin(async move {
+            let addr_str = format!("0.0.0.0:{}", port);
+            let listener = TcpListener::bind(&addr_str).await?;
+            info!("WebSocket listening on: {}", addr_str);
+
+            select_loop! {
+                _ = token.cancelled() => return Ok(()),
+
+                accept_result = listener.accept() => {
+                    match accept_result {
+                        Ok((stream, addr)) => {
+                            let config = *config.lock().unwrap();
+                            tokio::spawn(upgrade(stream, addr, config, tx.clone()));
+                        }
+                        Err(e) => warn!("WebSocket accept error: {}", e),
+                    }
+                }
+            }
+        })
+    }
+
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'static, std::io::Result<Box<dyn PeerLink>>> {
+        let config = *self.config.lock().unwrap();
+        Box::pin(async move {
+            let stream = TcpStream::connect(addr).await?;
+            let url = format!("ws://{}/", addr);
+            let (ws, _) = tokio::time::timeout(
+                HANDSHAKE_TIMEOUT,
+                tokio_tungstenite::client_async_with_config(url, stream, Some(config)),
+            )
+            .await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, "WebSocket upgrade timed out"))?
+            .map_err(Error::other)?;
+            Ok(Box::new(WebSocketLink::new(ws, addr)) as Box<dyn PeerLink>)
+        })
+    }
+}
+
+async fn upgrade(
+    stream: TcpStream,
+    addr: SocketAddr,
+    config: WebSocketConfig,
+    tx: mpsc::Sender<protocol::NodeEvent>,
+) {
+    let upgrade = tokio_tungstenite::accept_async_with_config(stream, Some(config));
+    let ws = match tokio::time::timeout(HANDSHAKE_TIMEOUT, upgrade).await {
+        Ok(Ok(ws)) => ws,
+        Ok(Err(e)) => {
+            warn!("WebSocket upgrade from {} failed: {}", addr, e);
+            return;
+        }
+        Err(_) => {
+            warn!("WebSocket upgrade from {} timed out", addr);
+            return;
+        }
+    };
+    info!("New incoming WebSocket connection from: {}", addr);
+    let stream = Box::new(WebSocketLink::new(ws, addr));
+    let event = protocol::PeerEvent::Connection { stream };
+    if let Err(e) = tx.send(protocol::NodeEvent::Net(event)).await {
+        error!("Failed to send PeerConnection: {}", e);
+    }
+}
@@ -0,0 +1,363 @@
+//! Conversions between the node's own types and the protobuf messages of the
+//! peer protocol. Encoding can't fail; decoding rejects anything the Rust
+//! types can't represent, such as a peer ID above 255 or a missing field.
+
+use super::codec::Frame;
+use crate::config::LimitsConfig;
+use crate::protocol::{OpId, PeerBeacon, PeerSyncOp, wire};
+use crate::state::{self, Doc, NodeKey};
+use crate::types::PeerId;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Arc;
+
+fn invalid(message: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, message.into())
+}
+
+fn narrow(value: u32, field: &str) -> Result<u8> {
+    u8::try_from(value).map_err(|_| invalid(format!("{} out of range: {}", field, value)))
+}
+
+fn required<T>(value: Option<T>, field: &str) -> Result<T> {
+    value.ok_or_else(|| invalid(format!("missing {}", field)))
+}
+
+/// Checks `frame` against `limits` before anything is built from it.
+/// Prefix sharing lets a small snapshot expand into huge identifiers, so
+/// the keys are counted as they will be once decoded.
+pub(super) fn check_limits(frame: &wire::LinkFrame, limits: &LimitsConfig) -> Result<()> {
+    use wire::link_frame::Variant as Frame;
+    use wire::sync_op::Variant as Op;
+
+    let depth = |id: &wire::Identifier| -> Result<usize> {
+        let depth = (id.shared as usize).saturating_add(id.keys.len());
+        if depth > limits.max_identifier_depth {
+            return Err(invalid(format!(
+                "identifier {} keys deep, limit is {}",
+                depth, limits.max_identifier_depth
+            )));
+        }
+        Ok(depth)
+    };
+    let snapshot = |snapshot: &wire::Snapshot| -> Result<()> {
+        let ids = snapshot
+            .entries
+            .iter()
+            .filter_map(|entry| entry.id.as_ref());
+        let mut keys = 0usize;
+        for id in ids.chain(&snapshot.tombstones) {
+            keys += depth(id)?;
+            if keys > limits.max_snapshot_keys {
+                return Err(invalid(format!(
+                    "snapshot above {} keys",
+                    limits.max_snapshot_keys
+                )));
+            }
+        }
+        Ok(())
+    };
+
+    let Some(Frame::Op(op)) = &frame.variant else {
+        return Ok(());
+    };
+    match &op.variant {
+        Some(
+            Op::Insert(wire::Insert { char_id, .. }) | Op::Remove(wire::Remove { char_id, .. }),
+        ) => {
+            char_id.as_ref().map_or(Ok(0), depth)?;
+        }
+        Some(Op::FullSync(wire::FullSync { state })) => {
+            state.as_ref().map_or(Ok(()), snapshot)?;
+        }
+        Some(Op::RangeSync(wire::RangeSync { buckets, state, .. })) => {
+            if buckets.len() > state::DIGEST_BUCKETS
+                || buckets
+                    .iter()
+                    .any(|&bucket| bucket as usize >= state::DIGEST_BUCKETS)
+            {
+                return Err(invalid(format!(
+                    "range sync outside of {} buckets",
+                    state::DIGEST_BUCKETS
+                )));
+            }
+            state.as_ref().map_or(Ok(()), snapshot)?;
+        }
+        Some(Op::Digest(wire::Digest { buckets })) if buckets.len() > state::DIGEST_BUCKETS => {
+            return Err(invalid(format!(
+                "digest of {} buckets, limit is {}",
+                buckets.len(),
+                state::DIGEST_BUCKETS
+            )));
+        }
+        Some(Op::Digest(_)) | None => {}
+    }
+    Ok(())
+}
+
+impl From<&NodeKey> for wire::NodeKey {
+    fn from(key: &NodeKey) -> Self {
+        Self {
+            digit: key.digit(),
+            peer_id: key.peer_id() as u32,
+            time: key.time(),
+        }
+    }
+}
+
+impl TryFrom<wire::NodeKey> for NodeKey {
+    type Error = Error;
+
+    fn try_from(key: wire::NodeKey) -> Result<Self> {
+        let peer_id: PeerId = narrow(key.peer_id, "peer_id")?;
+        Ok(NodeKey::new(key.digit, peer_id, key.time))
+    }
+}
+
+/// Encodes `id`, leaving out the keys it has in common with `prev`.
+fn encode_id(id: &[NodeKey], prev: &[NodeKey]) -> wire::Identifier {
+    // Keep at least one key, so that an identifier is never empty.
+    let shared = id
+        .iter()
+        .zip(prev)
+        .take_while(|(a, b)| a == b)
+        .count()
+        .min(id.len().saturating_sub(1));
+    wire::Identifier {
+        keys: id[shared..].iter().map(wire::NodeKey::from).collect(),
+        shared: shared as u32,
+    }
+}
+
+/// Decodes an identifier whose first `shared` keys come from `prev`.
+fn decode_id(id: Option<wire::Identifier>, prev: &[NodeKey]) -> Result<Vec<NodeKey>> {
+    let id = required(id, "identifier")?;
+    let shared = id.shared as usize;
+    if shared > prev.len() {
+        return Err(invalid(format!("shared prefix out of range: {}", shared)));
+    }
+    if id.keys.is_empty() {
+        return Err(invalid("empty identifier"));
+    }
+    let mut keys = Vec::with_capacity(shared + id.keys.len());
+    keys.extend_from_slice(&prev[..shared]);
+    for key in id.keys {
+        keys.push(key.try_into()?);
+    }
+    Ok(keys)
+}
+
+/// Decodes the identifier of a character, which can't be a sentinel or sort
+/// outside of them.
+fn decode_inner_id(id: Option<wire::Identifier>, prev: &[NodeKey]) -> Result<Vec<NodeKey>> {
+    let id = decode_id(id, prev)?;
+    if !state::is_inner(&id) {
+        return Err(invalid("identifier outside of the document"));
+    }
+    Ok(id)
+}
+
+/// Encodes sorted identifiers, each relative to the one before it.
+fn encode_ids<'a>(ids: impl Iterator<Item = &'a [NodeKey]>) -> Vec<wire::Identifier> {
+    let mut prev: &[NodeKey] = &[];
+    ids.map(|id| {
+        let encoded = encode_id(id, prev);
+        prev = id;
+        encoded
+    })
+    .collect()
+}
+
+impl From<&Doc> for wire::Snapshot {
+    fn from(doc: &Doc) -> Self {
+        let ids = encode_ids(doc.entries().map(|(id, _)| &id[..]));
+        let mut tombstones: Vec<_> = doc.tombstones().collect();
+        tombstones.sort_unstable();
+        Self {
+            entries: ids
+                .into_iter()
+                .zip(doc.entries())
+                .map(|(id, (_, value))| wire::Entry {
+                    id: Some(id),
+                    value: *value as u32,
+                })
+                .collect(),
+            tombstones: encode_ids(tombstones.into_iter().map(|id| &id[..])),
+        }
+    }
+}
+
+impl TryFrom<wire::Snapshot> for Doc {
+    type Error = Error;
+
+    fn try_from(snapshot: wire::Snapshot) -> Result<Self> {
+        let mut entries: Vec<(Arc<[NodeKey]>, u8)> = Vec::with_capacity(snapshot.entries.len());
+        for entry in snapshot.entries {
+            let prev = entries.last().map_or(&[][..], |(id, _)| &id[..]);
+            let id = decode_id(entry.id, prev)?;
+            entries.push((id.into(), narrow(entry.value, "value")?));
+        }
+        // Merging relies on the order, so a peer can't be trusted with it.
+        if entries.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+            return Err(invalid("snapshot entries out of order"));
+        }
+        // Nothing sorts before BOS, and anything after EOS is caught here,
+        // so the sentinels can only be first and last.
+        if entries
+            .iter()
+            .any(|(id, _)| !state::is_boundary(id) && !state::is_inner(id))
+        {
+            return Err(invalid("snapshot entry outside of the document"));
+        }
+        let mut tombstones: Vec<Arc<[NodeKey]>> = Vec::with_capacity(snapshot.tombstones.len());
+        for id in snapshot.tombstones {
+            let prev = tombstones.last().map_or(&[][..], |id| &id[..]);
+            let id = decode_inner_id(Some(id), prev)?;
+            tombstones.push(id.into());
+        }
+        Ok(Doc::from_parts(entries, tombstones))
+    }
+}
+
+impl From<OpId> for wire::OpId {
+    fn from(id: OpId) -> Self {
+        Self {
+            origin: id.origin as u32,
+            seq: id.seq,
+        }
+    }
+}
+
+impl TryFrom<Option<wire::OpId>> for OpId {
+    type Error = Error;
+
+    fn try_from(id: Option<wire::OpId>) -> Result<Self> {
+        let id = required(id, "op_id")?;
+        Ok(OpId {
+            origin: narrow(id.origin, "origin")?,
+            seq: id.seq,
+        })
+    }
+}
+
+impl From<&PeerSyncOp> for wire::SyncOp {
+    fn from(op: &PeerSyncOp) -> Self {
+        use wire::sync_op::Variant;
+
+        let variant = match op {
+            PeerSyncOp::Insert {
+                op_id,
+                char_id,
+                value,
+            } => Variant::Insert(wire::Insert {
+                op_id: Some((*op_id).into()),
+                char_id: Some(encode_id(char_id, &[])),
+                value: *value as u32,
+            }),
+            PeerSyncOp::Remove { op_id, char_id } => Variant::Remove(wire::Remove {
+                op_id: Some((*op_id).into()),
+                char_id: Some(encode_id(char_id, &[])),
+            }),
+            PeerSyncOp::FullSync { state } => Variant::FullSync(wire::FullSync {
+                state: Some(state.into()),
+            }),
+            PeerSyncOp::Digest { buckets } => Variant::Digest(wire::Digest {
+                buckets: buckets.clone(),
+            }),
+            PeerSyncOp::RangeSync {
+                buckets,
+                state,
+                reply,
+            } => Variant::RangeSync(wire::RangeSync {
+                buckets: buckets.clone(),
+                state: Some(state.into()),
+                reply: *reply,
+            }),
+        };
+        Self {
+            variant: Some(variant),
+        }
+    }
+}
+
+impl TryFrom<wire::SyncOp> for PeerSyncOp {
+    type Error = Error;
+
+    fn try_from(op: wire::SyncOp) -> Result<Self> {
+        use wire::sync_op::Variant;
+
+        Ok(match required(op.variant, "op")? {
+            Variant::Insert(insert) => PeerSyncOp::Insert {
+                op_id: insert.op_id.try_into()?,
+                char_id: decode_inner_id(insert.char_id, &[])?,
+                value: narrow(insert.value, "value")?,
+            },
+            Variant::Remove(remove) => PeerSyncOp::Remove {
+                op_id: remove.op_id.try_into()?,
+                char_id: decode_inner_id(remove.char_id, &[])?,
+            },
+            Variant::FullSync(sync) => PeerSyncOp::FullSync {
+                state: required(sync.state, "state")?.try_into()?,
+            },
+            Variant::Digest(digest) => PeerSyncOp::Digest {
+                buckets: digest.buckets,
+            },
+            Variant::RangeSync(sync) => PeerSyncOp::RangeSync {
+                buckets: sync.buckets,
+                state: required(sync.state, "state")?.try_into()?,
+                reply: sync.reply,
+            },
+        })
+    }
+}
+
+impl From<&Frame> for wire::LinkFrame {
+    fn from(frame: &Frame) -> Self {
+        use wire::link_frame::Variant;
+
+        let variant = match frame {
+            Frame::Op(op) => Variant::Op(op.into()),
+            Frame::Ping { nonce } => Variant::Ping(wire::Ping { nonce: *nonce }),
+            Frame::Pong { nonce } => Variant::Pong(wire::Pong { nonce: *nonce }),
+        };
+        Self {
+            variant: Some(variant),
+        }
+    }
+}
+
+impl TryFrom<wire::LinkFrame> for Frame {
+    type Error = Error;
+
+    fn try_from(frame: wire::LinkFrame) -> Result<Self> {
+        use wire::link_frame::Variant;
+
+        Ok(match required(frame.variant, "frame")? {
+            Variant::Op(op) => Frame::Op(op.try_into()?),
+            Variant::Ping(ping) => Frame::Ping { nonce: ping.nonce },
+            Variant::Pong(pong) => Frame::Pong { nonce: pong.nonce },
+        })
+    }
+}
+
+impl From<&PeerBeacon> for wire::Beacon {
+    fn from(beacon: &PeerBeacon) -> Self {
+        Self {
+            id: beacon.id as u32,
+            tcp_port: beacon.tcp_port as u32,
+            room: beacon.room.clone(),
+        }
+    }
+}
+
+impl TryFrom<wire::Beacon> for PeerBeacon {
+    type Error = Error;
+
+    fn try_from(beacon: wire::Beacon) -> Result<Self> {
+        Ok(PeerBeacon {
+            id: narrow(beacon.id, "id")?,
+            tcp_port: u16::try_from(beacon.tcp_port)
+                .map_err(|_| invalid(format!("tcp_port out of range: {}", beacon.tcp_port)))?,
+            room: beacon.room,
+        })
+    }
+}
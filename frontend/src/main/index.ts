@@ -9,6 +9,11 @@ import {
   updateBackendWindowReference,
   onExit,
   onSave,
+  onConnectPeer,
+  onPeerStats,
+  onListPeers,
+  onListRooms,
+  onReloadConfig,
 } from "./ipc";
 
 let main_window: BrowserWindow | null = null;
@@ -38,6 +43,11 @@ function createWindow(): void {
   });
   ipcMain.on("user:keydown", (_event: any, key_data: string, cursor_pos: number) => { onKeyDown(key_data, cursor_pos); });
   ipcMain.on("user:save", (_event: any, filename: string) => { onSave(filename); });
+  ipcMain.on("user:connect", (_event: any, address: string) => { onConnectPeer(address); });
+  ipcMain.on("user:peer-stats", () => { onPeerStats(); });
+  ipcMain.on("user:list-peers", () => { onListPeers(); });
+  ipcMain.on("user:list-rooms", () => { onListRooms(); });
+  ipcMain.on("user:reload-config", () => { onReloadConfig(); });
   
   main_window.on('ready-to-show', () => { main_window!.show() });
 
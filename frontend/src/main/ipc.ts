@@ -18,6 +18,94 @@ let backend: ChildProcessWithoutNullStreams | null = null;
 interface ServerEvent {
   op?: LocalOp | null;
   state?: FullState | null;
+  report?: ValidationReport | null;
+  status?: PeerStatus | null;
+  divergence?: DivergenceReport | null;
+  stats?: PeerStatsReport | null;
+  role?: RoleAssignment | null;
+  joined?: { peer?: PeerInfo | null } | null;
+  left?: { peer?: PeerInfo | null } | null;
+  reconnecting?: PeerReconnecting | null;
+  roster?: PeerRoster | null;
+  rooms?: RoomList | null;
+  configReloaded?: ConfigReloaded | null;
+}
+
+interface ConfigReloaded {
+  applied?: string[];
+  restartNeeded?: string[];
+  error?: string;
+}
+
+interface RoomList {
+  rooms?: RoomInfo[];
+}
+
+interface RoomInfo {
+  name?: string;
+  peers?: number;
+  joined?: boolean;
+}
+
+interface PeerInfo {
+  peerId?: number;
+  name?: string;
+  address?: string;
+  quality?: number;
+  rttUs?: number;
+}
+
+const QUALITIES = ["unknown", "good", "fair", "poor"];
+
+interface PeerReconnecting {
+  peer?: PeerInfo | null;
+  attempt?: number;
+  retryInMs?: number;
+}
+
+interface PeerRoster {
+  peers?: PeerInfo[];
+}
+
+interface RoleAssignment {
+  role?: number;
+}
+
+const ROLES = ["owner", "editor", "viewer"];
+
+interface PeerStatsReport {
+  peers?: PeerStats[];
+}
+
+interface PeerStats {
+  peerId?: number;
+  address?: string;
+  rttUs?: number;
+  smoothedRttUs?: number;
+  minRttUs?: number;
+  samples?: number;
+  queued?: number;
+}
+
+interface DivergenceReport {
+  peerId?: number;
+  ranges?: number[];
+  totalRanges?: number;
+}
+
+interface PeerStatus {
+  peerId?: number;
+  state?: number;
+  attempt?: number;
+  retryInMs?: number;
+  address?: string;
+}
+
+const PEER_STATES = ["connected", "disconnected", "reconnecting"];
+
+interface ValidationReport {
+  issues?: string[];
+  repaired?: boolean;
 }
 
 interface FullState {
@@ -31,6 +119,11 @@ interface LocalOp {
   remove?: object | null;
 }
 
+function describePeer(peer: PeerInfo | null | undefined): string {
+  const id = peer?.peerId ?? "?";
+  return peer?.name ? `${peer.name} (peer ${id}, ${peer.address})` : `Peer ${id} (${peer?.address})`;
+}
+
 /**************************************************************************************************/
 
 function handleServerEvent(event: ServerEvent): void {
@@ -55,6 +148,110 @@ function handleServerEvent(event: ServerEvent): void {
     }
     return;
   }
+  if (event.report) {
+    const issues = event.report.issues ?? [];
+    if (issues.length === 0) {
+      console.log("Document validation passed");
+    } else {
+      console.warn("Document validation issues:", issues, "repaired:", event.report.repaired ?? false);
+    }
+    return;
+  }
+  if (event.status) {
+    const { peerId, state, attempt, retryInMs, address } = event.status;
+    const name = PEER_STATES[state ?? 0] ?? "unknown";
+    if (name === "reconnecting") {
+      console.log(`Peer ${peerId ?? "?"} (${address}) reconnecting, attempt ${attempt} in ${retryInMs}ms`);
+    } else {
+      console.log(`Peer ${peerId ?? "?"} (${address}) ${name}`);
+    }
+    return;
+  }
+
+  if (event.divergence) {
+    const { peerId, ranges, totalRanges } = event.divergence;
+    console.warn(`Document diverged from peer ${peerId ?? "?"} in ${ranges?.length ?? 0}/${totalRanges ?? 0} ranges, reconciling`);
+    return;
+  }
+
+  if (event.stats) {
+    const peers = event.stats.peers ?? [];
+    if (peers.length === 0) {
+      console.log("No connected peers");
+    } else {
+      console.table(peers.map((peer) => ({
+        peer: peer.peerId ?? 0,
+        address: peer.address,
+        "rtt (ms)": (peer.rttUs ?? 0) / 1000,
+        "smoothed (ms)": (peer.smoothedRttUs ?? 0) / 1000,
+        "min (ms)": (peer.minRttUs ?? 0) / 1000,
+        samples: peer.samples ?? 0,
+        queued: peer.queued ?? 0,
+      })));
+    }
+    return;
+  }
+
+  if (event.role) {
+    const role = ROLES[event.role.role ?? 0] ?? "viewer";
+    console.log(`Joined as ${role}`);
+    main_window!.webContents.send("role-assigned", role);
+    return;
+  }
+
+  if (event.joined) {
+    console.log(`${describePeer(event.joined.peer)} joined`);
+    return;
+  }
+
+  if (event.left) {
+    console.log(`${describePeer(event.left.peer)} left`);
+    return;
+  }
+
+  if (event.reconnecting) {
+    const { peer, attempt, retryInMs } = event.reconnecting;
+    console.log(`${describePeer(peer)} reconnecting, attempt ${attempt} in ${retryInMs}ms`);
+    return;
+  }
+
+  if (event.roster) {
+    const peers = event.roster.peers ?? [];
+    if (peers.length === 0) {
+      console.log("No connected peers");
+    } else {
+      console.table(peers.map((peer) => ({
+        peer: peer.peerId ?? 0,
+        name: peer.name ?? "",
+        address: peer.address,
+        quality: QUALITIES[peer.quality ?? 0] ?? "unknown",
+        "rtt (ms)": (peer.rttUs ?? 0) / 1000,
+      })));
+    }
+    return;
+  }
+
+  if (event.rooms) {
+    console.table((event.rooms.rooms ?? []).map((room) => ({
+      room: room.name ?? "",
+      peers: room.peers ?? 0,
+      joined: room.joined ?? false,
+    })));
+    return;
+  }
+
+  if (event.configReloaded) {
+    const { applied, restartNeeded, error } = event.configReloaded;
+    if (error) {
+      console.error("Config not reloaded:", error);
+    } else {
+      console.log("Config reloaded, applied:", applied?.length ? applied.join(", ") : "nothing");
+      if (restartNeeded?.length) {
+        console.warn("Restart to apply:", restartNeeded.join(", "));
+      }
+    }
+    return;
+  }
 
   console.error("Unknown ServerEvent variant received:", event);
 }
@@ -161,6 +358,36 @@ export function onSave(filename: string): void {
 
 /**************************************************************************************************/
 
+export function onConnectPeer(address: string): void {
+  sendLocalCommand(ClientCommandFrame!.create({ connect: { address: address } }));
+}
+
+/**************************************************************************************************/
+
+export function onPeerStats(): void {
+  sendLocalCommand(ClientCommandFrame!.create({ stats: {} }));
+}
+
+/**************************************************************************************************/
+
+export function onListPeers(): void {
+  sendLocalCommand(ClientCommandFrame!.create({ listPeers: {} }));
+}
+
+/**************************************************************************************************/
+
+export function onListRooms(): void {
+  sendLocalCommand(ClientCommandFrame!.create({ listRooms: {} }));
+}
+
+/**************************************************************************************************/
+
+export function onReloadConfig(): void {
+  sendLocalCommand(ClientCommandFrame!.create({ reloadConfig: {} }));
+}
+
+/**************************************************************************************************/
+
 export function onExit(): void {
   sendLocalCommand(ClientCommandFrame!.create({ close: {} }));
 }
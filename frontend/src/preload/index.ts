@@ -7,6 +7,11 @@ const api = {
   maximize: () => ipcRenderer.send("window:maximize"),
   close: () => ipcRenderer.send("window:close"),
   save: (filename: string) => ipcRenderer.send("user:save", filename),
+  connectPeer: (address: string) => ipcRenderer.send("user:connect", address),
+  peerStats: () => ipcRenderer.send("user:peer-stats"),
+  listPeers: () => ipcRenderer.send("user:list-peers"),
+  listRooms: () => ipcRenderer.send("user:list-rooms"),
+  reloadConfig: () => ipcRenderer.send("user:reload-config"),
   onUserKeydown: (keyData, cursorPos) => ipcRenderer.send("user:keydown", keyData, cursorPos),
   onRemoveRequest: (
     callback: (position: number, is_remote: boolean) => void,
@@ -27,6 +32,9 @@ const api = {
       callback(new_text),
     );
   },
+  onRoleAssigned: (callback: (role: string) => void) => {
+    ipcRenderer.on("role-assigned", (_e, role: string) => callback(role));
+  },
 };
 
 // Use `contextBridge` APIs to expose Electron APIs to
@@ -3,6 +3,7 @@ import React, { useEffect, useState } from "react";
 import Taskbar from "./components/Taskbar";
 import TextEdit from "./components/TextEdit";
 import FileDialog from "./components/FileDialog";
+import ConnectDialog from "./components/ConnectDialog";
 import LoadingScreen from "./components/LoadingScreen";
 
 import "./styles/Taskbar.css";
@@ -11,6 +12,7 @@ import "./styles/TextEdit.css";
 export default function App(): React.JSX.Element {
   const [loaded, setLoaded] = useState<boolean>(false);
   const [dialog_active, setDialogActive] = useState<boolean>(false);
+  const [connect_active, setConnectActive] = useState<boolean>(false);
 
   useEffect(() => {
     setTimeout(() => {
@@ -20,11 +22,18 @@ export default function App(): React.JSX.Element {
 
   return (
     <>
-      <Taskbar onSave={ () => setDialogActive(true) } />
+      <Taskbar
+        onSave={ () => setDialogActive(true) }
+        onConnect={ () => setConnectActive(true) }
+      />
       <FileDialog
         active={dialog_active}
         onExit={() => setDialogActive(false)}
       />
+      <ConnectDialog
+        active={connect_active}
+        onExit={() => setConnectActive(false)}
+      />
       <TextEdit/>
     </>
   );
@@ -0,0 +1,55 @@
+import { useEffect, useRef } from "react";
+
+import "../styles/FileDialog.css";
+
+const ADDRESS_PATTERN = /^[^\s:]+:\d{1,5}$|^\[[0-9a-fA-F:.]+\]:\d{1,5}$/;
+
+export default function ConnectDialog({
+    active,
+    onExit
+}: {
+    active: boolean,
+    onExit: () => void
+}): React.JSX.Element {
+    let modal_ref = useRef<HTMLDivElement | null>(null);
+    let address_input_ref = useRef<HTMLDivElement | null>(null);
+
+    const handleClick = (event: MouseEvent) => {
+        if (modal_ref.current && !modal_ref.current.contains(event.target as Node)) {
+            onExit();
+        }
+    };
+
+    const onConnect = () => {
+        if (!address_input_ref.current) { return; }
+
+        const address = (address_input_ref.current.innerText || "").replace(/\s/gm, "");
+
+        if (!ADDRESS_PATTERN.test(address)) {
+            alert("Address must look like host:port");
+            return;
+        }
+
+        window.api.connectPeer(address);
+    }
+
+    useEffect(() => {
+        window.addEventListener("mousedown", handleClick);
+        return () => { window.removeEventListener("mousedown", handleClick); }
+    }, []);
+
+    return (
+        <div className={ active ? "modal-container active" : "modal-container" }>
+            <div className="order-modal" ref={ modal_ref }>
+                <div className="textfield">
+                    <h3>Peer address (host:port):</h3>
+                    <div contentEditable="true" className="text-input" ref={ address_input_ref }></div>
+                </div>
+                <div className="save-btn" onClick={ () => {
+                    onExit();
+                    onConnect();
+                    } }>Connect</div>
+            </div>
+        </div>
+    )
+}
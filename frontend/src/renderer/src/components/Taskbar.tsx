@@ -40,9 +40,11 @@ function DropdownMenu({
 }
 
 export default function Taskbar({
-  onSave
+  onSave,
+  onConnect
 }: {
-  onSave: () => void
+  onSave: () => void,
+  onConnect: () => void
 }): React.JSX.Element{
   
   const [activeMenu, setActiveMenu] = useState<number | null>(null);
@@ -53,6 +55,15 @@ export default function Taskbar({
       { label: "Save As...",  action: onSave            },
       { label: "Exit",        action: window.api.close  }
     ]
+  }, {
+    label: "Session",
+    options: [
+      { label: "Connect to Peer...", action: onConnect            },
+      { label: "Peers",              action: window.api.listPeers  },
+      { label: "Rooms",              action: window.api.listRooms  },
+      { label: "Peer Stats",         action: window.api.peerStats  },
+      { label: "Reload Config",      action: window.api.reloadConfig }
+    ]
   }]
   
 
@@ -13,6 +13,8 @@ export default function TextEdit(): React.JSX.Element {
   const canvas_ref = useRef<HTMLCanvasElement | null>(null);
   const edit_ref = useRef<HTMLDivElement | null>(null);
   const pending_inserts = useRef(0);
+  const [read_only, setReadOnly] = useState<boolean>(false);
+  const read_only_ref = useRef(false);
 
   useEffect(() => {
     if (canvas_ref.current === null || edit_ref.current === null) {
@@ -162,6 +164,10 @@ export default function TextEdit(): React.JSX.Element {
     window.api.onRemoveRequest(handlerRemove);
     window.api.onInsertRequest(handleInsert);
     window.api.onFullSync(handleFullSync);
+    window.api.onRoleAssigned((role: string) => {
+      read_only_ref.current = role === "viewer";
+      setReadOnly(read_only_ref.current);
+    });
 
 
     const isSupportedChar = (char: string): boolean => {
@@ -171,6 +177,10 @@ export default function TextEdit(): React.JSX.Element {
     };
 
     const handleKeyDown = (event: KeyboardEvent): void => {
+      if (read_only_ref.current) {
+        return;
+      }
+
       if ((event.ctrlKey || event.metaKey) && ["c", "a"].includes(event.key)) {
         console.error("Unhandled user input");
         return;
@@ -248,8 +258,8 @@ export default function TextEdit(): React.JSX.Element {
       <canvas ref={canvas_ref} className="glslCanvas"/>
       <div
         ref={edit_ref}
-        className="text-field"
-        contentEditable="plaintext-only"
+        className={read_only ? "text-field read-only" : "text-field"}
+        contentEditable={read_only ? "false" : "plaintext-only"}
         spellCheck={false}
       />
     </>
@@ -7,6 +7,11 @@ declare global {
       maximize: () => void;
       close: () => void;
       save: (filename: string) => void;
+      connectPeer: (address: string) => void;
+      peerStats: () => void;
+      listPeers: () => void;
+      listRooms: () => void;
+      reloadConfig: () => void;
       onUserKeydown: (keyData: string, cursorPos: number | undefined) => void;
       onRemoveRequest: (
         callback: (position: number, is_remote: boolean) => void,
@@ -15,6 +20,7 @@ declare global {
         callback: (position: number, char: string, is_remote: boolean) => void,
       ) => void;
       onFullSync: (callback: (new_text: string) => void) => void;
+      onRoleAssigned: (callback: (role: string) => void) => void;
     };
   }
 }